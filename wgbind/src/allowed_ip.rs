@@ -0,0 +1,271 @@
+//! Allowed ips of a peer in CIDR notation
+//!
+//! `wg_allowedip` keeps the family, a union of `in_addr`/`in6_addr` and the
+//! prefix length, and peers reference them through a `next_allowedip` chain.
+//! [`AllowedIp`] is the safe counterpart which parses and prints the notation
+//! used by wg(8), e.g. `10.0.0.0/24` or `fd00::/64`.
+//!
+//! The kernel clears the host bits of every entry it stores, `10.0.0.1/24`
+//! is reported back as `10.0.0.0/24`. [`AllowedIp::normalized`] does the same
+//! for callers which want to compare with what the kernel reports.
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use wgbindraw_sys::*;
+
+/// Returned if an address and prefix length do not form a valid allowed ip
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidAllowedIp {
+    pub input: String,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for InvalidAllowedIp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid allowed ip {:?}: {}", self.input, self.reason)
+    }
+}
+
+impl std::error::Error for InvalidAllowedIp {}
+
+/// A single allowed ip entry of a peer, e.g. `10.0.0.0/24`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct AllowedIp {
+    pub(crate) addr: IpAddr,
+    pub(crate) cidr: u8,
+}
+
+impl AllowedIp {
+    /// Fails if the prefix length exceeds 32 for IPv4 or 128 for IPv6
+    ///
+    /// Host bits are kept as they are, see [`AllowedIp::normalized`].
+    pub fn new(addr: IpAddr, cidr: u8) -> Result<Self, InvalidAllowedIp> {
+        if cidr > max_cidr(&addr) {
+            return Err(InvalidAllowedIp {
+                input: format!("{}/{}", addr, cidr),
+                reason: "prefix length exceeds the address length",
+            });
+        }
+
+        Ok(Self { addr, cidr })
+    }
+
+    /// An entry matching exactly one address, /32 or /128
+    pub fn host(addr: IpAddr) -> Self {
+        Self { addr, cidr: max_cidr(&addr) }
+    }
+
+    /// network address of the entry
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    /// prefix length of the entry
+    pub fn cidr(&self) -> u8 {
+        self.cidr
+    }
+
+    /// The same entry with all host bits cleared, as stored by the kernel
+    pub fn normalized(self) -> Self {
+        let addr = match self.addr {
+            IpAddr::V4(addr) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.cidr)).unwrap_or(0);
+                IpAddr::V4(Ipv4Addr::from(u32::from(addr) & mask))
+            }
+            IpAddr::V6(addr) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.cidr)).unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(u128::from(addr) & mask))
+            }
+        };
+
+        Self { addr, cidr: self.cidr }
+    }
+
+    /// true if no host bits are set
+    pub fn is_normalized(&self) -> bool {
+        self.normalized() == *self
+    }
+
+    /// Copies a raw `wg_allowedip`.
+    ///
+    /// Returns `None` if the entry carries neither `AF_INET` nor `AF_INET6`
+    /// or its prefix length is out of range.
+    pub(crate) fn from_raw(raw: &wg_allowedip) -> Option<Self> {
+        let addr = match i32::from(raw.family) {
+            libc::AF_INET => {
+                let ip4 = unsafe { raw.__bindgen_anon_1.ip4.as_ref() };
+                IpAddr::V4(Ipv4Addr::from(u32::from_be(ip4.s_addr)))
+            }
+            libc::AF_INET6 => {
+                let ip6 = unsafe { raw.__bindgen_anon_1.ip6.as_ref() };
+                IpAddr::V6(Ipv6Addr::from(ip6.s6_addr))
+            }
+            _ => return None,
+        };
+
+        Self::new(addr, raw.cidr).ok()
+    }
+
+    /// Creates an unlinked raw `wg_allowedip`
+    pub(crate) fn to_raw(self) -> wg_allowedip {
+        let mut raw: wg_allowedip = unsafe { std::mem::zeroed() };
+
+        match self.addr {
+            IpAddr::V4(addr) => {
+                raw.family = libc::AF_INET as u16;
+                unsafe { raw.__bindgen_anon_1.ip4.as_mut() }.s_addr = u32::from(addr).to_be();
+            }
+            IpAddr::V6(addr) => {
+                raw.family = libc::AF_INET6 as u16;
+                unsafe { raw.__bindgen_anon_1.ip6.as_mut() }.s6_addr = addr.octets();
+            }
+        }
+        raw.cidr = self.cidr;
+
+        raw
+    }
+}
+
+fn max_cidr(addr: &IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+/// Copies every entry of a `next_allowedip` chain, skipping unknown families
+///
+/// # Safety
+///
+/// `first` and every `next_allowedip` must be either NULL or point to a valid `wg_allowedip`.
+pub(crate) unsafe fn read_chain(first: *const wg_allowedip) -> Vec<AllowedIp> {
+    let mut allowed_ips = Vec::new();
+    let mut current = first;
+    while let Some(allowedip) = unsafe { current.as_ref() } {
+        allowed_ips.extend(AllowedIp::from_raw(allowedip));
+        current = allowedip.next_allowedip;
+    }
+
+    allowed_ips
+}
+
+/// Links the entries into a chain of boxed `wg_allowedip`
+///
+/// Returns the first and the last element, both NULL for an empty chain. Every
+/// element has to be released with `Box::from_raw`.
+pub(crate) fn build_chain(
+    allowed_ips: impl IntoIterator<Item = wg_allowedip>,
+) -> (*mut wg_allowedip, *mut wg_allowedip) {
+    let mut first: *mut wg_allowedip = std::ptr::null_mut();
+    let mut last: *mut wg_allowedip = std::ptr::null_mut();
+
+    for allowedip in allowed_ips {
+        let allowedip = Box::into_raw(Box::new(wg_allowedip {
+            next_allowedip: std::ptr::null_mut(),
+            ..allowedip
+        }));
+
+        match unsafe { last.as_mut() } {
+            Some(last) => last.next_allowedip = allowedip,
+            None => first = allowedip,
+        }
+        last = allowedip;
+    }
+
+    (first, last)
+}
+
+impl FromStr for AllowedIp {
+    type Err = InvalidAllowedIp;
+
+    /// Parses `addr/cidr`, a bare address is taken as a single host like wg(8) does
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason| InvalidAllowedIp { input: s.to_owned(), reason };
+
+        let (addr, cidr) = match s.split_once('/') {
+            Some((addr, cidr)) => (addr, Some(cidr)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid("not an ip address"))?;
+
+        match cidr {
+            None => Ok(Self::host(addr)),
+            Some(cidr) => {
+                // u8::from_str accepts a leading '+'
+                if cidr.is_empty() || !cidr.bytes().all(|b| b.is_ascii_digit()) {
+                    return Err(invalid("prefix length is not a number"));
+                }
+                let cidr = cidr.parse().map_err(|_| invalid("prefix length exceeds the address length"))?;
+                Self::new(addr, cidr).map_err(|e| invalid(e.reason))
+            }
+        }
+    }
+}
+
+impl fmt::Display for AllowedIp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.cidr)
+    }
+}
+
+#[cfg(feature = "serde")]
+serde_as_string!(AllowedIp);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_and_prints_cidr_notation() {
+        for input in ["10.0.0.0/24", "fd00::/64", "0.0.0.0/0", "::/0", "192.0.2.1/32"] {
+            let allowed_ip: AllowedIp = input.parse().unwrap();
+            assert_eq!(allowed_ip.to_string(), input);
+        }
+
+        assert_eq!("192.0.2.1".parse(), Ok(AllowedIp::host("192.0.2.1".parse().unwrap())));
+        assert_eq!("fd00::1".parse::<AllowedIp>().unwrap().cidr(), 128);
+    }
+
+    #[test]
+    fn it_rejects_invalid_entries() {
+        for input in ["10.0.0.0/33", "fd00::/129", "10.0.0.0/", "10.0.0.0/+8", "10.0.0/8", "wg0", ""] {
+            assert!(input.parse::<AllowedIp>().is_err(), "{:?}", input);
+        }
+        assert!(AllowedIp::new("10.0.0.0".parse().unwrap(), 33).is_err());
+    }
+
+    #[test]
+    fn it_normalizes_host_bits() {
+        let allowed_ip: AllowedIp = "10.1.2.3/20".parse().unwrap();
+        assert!(!allowed_ip.is_normalized());
+        assert_eq!(allowed_ip.normalized().to_string(), "10.1.0.0/20");
+
+        let allowed_ip: AllowedIp = "fd00::1:2/112".parse().unwrap();
+        assert_eq!(allowed_ip.normalized().to_string(), "fd00::1:0/112");
+
+        assert_eq!("10.1.2.3/0".parse::<AllowedIp>().unwrap().normalized().to_string(), "0.0.0.0/0");
+        assert!("10.1.2.3/32".parse::<AllowedIp>().unwrap().is_normalized());
+    }
+
+    #[test]
+    fn it_converts_chains_in_both_directions() {
+        let allowed_ips: Vec<AllowedIp> = ["10.0.0.0/24", "fd00::/64", "192.0.2.1/32"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+
+        let (first, last) = build_chain(allowed_ips.iter().map(|ip| ip.to_raw()));
+        assert_eq!(unsafe { read_chain(first) }, allowed_ips);
+        assert_eq!(AllowedIp::from_raw(unsafe { &*last }), Some(allowed_ips[2]));
+
+        let mut current = first;
+        while !current.is_null() {
+            let allowedip = unsafe { Box::from_raw(current) };
+            current = allowedip.next_allowedip;
+        }
+
+        assert_eq!(build_chain([]), (std::ptr::null_mut(), std::ptr::null_mut()));
+    }
+}
@@ -0,0 +1,235 @@
+//! Whatever runs the wireguard devices
+//!
+//! The free functions of this crate talk to the kernel module, or to a userspace
+//! implementation if it has a socket, see [`crate::uapi`]. Code written against
+//! [`Backend`] instead works the same with any implementation, e.g. the kernel
+//! through [`Kernel`] in production and [`crate::Mock`] in unit tests.
+//!
+//! ```no_run
+//! use wgbind::{Backend, DeviceConfig, Kernel};
+//!
+//! fn ensure_device(backend: &impl Backend, config: &DeviceConfig) -> Result<(), wgbind::Error> {
+//!     let name = "wg0".parse().unwrap();
+//!     if !backend.list()?.iter().any(|existing| existing == "wg0") {
+//!         backend.add(&name)?;
+//!     }
+//!     backend.set(&name, config)
+//! }
+//!
+//! ensure_device(&Kernel, &DeviceConfig::new().listen_port(51820)).unwrap();
+//! ```
+
+use std::rc::Rc;
+use std::sync::Arc;
+
+use crate::{DeviceConfig, Device, Error, InterfaceName};
+
+/// The operations wgbind needs to manage devices
+///
+/// Failures are reported with the same [`Error`] variants the kernel produces,
+/// so callers can match on them regardless of the implementation.
+pub trait Backend {
+    /// Names of all wireguard devices
+    fn list(&self) -> Result<Vec<String>, Error>;
+
+    /// A snapshot of a device including its peers
+    fn get(&self, name: &InterfaceName) -> Result<Device, Error>;
+
+    /// Writes a configuration to a device, see [`crate::configure_device`]
+    fn set(&self, name: &InterfaceName, config: &DeviceConfig) -> Result<(), Error>;
+
+    /// Creates a new device
+    fn add(&self, name: &InterfaceName) -> Result<(), Error>;
+
+    /// Removes a device
+    fn delete(&self, name: &InterfaceName) -> Result<(), Error>;
+}
+
+/// The kernel module, reached through the c library or the `netlink` feature
+///
+/// Same as the free functions this includes userspace devices with a socket in
+/// [`crate::uapi::SOCKET_DIR`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Kernel;
+
+impl Backend for Kernel {
+    fn list(&self) -> Result<Vec<String>, Error> {
+        crate::list_device_names()
+    }
+
+    fn get(&self, name: &InterfaceName) -> Result<Device, Error> {
+        crate::get_device(name)
+    }
+
+    fn set(&self, name: &InterfaceName, config: &DeviceConfig) -> Result<(), Error> {
+        crate::configure_device(name, config)
+    }
+
+    fn add(&self, name: &InterfaceName) -> Result<(), Error> {
+        crate::add_device(name)
+    }
+
+    fn delete(&self, name: &InterfaceName) -> Result<(), Error> {
+        crate::delete_device(name)
+    }
+}
+
+/// The kernel module alone, without the userspace devices [`Kernel`] includes
+///
+/// The socket directory of userspace implementations is shared by all network
+/// namespaces, hence [`InNetns`](crate::netns::InNetns) uses this backend.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KernelOnly;
+
+impl Backend for KernelOnly {
+    fn list(&self) -> Result<Vec<String>, Error> {
+        crate::kernel_device_names()
+    }
+
+    fn get(&self, name: &InterfaceName) -> Result<Device, Error> {
+        crate::kernel_get_device(name)
+    }
+
+    fn set(&self, name: &InterfaceName, config: &DeviceConfig) -> Result<(), Error> {
+        crate::kernel_set_device(&mut config.build(name))
+    }
+
+    fn add(&self, name: &InterfaceName) -> Result<(), Error> {
+        crate::add_device(name)
+    }
+
+    fn delete(&self, name: &InterfaceName) -> Result<(), Error> {
+        crate::delete_device(name)
+    }
+}
+
+macro_rules! forward_backend {
+    ($($pointer:ty),*) => {$(
+        impl<B: Backend + ?Sized> Backend for $pointer {
+            fn list(&self) -> Result<Vec<String>, Error> {
+                (**self).list()
+            }
+
+            fn get(&self, name: &InterfaceName) -> Result<Device, Error> {
+                (**self).get(name)
+            }
+
+            fn set(&self, name: &InterfaceName, config: &DeviceConfig) -> Result<(), Error> {
+                (**self).set(name, config)
+            }
+
+            fn add(&self, name: &InterfaceName) -> Result<(), Error> {
+                (**self).add(name)
+            }
+
+            fn delete(&self, name: &InterfaceName) -> Result<(), Error> {
+                (**self).delete(name)
+            }
+        }
+    )*};
+}
+
+forward_backend!(&B, Box<B>, Rc<B>, Arc<B>);
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+    use crate::wireguard_device::WireguardControl;
+    use crate::{sync, Peer, PeerConfig, PrivateKey, PublicKey};
+
+    /// Hands out a fixed device and records what gets written
+    struct Recorder {
+        device: Device,
+        written: RefCell<Vec<DeviceConfig>>,
+    }
+
+    impl Backend for Recorder {
+        fn list(&self) -> Result<Vec<String>, Error> {
+            Ok(vec![self.device.name.clone()])
+        }
+
+        fn get(&self, _: &InterfaceName) -> Result<Device, Error> {
+            Ok(self.device.clone())
+        }
+
+        fn set(&self, _: &InterfaceName, config: &DeviceConfig) -> Result<(), Error> {
+            self.written.borrow_mut().push(config.clone());
+            Ok(())
+        }
+
+        fn add(&self, _: &InterfaceName) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn delete(&self, _: &InterfaceName) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    fn recorder() -> Recorder {
+        let device = Device {
+            name: "wg0".into(),
+            ifindex: 4,
+            public_key: Some(PublicKey::from_bytes([1; 32])),
+            private_key: Some(PrivateKey::from_bytes([9; 32])),
+            fwmark: 0,
+            listen_port: 51820,
+            peers: vec![Peer {
+                public_key: PublicKey::from_bytes([2; 32]),
+                preshared_key: None,
+                endpoint: Some("192.0.2.1:51820".parse().unwrap()),
+                last_handshake_time: Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+                rx_bytes: 42,
+                tx_bytes: 7,
+                persistent_keepalive_interval: 25,
+                allowed_ips: vec!["10.0.0.2/32".parse().unwrap()],
+            }],
+        };
+
+        Recorder { device, written: RefCell::new(Vec::new()) }
+    }
+
+    #[test]
+    fn it_reads_and_writes_devices_through_the_backend() {
+        let recorder = recorder();
+        let mut handle = DeviceConfig::new().build(&"wg0".parse().unwrap()).with_backend(&recorder);
+
+        handle.update_device().unwrap();
+        let read = unsafe { Device::from_raw(&*handle.raw_device_ptr()) };
+        assert_eq!(read.ifindex(), 4);
+        assert_eq!(read.public_key(), recorder.device.public_key());
+        assert_eq!(read.listen_port(), 51820);
+        let (peer, expected) = (&read.peers()[0], &recorder.device.peers()[0]);
+        assert_eq!(peer.endpoint(), expected.endpoint());
+        assert_eq!(peer.last_handshake_time(), expected.last_handshake_time());
+        assert_eq!(peer.rx_bytes(), 42);
+        assert_eq!(peer.allowed_ips(), expected.allowed_ips());
+
+        // like a device read by wg_get_device only the keys and peers are written back
+        handle.refresh_device().unwrap();
+        let peer = PeerConfig::new(*expected.public_key())
+            .endpoint(expected.endpoint().unwrap())
+            .allowed_ips(expected.allowed_ips().iter().copied());
+        assert_eq!(
+            recorder.written.borrow().as_slice(),
+            &[DeviceConfig::new().private_key(PrivateKey::from_bytes([9; 32])).peer(peer)]
+        );
+    }
+
+    #[test]
+    fn it_syncs_through_any_backend() {
+        let recorder = recorder();
+        let added = PeerConfig::new(PublicKey::from_bytes([3; 32]))
+            .allowed_ip("10.0.0.3/32".parse().unwrap())
+            .replace_allowed_ips(true);
+        let desired = recorder.device.to_config().peer(added.clone());
+
+        let changes = sync::sync_with(&recorder, &"wg0".parse().unwrap(), &desired).unwrap();
+        assert_eq!(changes.added, vec![added.public_key]);
+        assert_eq!(recorder.written.borrow()[0].peers, vec![added]);
+        assert_eq!(recorder.list().unwrap(), vec!["wg0"]);
+    }
+}
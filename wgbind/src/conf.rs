@@ -0,0 +1,812 @@
+//! The configuration file format of wg(8)
+//!
+//! `wg setconf` reads an INI like format with one `[Interface]` section and
+//! any number of `[Peer]` sections:
+//!
+//! ```text
+//! [Interface]
+//! PrivateKey = yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=
+//! ListenPort = 51820
+//!
+//! [Peer]
+//! PublicKey = xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=
+//! Endpoint = 192.0.2.1:51820
+//! AllowedIPs = 10.0.0.0/24, fd00::/64
+//! ```
+//!
+//! [`parse`] turns such a file into a [`DeviceConfig`] with the semantics of
+//! `wg setconf`: peers missing from the file are removed and the allowed ips
+//! of every listed peer are replaced. Keys and section names are case
+//! insensitive, `#` starts a comment. Every error points at the line and
+//! column it was found at.
+//!
+//! [`parse_append`] keeps the semantics of `wg addconf` instead, which only
+//! adds to the device. [`showconf`] goes the other way and writes the configuration of a device
+//! the way `wg showconf` does.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use wgbind::{conf, configure_device};
+//!
+//! let content = std::fs::read_to_string("/etc/wireguard/wg0.conf").unwrap();
+//! let config = conf::parse(&content).unwrap();
+//!
+//! configure_device(&"wg0".parse().unwrap(), &config).unwrap();
+//! ```
+
+use std::fmt::{self, Write};
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::str::FromStr;
+
+use crate::allowed_ip::AllowedIp;
+use crate::config::{DeviceConfig, PeerConfig};
+use crate::device::Device;
+use crate::key::{PresharedKey, PrivateKey, PublicKey};
+
+/// What is wrong at the position of a [`ParseError`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    /// a line is neither a section header, a `key = value` pair nor a comment
+    ExpectedKeyValue,
+    UnknownSection(String),
+    /// a key which is unknown within its section
+    UnknownKey(String),
+    /// a `key = value` pair before the first section header
+    OutsideSection,
+    /// the value of the key cannot be parsed
+    InvalidValue { key: &'static str, reason: String },
+    /// a `[Peer]` section without `PublicKey`, points at the section header
+    MissingPublicKey,
+    /// a section without a key it requires, points at the section header
+    MissingKey(&'static str),
+}
+
+/// Returned if a configuration file cannot be parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// 1 based line number
+    pub line: usize,
+    /// 1 based column in bytes
+    pub column: usize,
+    pub kind: ParseErrorKind,
+}
+
+impl ParseError {
+    pub(crate) fn new(line: usize, column: usize, kind: ParseErrorKind) -> Self {
+        Self { line, column, kind }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}, column {}: ", self.line, self.column)?;
+
+        match &self.kind {
+            ParseErrorKind::ExpectedKeyValue => write!(f, "expected a section header or key = value"),
+            ParseErrorKind::UnknownSection(name) => write!(f, "unknown section [{}]", name),
+            ParseErrorKind::UnknownKey(key) => write!(f, "unknown key {:?}", key),
+            ParseErrorKind::OutsideSection => write!(f, "key = value outside of a section"),
+            ParseErrorKind::InvalidValue { key, reason } => write!(f, "invalid {}: {}", key, reason),
+            ParseErrorKind::MissingPublicKey => write!(f, "peer without PublicKey"),
+            ParseErrorKind::MissingKey(key) => write!(f, "missing {}", key),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// A `key = value` line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Entry<'a> {
+    pub line: usize,
+    pub column: usize,
+    pub key: &'a str,
+    pub value: &'a str,
+    pub value_column: usize,
+}
+
+impl<'a> Entry<'a> {
+    pub(crate) fn invalid(&self, key: &'static str, reason: impl fmt::Display) -> ParseError {
+        self.invalid_at(self.value_column, key, reason)
+    }
+
+    pub(crate) fn invalid_at(&self, column: usize, key: &'static str, reason: impl fmt::Display) -> ParseError {
+        ParseError::new(
+            self.line,
+            column,
+            ParseErrorKind::InvalidValue {
+                key,
+                reason: reason.to_string(),
+            },
+        )
+    }
+
+    pub(crate) fn unknown(&self) -> ParseError {
+        ParseError::new(self.line, self.column, ParseErrorKind::UnknownKey(self.key.to_owned()))
+    }
+
+    /// Splits a comma separated value, yielding every non empty item with its column
+    pub(crate) fn list(&self) -> impl Iterator<Item = (usize, &'a str)> + 'a {
+        self.split(',')
+    }
+
+    /// Same as [`Entry::list`] with another separator
+    pub(crate) fn split(&self, separator: char) -> impl Iterator<Item = (usize, &'a str)> + 'a {
+        let value = self.value;
+        let value_column = self.value_column;
+
+        value.split(separator).scan(0, move |offset, item| {
+            let column = value_column + *offset + (item.len() - item.trim_start().len());
+            *offset += item.len() + 1;
+            Some((column, item.trim()))
+        })
+        .filter(|(_, item)| !item.is_empty())
+    }
+}
+
+/// A single meaningful line of a configuration file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Token<'a> {
+    Section { line: usize, column: usize, name: &'a str },
+    Entry(Entry<'a>),
+}
+
+impl Token<'_> {
+    pub(crate) fn line(&self) -> usize {
+        match self {
+            Token::Section { line, .. } => *line,
+            Token::Entry(entry) => entry.line,
+        }
+    }
+}
+
+/// Splits a configuration file into section headers and `key = value` pairs
+///
+/// Comments and blank lines are skipped, keys and values are trimmed.
+pub(crate) fn tokenize(input: &str) -> impl Iterator<Item = Result<Token<'_>, ParseError>> {
+    input.lines().enumerate().filter_map(|(index, line)| {
+        let content = match line.find('#') {
+            Some(comment) => &line[..comment],
+            None => line,
+        };
+        tokenize_line(index + 1, content)
+    })
+}
+
+/// Like [`tokenize`], but only lines starting with `#` or `;` are comments
+///
+/// Keyfiles of NetworkManager have no trailing comments, a `#` within a value is kept.
+pub(crate) fn tokenize_keyfile(input: &str) -> impl Iterator<Item = Result<Token<'_>, ParseError>> {
+    input.lines().enumerate().filter_map(|(index, line)| {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with('#') || trimmed.starts_with(';') {
+            return None;
+        }
+        tokenize_line(index + 1, line)
+    })
+}
+
+fn tokenize_line(line_number: usize, content: &str) -> Option<Result<Token<'_>, ParseError>> {
+    let trimmed = content.trim_start();
+    let column = content.len() - trimmed.len() + 1;
+    let trimmed = trimmed.trim_end();
+
+    if trimmed.is_empty() {
+        return None;
+    }
+
+    if let Some(name) = trimmed.strip_prefix('[') {
+        return Some(match name.strip_suffix(']') {
+            Some(name) => Ok(Token::Section {
+                line: line_number,
+                column,
+                name: name.trim(),
+            }),
+            None => Err(ParseError::new(line_number, column, ParseErrorKind::ExpectedKeyValue)),
+        });
+    }
+
+    let Some((key, value)) = trimmed.split_once('=') else {
+        return Some(Err(ParseError::new(line_number, column, ParseErrorKind::ExpectedKeyValue)));
+    };
+    let key = key.trim_end();
+    if key.is_empty() {
+        return Some(Err(ParseError::new(line_number, column, ParseErrorKind::ExpectedKeyValue)));
+    }
+
+    // value still starts right behind the '='
+    let value_start = trimmed.len() - value.len() + (value.len() - value.trim_start().len());
+    Some(Ok(Token::Entry(Entry {
+        line: line_number,
+        column,
+        key,
+        value: value.trim(),
+        value_column: column + value_start,
+    })))
+}
+
+/// The value of `key` parsed with `FromStr`
+pub(crate) fn value<T: FromStr>(entry: &Entry<'_>, key: &'static str) -> Result<T, ParseError>
+where
+    T::Err: fmt::Display,
+{
+    entry.value.parse().map_err(|error| entry.invalid(key, error))
+}
+
+pub(crate) fn key<T: FromStr>(entry: &Entry<'_>, key: &'static str) -> Result<T, ParseError> {
+    entry.value.parse().map_err(|_| entry.invalid(key, "not a base64 encoded 32 byte key"))
+}
+
+/// A number or `off`, which maps to 0
+pub(crate) fn number_or_off<T: FromStr + Default>(entry: &Entry<'_>, key: &'static str) -> Result<T, ParseError>
+where
+    T::Err: fmt::Display,
+{
+    if entry.value.eq_ignore_ascii_case("off") {
+        return Ok(T::default());
+    }
+
+    value(entry, key)
+}
+
+pub(crate) fn fwmark(entry: &Entry<'_>) -> Result<u32, ParseError> {
+    let value = entry.value;
+    if value.eq_ignore_ascii_case("off") {
+        return Ok(0);
+    }
+
+    let parsed = match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+
+    parsed.map_err(|error| entry.invalid("FwMark", error))
+}
+
+/// The value of `Endpoint` as written
+pub(crate) enum EndpointValue {
+    Addr(SocketAddr),
+    Host(String, u16),
+}
+
+/// `address:port` or `hostname:port`, without resolving the hostname
+pub(crate) fn endpoint_value(entry: &Entry<'_>) -> Result<EndpointValue, ParseError> {
+    if let Ok(endpoint) = entry.value.parse() {
+        return Ok(EndpointValue::Addr(endpoint));
+    }
+
+    let Some((host, port)) = entry.value.rsplit_once(':') else {
+        return Err(entry.invalid("Endpoint", "expected host:port"));
+    };
+    let host = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+    let port: u16 = port.parse().map_err(|error| entry.invalid("Endpoint", error))?;
+    if host.is_empty() {
+        return Err(entry.invalid("Endpoint", "expected host:port"));
+    }
+
+    Ok(EndpointValue::Host(host.to_owned(), port))
+}
+
+/// `address:port` or `hostname:port`, hostnames are resolved right away like wg(8) does
+pub(crate) fn endpoint(entry: &Entry<'_>) -> Result<SocketAddr, ParseError> {
+    match endpoint_value(entry)? {
+        EndpointValue::Addr(endpoint) => Ok(endpoint),
+        EndpointValue::Host(host, port) => resolve(&host, port).map_err(|error| entry.invalid("Endpoint", error)),
+    }
+}
+
+fn resolve(host: &str, port: u16) -> io::Result<SocketAddr> {
+    (host, port).to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, format!("{} did not resolve to any address", host))
+    })
+}
+
+/// The endpoint of a peer given by hostname, which is kept as written
+///
+/// The peer itself has no endpoint until [`resolve_endpoints`] looks the
+/// hostname up, right before the configuration is applied.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HostEndpoint {
+    pub public_key: PublicKey,
+    pub host: String,
+    pub port: u16,
+}
+
+impl HostEndpoint {
+    /// The first address the hostname resolves to, like wg(8) picks it
+    pub fn resolve(&self) -> io::Result<SocketAddr> {
+        resolve(&self.host, self.port)
+    }
+}
+
+impl fmt::Display for HostEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.host, self.port)
+    }
+}
+
+/// A copy of `config` with the endpoints of `hosts` resolved and filled in
+///
+/// Hosts of peers missing from `config` are ignored.
+pub fn resolve_endpoints(config: &DeviceConfig, hosts: &[HostEndpoint]) -> io::Result<DeviceConfig> {
+    let mut config = config.clone();
+    for host in hosts {
+        if let Some(peer) = config.peers.iter_mut().find(|peer| peer.public_key == host.public_key) {
+            peer.endpoint = Some(host.resolve()?);
+        }
+    }
+
+    Ok(config)
+}
+
+pub(crate) fn allowed_ips(entry: &Entry<'_>) -> Result<Vec<AllowedIp>, ParseError> {
+    entry
+        .list()
+        .map(|(column, item)| item.parse().map_err(|error| entry.invalid_at(column, "AllowedIPs", error)))
+        .collect()
+}
+
+/// The sections of a configuration file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Section {
+    Interface,
+    Peer,
+}
+
+impl Section {
+    pub(crate) fn parse(line: usize, column: usize, name: &str) -> Result<Self, ParseError> {
+        if name.eq_ignore_ascii_case("Interface") {
+            Ok(Section::Interface)
+        } else if name.eq_ignore_ascii_case("Peer") {
+            Ok(Section::Peer)
+        } else {
+            Err(ParseError::new(line, column, ParseErrorKind::UnknownSection(name.to_owned())))
+        }
+    }
+}
+
+/// A `[Peer]` section whose public key may not have shown up yet
+#[derive(Debug, Default)]
+pub(crate) struct PendingPeer {
+    line: usize,
+    column: usize,
+    public_key: Option<PublicKey>,
+    preshared_key: Option<PresharedKey>,
+    endpoint: Option<SocketAddr>,
+    host: Option<(String, u16)>,
+    persistent_keepalive_interval: Option<u16>,
+    allowed_ips: Vec<AllowedIp>,
+}
+
+impl PendingPeer {
+    pub(crate) fn new(line: usize, column: usize) -> Self {
+        Self {
+            line,
+            column,
+            ..Self::default()
+        }
+    }
+
+    /// Applies a key of the `[Peer]` section, `Ok(false)` if the key is unknown
+    pub(crate) fn apply(&mut self, entry: &Entry<'_>) -> Result<bool, ParseError> {
+        match entry.key.to_ascii_lowercase().as_str() {
+            "publickey" => self.public_key = Some(key(entry, "PublicKey")?),
+            "presharedkey" => self.preshared_key = Some(key(entry, "PresharedKey")?),
+            "endpoint" => {
+                self.endpoint = Some(endpoint(entry)?);
+                self.host = None;
+            }
+            "persistentkeepalive" => {
+                self.persistent_keepalive_interval = Some(number_or_off(entry, "PersistentKeepalive")?)
+            }
+            "allowedips" => self.allowed_ips.extend(allowed_ips(entry)?),
+            _ => return Ok(false),
+        }
+
+        Ok(true)
+    }
+
+    /// Same as [`PendingPeer::apply`], but a hostname endpoint is kept instead of resolved
+    pub(crate) fn apply_keeping_host(&mut self, entry: &Entry<'_>) -> Result<bool, ParseError> {
+        if !entry.key.eq_ignore_ascii_case("endpoint") {
+            return self.apply(entry);
+        }
+
+        match endpoint_value(entry)? {
+            EndpointValue::Addr(endpoint) => {
+                self.endpoint = Some(endpoint);
+                self.host = None;
+            }
+            EndpointValue::Host(host, port) => {
+                self.endpoint = None;
+                self.host = Some((host, port));
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// The hostname endpoint kept by [`PendingPeer::apply_keeping_host`]
+    pub(crate) fn host_endpoint(&self) -> Option<HostEndpoint> {
+        let (host, port) = self.host.clone()?;
+
+        Some(HostEndpoint {
+            public_key: self.public_key?,
+            host,
+            port,
+        })
+    }
+
+    pub(crate) fn finish(self) -> Result<PeerConfig, ParseError> {
+        let Some(public_key) = self.public_key else {
+            return Err(ParseError::new(self.line, self.column, ParseErrorKind::MissingPublicKey));
+        };
+
+        Ok(PeerConfig {
+            public_key,
+            preshared_key: self.preshared_key,
+            endpoint: self.endpoint,
+            persistent_keepalive_interval: self.persistent_keepalive_interval,
+            allowed_ips: self.allowed_ips,
+            remove: false,
+            replace_allowed_ips: false,
+        })
+    }
+}
+
+/// Applies a key of the `[Interface]` section, `Ok(false)` if the key is unknown
+pub(crate) fn apply_interface(config: &mut DeviceConfig, entry: &Entry<'_>) -> Result<bool, ParseError> {
+    match entry.key.to_ascii_lowercase().as_str() {
+        "privatekey" => config.private_key = Some(key::<PrivateKey>(entry, "PrivateKey")?),
+        "listenport" => config.listen_port = Some(value(entry, "ListenPort")?),
+        "fwmark" => config.fwmark = Some(fwmark(entry)?),
+        _ => return Ok(false),
+    }
+
+    Ok(true)
+}
+
+/// Parses a configuration file the way `wg setconf` does
+///
+/// The returned configuration replaces all peers of the device and the allowed
+/// ips of every peer, hence the device ends up exactly as described by the file.
+pub fn parse(input: &str) -> Result<DeviceConfig, ParseError> {
+    parse_with(input, |_| Ok(false)).map(replace_all)
+}
+
+/// Parses a configuration file the way `wg addconf` does
+///
+/// Neither peers nor allowed ips are replaced: new peers are added, the allowed
+/// ips of existing peers are added to the ones they have already. Pass the
+/// result to [`add_config`](crate::add_config).
+pub fn parse_append(input: &str) -> Result<DeviceConfig, ParseError> {
+    parse_with(input, |_| Ok(false))
+}
+
+/// Turns on the replace semantics of `wg setconf`
+pub(crate) fn replace_all(mut config: DeviceConfig) -> DeviceConfig {
+    config.replace_peers = true;
+    for peer in &mut config.peers {
+        peer.replace_allowed_ips = true;
+    }
+
+    config
+}
+
+/// Parses a configuration file without the replace semantics of `wg setconf`
+///
+/// Keys of the `[Interface]` section unknown to wg(8) are handed to `extra`,
+/// which returns `Ok(false)` if it does not know them either.
+pub(crate) fn parse_with(
+    input: &str,
+    extra: impl FnMut(&Entry<'_>) -> Result<bool, ParseError>,
+) -> Result<DeviceConfig, ParseError> {
+    parse_sections(input, extra, None)
+}
+
+/// Same as [`parse_with`], but hostname endpoints are returned as written instead of resolved
+pub(crate) fn parse_keeping_hosts(
+    input: &str,
+    extra: impl FnMut(&Entry<'_>) -> Result<bool, ParseError>,
+) -> Result<(DeviceConfig, Vec<HostEndpoint>), ParseError> {
+    let mut hosts = Vec::new();
+    let config = parse_sections(input, extra, Some(&mut hosts))?;
+
+    Ok((config, hosts))
+}
+
+fn parse_sections(
+    input: &str,
+    mut extra: impl FnMut(&Entry<'_>) -> Result<bool, ParseError>,
+    mut hosts: Option<&mut Vec<HostEndpoint>>,
+) -> Result<DeviceConfig, ParseError> {
+    let mut config = DeviceConfig::new();
+    let mut section = None;
+    let mut peer: Option<PendingPeer> = None;
+
+    for token in tokenize(input) {
+        match token? {
+            Token::Section { line, column, name } => {
+                let next = Section::parse(line, column, name)?;
+                if let Some(peer) = peer.take() {
+                    finish_peer(&mut config, peer, hosts.as_deref_mut())?;
+                }
+                if next == Section::Peer {
+                    peer = Some(PendingPeer::new(line, column));
+                }
+                section = Some(next);
+            }
+            Token::Entry(entry) => {
+                let known = match (section, peer.as_mut()) {
+                    (None, _) => {
+                        return Err(ParseError::new(entry.line, entry.column, ParseErrorKind::OutsideSection))
+                    }
+                    (Some(Section::Peer), Some(peer)) if hosts.is_some() => peer.apply_keeping_host(&entry)?,
+                    (Some(Section::Peer), Some(peer)) => peer.apply(&entry)?,
+                    _ => apply_interface(&mut config, &entry)? || extra(&entry)?,
+                };
+                if !known {
+                    return Err(entry.unknown());
+                }
+            }
+        }
+    }
+
+    if let Some(peer) = peer {
+        finish_peer(&mut config, peer, hosts)?;
+    }
+
+    Ok(config)
+}
+
+fn finish_peer(
+    config: &mut DeviceConfig,
+    peer: PendingPeer,
+    hosts: Option<&mut Vec<HostEndpoint>>,
+) -> Result<(), ParseError> {
+    if let (Some(hosts), Some(host)) = (hosts, peer.host_endpoint()) {
+        hosts.push(host);
+    }
+    config.peers.push(peer.finish()?);
+
+    Ok(())
+}
+
+/// The keys of the `[Interface]` section known to wg(8) with their values, in the order they are written
+pub(crate) fn interface_keys(config: &DeviceConfig) -> Vec<(&'static str, String)> {
+    let mut keys = Vec::new();
+    if let Some(port) = config.listen_port {
+        keys.push(("ListenPort", port.to_string()));
+    }
+    match config.fwmark {
+        Some(0) => keys.push(("FwMark", "off".to_owned())),
+        Some(fwmark) => keys.push(("FwMark", format!("0x{:x}", fwmark))),
+        None => {}
+    }
+    if let Some(key) = &config.private_key {
+        keys.push(("PrivateKey", key.to_string()));
+    }
+
+    keys
+}
+
+/// The keys of a `[Peer]` section, `host` takes the place of a missing endpoint
+pub(crate) fn peer_keys(peer: &PeerConfig, host: Option<&HostEndpoint>) -> Vec<(&'static str, String)> {
+    let mut keys = vec![("PublicKey", peer.public_key.to_string())];
+    if let Some(key) = &peer.preshared_key {
+        keys.push(("PresharedKey", key.to_string()));
+    }
+    if !peer.allowed_ips.is_empty() {
+        let allowed_ips = peer.allowed_ips.iter().map(|ip| ip.to_string()).collect::<Vec<_>>();
+        keys.push(("AllowedIPs", allowed_ips.join(", ")));
+    }
+    match (peer.endpoint, host) {
+        (Some(endpoint), _) => keys.push(("Endpoint", endpoint.to_string())),
+        (None, Some(host)) => keys.push(("Endpoint", host.to_string())),
+        (None, None) => {}
+    }
+    match peer.persistent_keepalive_interval {
+        Some(0) => keys.push(("PersistentKeepalive", "off".to_owned())),
+        Some(interval) => keys.push(("PersistentKeepalive", interval.to_string())),
+        None => {}
+    }
+
+    keys
+}
+
+/// Writes `key = value` lines
+pub(crate) fn write_keys(output: &mut String, keys: &[(&'static str, String)]) {
+    for (key, value) in keys {
+        // writing to a String cannot fail
+        let _ = writeln!(output, "{} = {}", key, value);
+    }
+}
+
+/// Writes the keys of the `[Interface]` section known to wg(8), without the header
+pub(crate) fn write_interface(output: &mut String, config: &DeviceConfig) {
+    write_keys(output, &interface_keys(config));
+}
+
+/// Writes one `[Peer]` section per peer, each preceded by a blank line
+pub(crate) fn write_peers(output: &mut String, peers: &[PeerConfig]) {
+    for peer in peers {
+        output.push_str("\n[Peer]\n");
+        write_keys(output, &peer_keys(peer, None));
+    }
+}
+
+/// Writes the configuration of a device the way `wg showconf` does
+///
+/// Keys are base64 encoded, allowed ips keep the order reported by the kernel
+/// and values which are not set are left out. Feeding the result to [`parse`]
+/// and [`set_device`](crate::set_device) reproduces the device.
+///
+/// # Example
+///
+/// ```no_run
+/// use wgbind::{conf, get_device};
+///
+/// let device = get_device(&"wg0".parse().unwrap()).unwrap();
+/// std::fs::write("wg0.conf.backup", conf::showconf(&device)).unwrap();
+/// ```
+pub fn showconf(device: &Device) -> String {
+    let config = device.to_config();
+
+    let mut output = String::from("[Interface]\n");
+    write_interface(&mut output, &config);
+    write_peers(&mut output, &config.peers);
+
+    output
+}
+
+impl FromStr for DeviceConfig {
+    type Err = ParseError;
+
+    /// Same as [`parse`]
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wireguard_device::WireguardControl;
+
+    const PRIVATE: &str = "yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=";
+    const PUBLIC: &str = "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=";
+    const PSK: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+
+    fn error(input: &str) -> (usize, usize, ParseErrorKind) {
+        let error = parse(input).unwrap_err();
+        (error.line, error.column, error.kind)
+    }
+
+    #[test]
+    fn it_parses_a_setconf_file() {
+        let input = format!(
+            "# managed by ansible\n\
+             [Interface]\n\
+             PrivateKey = {PRIVATE}\n\
+             listenport=51820 # comment\n\
+             FwMark = 0x1234\n\
+             \n\
+             [peer]\n\
+             PublicKey = {PUBLIC}\n\
+             PresharedKey = {PSK}\n\
+             Endpoint = [2001:db8::1]:51820\n\
+             AllowedIPs = 10.0.0.0/24, fd00::/64\n\
+             AllowedIPs = 192.0.2.1\n\
+             PersistentKeepalive = off\n\
+             [Peer]\n\
+             PublicKey = {PRIVATE}\n\
+             AllowedIPs =\n"
+        );
+
+        let config = parse(&input).unwrap();
+        assert_eq!(config.private_key, Some(PRIVATE.parse().unwrap()));
+        assert_eq!(config.listen_port, Some(51820));
+        assert_eq!(config.fwmark, Some(0x1234));
+        assert!(config.replace_peers);
+        assert_eq!(config.peers.len(), 2);
+
+        let peer = &config.peers[0];
+        assert_eq!(peer.public_key, PUBLIC.parse().unwrap());
+        assert_eq!(peer.preshared_key, Some(PSK.parse().unwrap()));
+        assert_eq!(peer.endpoint, Some("[2001:db8::1]:51820".parse().unwrap()));
+        assert_eq!(peer.persistent_keepalive_interval, Some(0));
+        assert!(peer.replace_allowed_ips);
+        assert_eq!(
+            peer.allowed_ips,
+            ["10.0.0.0/24", "fd00::/64", "192.0.2.1/32"].map(|ip| ip.parse().unwrap())
+        );
+
+        assert!(config.peers[1].allowed_ips.is_empty());
+        assert_eq!(input.parse::<DeviceConfig>(), Ok(config.clone()));
+
+        let appended = parse_append(&input).unwrap();
+        assert!(!appended.replace_peers);
+        assert!(appended.peers.iter().all(|peer| !peer.replace_allowed_ips));
+        assert_eq!(replace_all(appended), config);
+    }
+
+    #[test]
+    fn it_resolves_endpoint_hostnames() {
+        let config = parse(&format!("[Peer]\nPublicKey = {PUBLIC}\nEndpoint = localhost:51820")).unwrap();
+        let endpoint = config.peers[0].endpoint.unwrap();
+        assert!(endpoint.ip().is_loopback());
+        assert_eq!(endpoint.port(), 51820);
+    }
+
+    #[test]
+    fn it_writes_showconf_output_which_parses_again() {
+        let config = DeviceConfig::new()
+            .private_key(PRIVATE.parse().unwrap())
+            .listen_port(51820)
+            .fwmark(0x1234)
+            .peer(
+                PeerConfig::new(PUBLIC.parse().unwrap())
+                    .preshared_key(PSK.parse().unwrap())
+                    .endpoint("[2001:db8::1]:51820".parse().unwrap())
+                    .persistent_keepalive_interval(25)
+                    .allowed_ips(["fd00::/64", "10.0.0.0/24"].map(|ip| ip.parse().unwrap())),
+            )
+            .peer(PeerConfig::new(PRIVATE.parse().unwrap()));
+
+        let raw = config.build(&"wg0".parse().unwrap());
+        let device = unsafe { Device::from_raw(&*raw.raw_device_ptr()) };
+
+        let output = showconf(&device);
+        assert_eq!(
+            output,
+            format!(
+                "[Interface]\n\
+                 ListenPort = 51820\n\
+                 FwMark = 0x1234\n\
+                 PrivateKey = {PRIVATE}\n\
+                 \n\
+                 [Peer]\n\
+                 PublicKey = {PUBLIC}\n\
+                 PresharedKey = {PSK}\n\
+                 AllowedIPs = fd00::/64, 10.0.0.0/24\n\
+                 Endpoint = [2001:db8::1]:51820\n\
+                 PersistentKeepalive = 25\n\
+                 \n\
+                 [Peer]\n\
+                 PublicKey = {PRIVATE}\n"
+            )
+        );
+
+        let mut expected = config;
+        expected.replace_peers = true;
+        for peer in &mut expected.peers {
+            peer.replace_allowed_ips = true;
+        }
+        expected.peers[1].persistent_keepalive_interval = None;
+        assert_eq!(parse(&output), Ok(expected));
+    }
+
+    #[test]
+    fn it_reports_line_and_column() {
+        assert_eq!(error("ListenPort = 1"), (1, 1, ParseErrorKind::OutsideSection));
+        assert_eq!(error("[Interface]\n  nonsense"), (2, 3, ParseErrorKind::ExpectedKeyValue));
+        assert_eq!(error("[Interface]\n[Tunnel]"), (2, 1, ParseErrorKind::UnknownSection("Tunnel".into())));
+        assert_eq!(error("[Interface]\nAddress = 10.0.0.1/24"), (2, 1, ParseErrorKind::UnknownKey("Address".into())));
+        assert_eq!(error("[Interface]\n[Peer]\nEndpoint = 192.0.2.1:1"), (2, 1, ParseErrorKind::MissingPublicKey));
+
+        let (line, column, kind) = error("[Interface]\nListenPort =  65536");
+        assert_eq!((line, column), (2, 15));
+        assert!(matches!(kind, ParseErrorKind::InvalidValue { key: "ListenPort", .. }));
+
+        let (line, column, kind) = error(&format!("[Peer]\nPublicKey = {PUBLIC}\nAllowedIPs = 10.0.0.0/24,  10.0.0.0/33"));
+        assert_eq!((line, column), (3, 28));
+        assert!(matches!(kind, ParseErrorKind::InvalidValue { key: "AllowedIPs", .. }));
+
+        let (_, column, kind) = error("[Peer]\nPublicKey = abc");
+        assert_eq!(column, 13);
+        assert!(matches!(kind, ParseErrorKind::InvalidValue { key: "PublicKey", .. }));
+    }
+}
@@ -0,0 +1,443 @@
+//! Configuration written to the kernel with [`set_device`](crate::set_device)
+//!
+//! The c library only looks at a field of `wg_device` or `wg_peer` if the
+//! matching `WGDEVICE_HAS_*`/`WGPEER_HAS_*` flag is set, and peers and allowed
+//! ips have to be handed over as linked lists. [`DeviceConfig`] and
+//! [`PeerConfig`] hide both: every value which is set ends up in the raw
+//! device together with its flag, everything else is left untouched.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use wgbind::{configure_device, DeviceConfig, PeerConfig, PrivateKey, PublicKey};
+//!
+//! let peer: PublicKey = "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=".parse().unwrap();
+//!
+//! let config = DeviceConfig::new()
+//!     .private_key(PrivateKey::generate())
+//!     .listen_port(51820)
+//!     .peer(
+//!         PeerConfig::new(peer)
+//!             .endpoint("192.0.2.1:51820".parse().unwrap())
+//!             .persistent_keepalive_interval(25)
+//!             .allowed_ip("10.0.0.0/24".parse().unwrap()),
+//!     );
+//!
+//! configure_device(&"wg0".parse().unwrap(), &config).unwrap();
+//! ```
+
+use std::net::SocketAddr;
+
+use wgbindraw_sys::*;
+
+use crate::allowed_ip::{self, AllowedIp};
+use crate::endpoint;
+use crate::interface_name::InterfaceName;
+use crate::key::{PresharedKey, PrivateKey, PublicKey};
+use crate::wireguard_device::WireguardDevice;
+
+/// Desired state of a single peer
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PeerConfig {
+    pub public_key: PublicKey,
+    /// `Some` of a zero key removes the preshared key
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub preshared_key: Option<PresharedKey>,
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub endpoint: Option<SocketAddr>,
+    /// keepalive interval in seconds, `Some(0)` disables it
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub persistent_keepalive_interval: Option<u16>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub allowed_ips: Vec<AllowedIp>,
+    /// removes the peer from the device instead of adding or updating it
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub remove: bool,
+    /// replaces the allowed ips of the peer instead of adding to them
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub replace_allowed_ips: bool,
+}
+
+impl PeerConfig {
+    pub fn new(public_key: PublicKey) -> Self {
+        Self {
+            public_key,
+            preshared_key: None,
+            endpoint: None,
+            persistent_keepalive_interval: None,
+            allowed_ips: Vec::new(),
+            remove: false,
+            replace_allowed_ips: false,
+        }
+    }
+
+    pub fn preshared_key(mut self, key: PresharedKey) -> Self {
+        self.preshared_key = Some(key);
+        self
+    }
+
+    pub fn endpoint(mut self, endpoint: SocketAddr) -> Self {
+        self.endpoint = Some(endpoint);
+        self
+    }
+
+    pub fn persistent_keepalive_interval(mut self, seconds: u16) -> Self {
+        self.persistent_keepalive_interval = Some(seconds);
+        self
+    }
+
+    pub fn allowed_ip(mut self, allowed_ip: AllowedIp) -> Self {
+        self.allowed_ips.push(allowed_ip);
+        self
+    }
+
+    pub fn allowed_ips(mut self, allowed_ips: impl IntoIterator<Item = AllowedIp>) -> Self {
+        self.allowed_ips.extend(allowed_ips);
+        self
+    }
+
+    pub fn remove(mut self, remove: bool) -> Self {
+        self.remove = remove;
+        self
+    }
+
+    pub fn replace_allowed_ips(mut self, replace: bool) -> Self {
+        self.replace_allowed_ips = replace;
+        self
+    }
+
+    /// A copy without the preshared key, e.g. for logging
+    pub fn redacted(&self) -> Self {
+        Self {
+            preshared_key: None,
+            ..self.clone()
+        }
+    }
+
+    pub(crate) fn flags(&self) -> wg_peer_flags {
+        let mut flags = wg_peer_flags::WGPEER_HAS_PUBLIC_KEY;
+
+        if self.preshared_key.is_some() {
+            flags |= wg_peer_flags::WGPEER_HAS_PRESHARED_KEY;
+        }
+        if self.persistent_keepalive_interval.is_some() {
+            flags |= wg_peer_flags::WGPEER_HAS_PERSISTENT_KEEPALIVE_INTERVAL;
+        }
+        if self.remove {
+            flags |= wg_peer_flags::WGPEER_REMOVE_ME;
+        }
+        if self.replace_allowed_ips {
+            flags |= wg_peer_flags::WGPEER_REPLACE_ALLOWEDIPS;
+        }
+
+        flags
+    }
+
+    fn to_raw(&self) -> wg_peer {
+        let mut peer: wg_peer = unsafe { std::mem::zeroed() };
+
+        peer.flags = self.flags();
+        peer.public_key = *self.public_key.as_bytes();
+        if let Some(key) = &self.preshared_key {
+            peer.preshared_key = *key.as_bytes();
+        }
+        if let Some(endpoint) = &self.endpoint {
+            peer.endpoint = endpoint::to_raw(endpoint);
+        }
+        peer.persistent_keepalive_interval = self.persistent_keepalive_interval.unwrap_or_default();
+
+        peer
+    }
+
+    /// Reads back what [`PeerConfig::to_raw`] wrote, the flags decide which values are set
+    ///
+    /// # Safety
+    ///
+    /// Every pointer of the allowed ip chain must be either NULL or point to a
+    /// valid `wg_allowedip`.
+    unsafe fn from_raw(raw: &wg_peer) -> Self {
+        let has = |flag: wg_peer_flags| raw.flags & flag == flag;
+
+        Self {
+            public_key: PublicKey::from_bytes(raw.public_key),
+            preshared_key: has(wg_peer_flags::WGPEER_HAS_PRESHARED_KEY)
+                .then(|| PresharedKey::from_bytes(raw.preshared_key)),
+            endpoint: endpoint::from_raw(&raw.endpoint),
+            persistent_keepalive_interval: has(wg_peer_flags::WGPEER_HAS_PERSISTENT_KEEPALIVE_INTERVAL)
+                .then_some(raw.persistent_keepalive_interval),
+            allowed_ips: unsafe { allowed_ip::read_chain(raw.first_allowedip) },
+            remove: has(wg_peer_flags::WGPEER_REMOVE_ME),
+            replace_allowed_ips: has(wg_peer_flags::WGPEER_REPLACE_ALLOWEDIPS),
+        }
+    }
+}
+
+/// Desired state of a device
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(default))]
+pub struct DeviceConfig {
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub private_key: Option<PrivateKey>,
+    pub listen_port: Option<u16>,
+    /// `Some(0)` removes the fwmark
+    pub fwmark: Option<u32>,
+    /// removes every peer not part of this configuration
+    pub replace_peers: bool,
+    pub peers: Vec<PeerConfig>,
+}
+
+impl DeviceConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn private_key(mut self, key: PrivateKey) -> Self {
+        self.private_key = Some(key);
+        self
+    }
+
+    pub fn listen_port(mut self, port: u16) -> Self {
+        self.listen_port = Some(port);
+        self
+    }
+
+    pub fn fwmark(mut self, fwmark: u32) -> Self {
+        self.fwmark = Some(fwmark);
+        self
+    }
+
+    pub fn replace_peers(mut self, replace: bool) -> Self {
+        self.replace_peers = replace;
+        self
+    }
+
+    pub fn peer(mut self, peer: PeerConfig) -> Self {
+        self.peers.push(peer);
+        self
+    }
+
+    pub fn peers(mut self, peers: impl IntoIterator<Item = PeerConfig>) -> Self {
+        self.peers.extend(peers);
+        self
+    }
+
+    /// A copy which only adds to a device, like `wg addconf`
+    ///
+    /// Clears `replace_peers` and `replace_allowed_ips` of every peer, so peers
+    /// missing from the configuration stay and the allowed ips of existing
+    /// peers are added to the ones they have already.
+    pub fn incremental(&self) -> Self {
+        Self {
+            replace_peers: false,
+            peers: self
+                .peers
+                .iter()
+                .map(|peer| PeerConfig {
+                    replace_allowed_ips: false,
+                    ..peer.clone()
+                })
+                .collect(),
+            ..self.clone()
+        }
+    }
+
+    /// A copy without private and preshared keys, e.g. for logging
+    ///
+    /// Serialized, the missing keys are skipped entirely. Note that applying a
+    /// redacted configuration keeps the keys of the device as they are.
+    pub fn redacted(&self) -> Self {
+        Self {
+            private_key: None,
+            peers: self.peers.iter().map(PeerConfig::redacted).collect(),
+            ..self.clone()
+        }
+    }
+
+    pub(crate) fn flags(&self) -> wg_device_flags {
+        let mut flags = wg_device_flags(0);
+
+        if self.private_key.is_some() {
+            flags |= wg_device_flags::WGDEVICE_HAS_PRIVATE_KEY;
+        }
+        if self.listen_port.is_some() {
+            flags |= wg_device_flags::WGDEVICE_HAS_LISTEN_PORT;
+        }
+        if self.fwmark.is_some() {
+            flags |= wg_device_flags::WGDEVICE_HAS_FWMARK;
+        }
+        if self.replace_peers {
+            flags |= wg_device_flags::WGDEVICE_REPLACE_PEERS;
+        }
+
+        flags
+    }
+
+    /// Assembles a rust allocated wg_device including the peer and allowed ip lists
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of the Network Interface e.g. wg0
+    pub fn build(&self, name: &InterfaceName) -> WireguardDevice {
+        let mut raw: wg_device = unsafe { std::mem::zeroed() };
+
+        raw.name = name.to_raw();
+        raw.flags = self.flags();
+        if let Some(key) = &self.private_key {
+            raw.private_key = *key.as_bytes();
+        }
+        raw.listen_port = self.listen_port.unwrap_or_default();
+        raw.fwmark = self.fwmark.unwrap_or_default();
+
+        let mut device = WireguardDevice::new(raw);
+        for peer in &self.peers {
+            device.push_peer(peer.to_raw(), peer.allowed_ips.iter().map(|ip| ip.to_raw()));
+        }
+
+        device
+    }
+
+    /// Reads back what [`DeviceConfig::build`] assembled, the flags decide which values are set
+    ///
+    /// This is how a raw device goes to a [`Backend`](crate::Backend), which only
+    /// takes configurations.
+    ///
+    /// # Safety
+    ///
+    /// Every pointer of the peer chain and of each allowed ip chain must be
+    /// either NULL or point to a valid element.
+    pub(crate) unsafe fn from_raw(raw: &wg_device) -> Self {
+        let has = |flag: wg_device_flags| raw.flags & flag == flag;
+
+        let mut peers = Vec::new();
+        let mut current = raw.first_peer;
+        while let Some(peer) = unsafe { current.as_ref() } {
+            peers.push(unsafe { PeerConfig::from_raw(peer) });
+            current = peer.next_peer;
+        }
+
+        Self {
+            private_key: has(wg_device_flags::WGDEVICE_HAS_PRIVATE_KEY)
+                .then(|| PrivateKey::from_bytes(raw.private_key)),
+            listen_port: has(wg_device_flags::WGDEVICE_HAS_LISTEN_PORT).then_some(raw.listen_port),
+            fwmark: has(wg_device_flags::WGDEVICE_HAS_FWMARK).then_some(raw.fwmark),
+            replace_peers: has(wg_device_flags::WGDEVICE_REPLACE_PEERS),
+            peers,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wireguard_device::WireguardControl;
+    use crate::Device;
+
+    fn peer() -> PeerConfig {
+        PeerConfig::new(PublicKey::from_bytes([2; 32]))
+            .preshared_key(PresharedKey::from_bytes([3; 32]))
+            .endpoint("192.0.2.1:51820".parse().unwrap())
+            .allowed_ip("10.0.0.0/24".parse().unwrap())
+            .allowed_ip("fd00::/64".parse().unwrap())
+    }
+
+    #[test]
+    fn it_sets_flags_for_every_value() {
+        assert_eq!(DeviceConfig::new().flags(), wg_device_flags(0));
+        assert_eq!(
+            DeviceConfig::new().listen_port(0).fwmark(0).flags(),
+            wg_device_flags::WGDEVICE_HAS_LISTEN_PORT | wg_device_flags::WGDEVICE_HAS_FWMARK
+        );
+
+        assert_eq!(
+            peer().flags(),
+            wg_peer_flags::WGPEER_HAS_PUBLIC_KEY | wg_peer_flags::WGPEER_HAS_PRESHARED_KEY
+        );
+        assert_eq!(
+            peer().persistent_keepalive_interval(0).remove(true).replace_allowed_ips(true).flags(),
+            wg_peer_flags::WGPEER_HAS_PUBLIC_KEY
+                | wg_peer_flags::WGPEER_HAS_PRESHARED_KEY
+                | wg_peer_flags::WGPEER_HAS_PERSISTENT_KEEPALIVE_INTERVAL
+                | wg_peer_flags::WGPEER_REMOVE_ME
+                | wg_peer_flags::WGPEER_REPLACE_ALLOWEDIPS
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn it_deserializes_partial_configurations() {
+        let json = serde_json::json!({
+            "listen_port": 51820,
+            "peers": [{
+                "public_key": PublicKey::from_bytes([2; 32]).to_base64(),
+                "endpoint": "192.0.2.1:51820",
+                "allowed_ips": ["10.0.0.0/24"]
+            }]
+        });
+
+        let config: DeviceConfig = serde_json::from_value(json).unwrap();
+        assert_eq!(config.private_key, None);
+        assert_eq!(config.listen_port, Some(51820));
+        assert_eq!(config.peers[0].endpoint, Some("192.0.2.1:51820".parse().unwrap()));
+        assert!(!config.peers[0].replace_allowed_ips);
+
+        let config = config.private_key(PrivateKey::from_bytes([1; 32]));
+        let json = serde_json::to_value(config.redacted()).unwrap();
+        assert!(json.get("private_key").is_none());
+    }
+
+    #[test]
+    fn it_clears_replace_flags_for_incremental_updates() {
+        let config = DeviceConfig::new()
+            .replace_peers(true)
+            .peer(peer().replace_allowed_ips(true).remove(true))
+            .incremental();
+
+        assert_eq!(config.flags(), wg_device_flags(0));
+        assert_eq!(
+            config.peers[0].flags(),
+            wg_peer_flags::WGPEER_HAS_PUBLIC_KEY
+                | wg_peer_flags::WGPEER_HAS_PRESHARED_KEY
+                | wg_peer_flags::WGPEER_REMOVE_ME
+        );
+        assert_eq!(config.peers[0].allowed_ips, peer().allowed_ips);
+    }
+
+    #[test]
+    fn it_builds_the_linked_lists() {
+        let config = DeviceConfig::new()
+            .private_key(PrivateKey::from_bytes([1; 32]))
+            .listen_port(51820)
+            .replace_peers(true)
+            .peer(peer())
+            .peer(PeerConfig::new(PublicKey::from_bytes([4; 32])));
+
+        let device = config.build(&"wg0".parse().unwrap());
+        assert_eq!(device.name().unwrap().as_str(), "wg0");
+        assert_eq!(
+            device.flags(),
+            wg_device_flags::WGDEVICE_HAS_PRIVATE_KEY
+                | wg_device_flags::WGDEVICE_HAS_LISTEN_PORT
+                | wg_device_flags::WGDEVICE_REPLACE_PEERS
+        );
+
+        let snapshot = unsafe { Device::from_raw(&*device.raw_device_ptr()) };
+        assert_eq!(snapshot.listen_port(), 51820);
+        assert_eq!(snapshot.peers().len(), 2);
+        assert_eq!(snapshot.peers()[0].public_key(), &PublicKey::from_bytes([2; 32]));
+        assert_eq!(snapshot.peers()[0].allowed_ips(), peer().allowed_ips.as_slice());
+        assert!(snapshot.peers()[1].allowed_ips().is_empty());
+    }
+
+    #[test]
+    fn it_reads_back_built_devices() {
+        let config = DeviceConfig::new()
+            .fwmark(0)
+            .replace_peers(true)
+            .peer(peer().persistent_keepalive_interval(25).replace_allowed_ips(true))
+            .peer(PeerConfig::new(PublicKey::from_bytes([4; 32])).remove(true));
+
+        let device = config.build(&"wg0".parse().unwrap());
+        assert_eq!(unsafe { DeviceConfig::from_raw(&*device.raw_device_ptr()) }, config);
+    }
+}
@@ -0,0 +1,245 @@
+//! Rust port of the key helpers of the c library
+//!
+//! Used instead of the c functions with the `netlink` feature. The functions keep
+//! the names and signatures of the bindings so the callers in [`crate::key`] do not
+//! change, and they compute the very same bytes: constant time base64 and the
+//! TweetNaCl style curve25519 ladder of wireguard.c.
+
+use std::hint::black_box;
+
+use wgbindraw_sys::{wg_key, wg_key_b64_string};
+
+use crate::key::zeroize;
+
+type Fe = [i64; 16];
+
+pub(crate) unsafe fn wg_key_is_zero(key: *mut wg_key) -> bool {
+    let mut acc: u8 = 0;
+    for byte in unsafe { &*key } {
+        acc = black_box(acc | byte);
+    }
+
+    1 & ((acc as i32 - 1) >> 8) != 0
+}
+
+fn encode_base64(dest: &mut [::core::ffi::c_char], src: [u8; 3]) {
+    let input = [
+        (src[0] >> 2) & 63,
+        ((src[0] << 4) | (src[1] >> 4)) & 63,
+        ((src[1] << 2) | (src[2] >> 6)) & 63,
+        src[2] & 63,
+    ];
+
+    for (d, i) in dest.iter_mut().zip(input) {
+        let i = i as i32;
+        *d = (i + 'A' as i32 + (((25 - i) >> 8) & 6) - (((51 - i) >> 8) & 75)
+            - (((61 - i) >> 8) & 15)
+            + (((62 - i) >> 8) & 3)) as ::core::ffi::c_char;
+    }
+}
+
+pub(crate) unsafe fn wg_key_to_base64(base64: *mut wg_key_b64_string, key: *mut wg_key) {
+    let (base64, key) = unsafe { (&mut *base64, &*key) };
+
+    for i in 0..32 / 3 {
+        encode_base64(&mut base64[i * 4..], [key[i * 3], key[i * 3 + 1], key[i * 3 + 2]]);
+    }
+    encode_base64(&mut base64[40..], [key[30], key[31], 0]);
+    base64[43] = b'=' as ::core::ffi::c_char;
+    base64[44] = 0;
+}
+
+fn decode_base64(src: &[::core::ffi::c_char]) -> i32 {
+    let mut val = 0;
+    for (i, c) in src[..4].iter().enumerate() {
+        let c = *c as i32;
+        val |= (-1
+            + (((('A' as i32 - 1) - c) & (c - ('Z' as i32 + 1))) >> 8 & (c - 64))
+            + (((('a' as i32 - 1) - c) & (c - ('z' as i32 + 1))) >> 8 & (c - 70))
+            + (((('0' as i32 - 1) - c) & (c - ('9' as i32 + 1))) >> 8 & (c + 5))
+            + (((('+' as i32 - 1) - c) & (c - ('+' as i32 + 1))) >> 8 & 63)
+            + (((('/' as i32 - 1) - c) & (c - ('/' as i32 + 1))) >> 8 & 64))
+            << (18 - 6 * i);
+    }
+    val
+}
+
+pub(crate) unsafe fn wg_key_from_base64(key: *mut wg_key, base64: *mut wg_key_b64_string) -> ::core::ffi::c_int {
+    let (key, base64) = unsafe { (&mut *key, &*base64) };
+
+    let length = base64.iter().position(|c| *c == 0).unwrap_or(base64.len());
+    if length != base64.len() - 1 || base64[43] != b'=' as ::core::ffi::c_char {
+        return -libc::EINVAL;
+    }
+
+    let mut ret: u8 = 0;
+    for i in 0..32 / 3 {
+        let val = decode_base64(&base64[i * 4..]);
+        ret = black_box(ret | (val as u32 >> 31) as u8);
+        key[i * 3] = (val >> 16) as u8;
+        key[i * 3 + 1] = (val >> 8) as u8;
+        key[i * 3 + 2] = val as u8;
+    }
+    let val = decode_base64(&[base64[40], base64[41], base64[42], b'A' as ::core::ffi::c_char]);
+    ret = black_box(ret | (val as u32 >> 31) as u8 | (val & 0xff) as u8);
+    key[30] = (val >> 16) as u8;
+    key[31] = (val >> 8) as u8;
+
+    -(libc::EINVAL & !((ret as i32 - 1) >> 8))
+}
+
+fn carry(o: &mut Fe) {
+    for i in 0..16 {
+        o[(i + 1) % 16] += if i == 15 { 38 } else { 1 } * (o[i] >> 16);
+        o[i] &= 0xffff;
+    }
+}
+
+fn cswap(p: &mut Fe, q: &mut Fe, b: i64) {
+    let c = !(b - 1);
+    for (p, q) in p.iter_mut().zip(q.iter_mut()) {
+        let t = c & (*p ^ *q);
+        *p ^= t;
+        *q ^= t;
+    }
+}
+
+fn pack(o: &mut wg_key, n: &Fe) {
+    let mut t = *n;
+    let mut m: Fe = [0; 16];
+    carry(&mut t);
+    carry(&mut t);
+    carry(&mut t);
+    for _ in 0..2 {
+        m[0] = t[0] - 0xffed;
+        for i in 1..15 {
+            m[i] = t[i] - 0xffff - ((m[i - 1] >> 16) & 1);
+            m[i - 1] &= 0xffff;
+        }
+        m[15] = t[15] - 0x7fff - ((m[14] >> 16) & 1);
+        let b = (m[15] >> 16) & 1;
+        m[14] &= 0xffff;
+        cswap(&mut t, &mut m, 1 - b);
+    }
+    for i in 0..16 {
+        o[2 * i] = t[i] as u8;
+        o[2 * i + 1] = (t[i] >> 8) as u8;
+    }
+
+    zeroize(&mut m);
+    zeroize(&mut t);
+}
+
+fn add(a: &Fe, b: &Fe) -> Fe {
+    std::array::from_fn(|i| a[i] + b[i])
+}
+
+fn subtract(a: &Fe, b: &Fe) -> Fe {
+    std::array::from_fn(|i| a[i] - b[i])
+}
+
+fn multmod(a: &Fe, b: &Fe) -> Fe {
+    let mut t = [0i64; 31];
+    for i in 0..16 {
+        for j in 0..16 {
+            t[i + j] += a[i] * b[j];
+        }
+    }
+    for i in 0..15 {
+        t[i] += 38 * t[i + 16];
+    }
+
+    let mut o: Fe = [0; 16];
+    o.copy_from_slice(&t[..16]);
+    carry(&mut o);
+    carry(&mut o);
+
+    zeroize(&mut t);
+    o
+}
+
+fn invert(i: &Fe) -> Fe {
+    let mut c = *i;
+    for a in (0..=253).rev() {
+        c = multmod(&c, &c);
+        if a != 2 && a != 4 {
+            c = multmod(&c, i);
+        }
+    }
+    c
+}
+
+fn clamp_key(z: &mut wg_key) {
+    z[31] = (z[31] & 127) | 64;
+    z[0] &= 248;
+}
+
+pub(crate) unsafe fn wg_generate_public_key(public_key: *mut wg_key, private_key: *mut wg_key) {
+    let mut z = unsafe { *private_key };
+    clamp_key(&mut z);
+
+    let mut a: Fe = [0; 16];
+    let mut b: Fe = [0; 16];
+    let mut c: Fe = [0; 16];
+    let mut d: Fe = [0; 16];
+    a[0] = 1;
+    b[0] = 9;
+    d[0] = 1;
+    let mut constant: Fe = [0; 16];
+    constant[0] = 0xdb41;
+    constant[1] = 1;
+    let nine = b;
+
+    for i in (0..=254).rev() {
+        let r = ((z[i >> 3] >> (i & 7)) & 1) as i64;
+        cswap(&mut a, &mut b, r);
+        cswap(&mut c, &mut d, r);
+        let mut e = add(&a, &c);
+        a = subtract(&a, &c);
+        c = add(&b, &d);
+        b = subtract(&b, &d);
+        d = multmod(&e, &e);
+        let f = multmod(&a, &a);
+        a = multmod(&c, &a);
+        c = multmod(&b, &e);
+        e = add(&a, &c);
+        a = subtract(&a, &c);
+        b = multmod(&a, &a);
+        c = subtract(&d, &f);
+        a = multmod(&c, &constant);
+        a = add(&a, &d);
+        c = multmod(&c, &a);
+        a = multmod(&d, &f);
+        d = multmod(&b, &nine);
+        b = multmod(&e, &e);
+        cswap(&mut a, &mut b, r);
+        cswap(&mut c, &mut d, r);
+    }
+    c = invert(&c);
+    a = multmod(&a, &c);
+    pack(unsafe { &mut *public_key }, &a);
+
+    zeroize(&mut z);
+    zeroize(&mut a);
+    zeroize(&mut b);
+    zeroize(&mut c);
+    zeroize(&mut d);
+}
+
+pub(crate) unsafe fn wg_generate_private_key(private_key: *mut wg_key) {
+    unsafe { wg_generate_preshared_key(private_key) };
+    clamp_key(unsafe { &mut *private_key });
+}
+
+pub(crate) unsafe fn wg_generate_preshared_key(preshared_key: *mut wg_key) {
+    let key = unsafe { &mut *preshared_key };
+    let read = unsafe { libc::getrandom(key.as_mut_ptr().cast(), key.len(), 0) };
+    if read == key.len() as isize {
+        return;
+    }
+
+    use std::io::Read;
+    std::fs::File::open("/dev/urandom")
+        .and_then(|mut random| random.read_exact(key))
+        .expect("/dev/urandom must be readable");
+}
@@ -0,0 +1,450 @@
+//! Owned snapshot of a wireguard device
+//!
+//! `wg_get_device` hands out a `wg_device` whose peers and allowed ips are
+//! linked lists in memory owned by the c library. The types in this module
+//! copy everything out of these lists, so once a snapshot exists the c
+//! allocation can be released and no raw pointer is left behind.
+//!
+//! With the `serde` feature snapshots serialize with base64 keys and string
+//! endpoints and allowed ips, see [`Device::redacted`] to leave out secrets.
+
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use wgbindraw_sys::*;
+
+use crate::allowed_ip::{self, AllowedIp};
+use crate::config::{DeviceConfig, PeerConfig};
+use crate::endpoint;
+use crate::key::{PresharedKey, PrivateKey, PublicKey};
+use crate::wireguard_device::WireguardDevice;
+
+/// Session keys older than this are rejected, REJECT_AFTER_TIME of the wireguard paper.
+/// A peer without a handshake within this window has no working session.
+pub const REJECT_AFTER_TIME: Duration = Duration::from_secs(180);
+
+/// A peer of a wireguard device as reported by the kernel
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Peer {
+    pub(crate) public_key: PublicKey,
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub(crate) preshared_key: Option<PresharedKey>,
+    pub(crate) endpoint: Option<SocketAddr>,
+    pub(crate) last_handshake_time: Option<SystemTime>,
+    pub(crate) rx_bytes: u64,
+    pub(crate) tx_bytes: u64,
+    pub(crate) persistent_keepalive_interval: u16,
+    pub(crate) allowed_ips: Vec<AllowedIp>,
+}
+
+impl Peer {
+    pub fn public_key(&self) -> &PublicKey {
+        &self.public_key
+    }
+
+    /// `None` if the peer has no preshared key configured
+    pub fn preshared_key(&self) -> Option<&PresharedKey> {
+        self.preshared_key.as_ref()
+    }
+
+    /// `None` if the peer has no endpoint yet
+    pub fn endpoint(&self) -> Option<SocketAddr> {
+        self.endpoint
+    }
+
+    /// `None` if there was no handshake with this peer yet
+    pub fn last_handshake_time(&self) -> Option<SystemTime> {
+        self.last_handshake_time
+    }
+
+    /// Time passed since the last handshake, `None` if there was none
+    ///
+    /// A handshake time in the future, e.g. after the clock was set back, counts as just now.
+    pub fn time_since_last_handshake(&self) -> Option<Duration> {
+        self.time_since_last_handshake_at(SystemTime::now())
+    }
+
+    /// true if there was no handshake within [`REJECT_AFTER_TIME`]
+    ///
+    /// The kernel retries a handshake well before the session expires, hence a stale
+    /// peer is usually offline or misconfigured.
+    pub fn is_stale(&self) -> bool {
+        self.is_stale_at(SystemTime::now())
+    }
+
+    fn time_since_last_handshake_at(&self, now: SystemTime) -> Option<Duration> {
+        self.last_handshake_time
+            .map(|time| now.duration_since(time).unwrap_or_default())
+    }
+
+    fn is_stale_at(&self, now: SystemTime) -> bool {
+        self.time_since_last_handshake_at(now)
+            .is_none_or(|elapsed| elapsed > REJECT_AFTER_TIME)
+    }
+
+    /// bytes received from this peer
+    pub fn rx_bytes(&self) -> u64 {
+        self.rx_bytes
+    }
+
+    /// bytes sent to this peer
+    pub fn tx_bytes(&self) -> u64 {
+        self.tx_bytes
+    }
+
+    /// keepalive interval in seconds, 0 if disabled
+    pub fn persistent_keepalive_interval(&self) -> u16 {
+        self.persistent_keepalive_interval
+    }
+
+    /// keepalive interval, `None` if disabled
+    pub fn persistent_keepalive(&self) -> Option<Duration> {
+        (self.persistent_keepalive_interval != 0)
+            .then(|| Duration::from_secs(self.persistent_keepalive_interval.into()))
+    }
+
+    pub fn allowed_ips(&self) -> &[AllowedIp] {
+        &self.allowed_ips
+    }
+
+    /// A configuration which sets this peer including its allowed ips
+    ///
+    /// A keepalive interval of 0 and a missing preshared key are left out like
+    /// `wg showconf` does, an existing peer keeps its own.
+    pub fn to_config(&self) -> PeerConfig {
+        PeerConfig {
+            public_key: self.public_key,
+            preshared_key: self.preshared_key.clone(),
+            endpoint: self.endpoint,
+            persistent_keepalive_interval: (self.persistent_keepalive_interval != 0)
+                .then_some(self.persistent_keepalive_interval),
+            allowed_ips: self.allowed_ips.clone(),
+            remove: false,
+            replace_allowed_ips: true,
+        }
+    }
+
+    /// Copies a raw `wg_peer` including its `next_allowedip` chain.
+    ///
+    /// # Safety
+    ///
+    /// Every pointer of the allowed ip chain must be either NULL or point to a
+    /// valid `wg_allowedip`.
+    pub(crate) unsafe fn from_raw(raw: &wg_peer) -> Self {
+        let allowed_ips = unsafe { allowed_ip::read_chain(raw.first_allowedip) };
+
+        let preshared_key = PresharedKey::from_bytes(raw.preshared_key);
+
+        Self {
+            public_key: PublicKey::from_bytes(raw.public_key),
+            preshared_key: (!preshared_key.is_zero()).then_some(preshared_key),
+            endpoint: endpoint::from_raw(&raw.endpoint),
+            last_handshake_time: handshake_time(&raw.last_handshake_time),
+            rx_bytes: raw.rx_bytes,
+            tx_bytes: raw.tx_bytes,
+            persistent_keepalive_interval: raw.persistent_keepalive_interval,
+            allowed_ips,
+        }
+    }
+}
+
+impl Peer {
+    /// The raw peer as wg_get_device hands it out, without allowed ips
+    fn to_raw(&self) -> wg_peer {
+        let mut raw: wg_peer = unsafe { std::mem::zeroed() };
+
+        raw.flags = wg_peer_flags::WGPEER_HAS_PUBLIC_KEY;
+        raw.public_key = *self.public_key.as_bytes();
+        if let Some(key) = &self.preshared_key {
+            raw.flags |= wg_peer_flags::WGPEER_HAS_PRESHARED_KEY;
+            raw.preshared_key = *key.as_bytes();
+        }
+        if let Some(endpoint) = &self.endpoint {
+            raw.endpoint = endpoint::to_raw(endpoint);
+        }
+        if let Some(since_epoch) = self.last_handshake_time.and_then(|time| time.duration_since(UNIX_EPOCH).ok()) {
+            raw.last_handshake_time = timespec64 {
+                tv_sec: since_epoch.as_secs() as i64,
+                tv_nsec: since_epoch.subsec_nanos() as i64,
+            };
+        }
+        raw.rx_bytes = self.rx_bytes;
+        raw.tx_bytes = self.tx_bytes;
+        raw.persistent_keepalive_interval = self.persistent_keepalive_interval;
+
+        raw
+    }
+}
+
+/// A zero timestamp means there was no handshake
+fn handshake_time(raw: &timespec64) -> Option<SystemTime> {
+    if raw.tv_sec == 0 && raw.tv_nsec == 0 {
+        return None;
+    }
+
+    let since_epoch = Duration::new(raw.tv_sec.max(0) as u64, raw.tv_nsec.clamp(0, 999_999_999) as u32);
+    UNIX_EPOCH.checked_add(since_epoch)
+}
+
+/// Owned copy of a `wg_device` and all of its peers
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Device {
+    pub(crate) name: String,
+    pub(crate) ifindex: u32,
+    pub(crate) public_key: Option<PublicKey>,
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub(crate) private_key: Option<PrivateKey>,
+    pub(crate) fwmark: u32,
+    pub(crate) listen_port: u16,
+    pub(crate) peers: Vec<Peer>,
+}
+
+impl Device {
+    /// name of the network interface e.g. wg0
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn ifindex(&self) -> u32 {
+        self.ifindex
+    }
+
+    pub fn public_key(&self) -> Option<&PublicKey> {
+        self.public_key.as_ref()
+    }
+
+    pub fn private_key(&self) -> Option<&PrivateKey> {
+        self.private_key.as_ref()
+    }
+
+    pub fn fwmark(&self) -> u32 {
+        self.fwmark
+    }
+
+    pub fn listen_port(&self) -> u16 {
+        self.listen_port
+    }
+
+    pub fn peers(&self) -> &[Peer] {
+        &self.peers
+    }
+
+    /// A copy without private and preshared keys, e.g. for logging or storing state
+    ///
+    /// Serialized, the missing keys are skipped entirely.
+    pub fn redacted(&self) -> Self {
+        Self {
+            private_key: None,
+            peers: self
+                .peers
+                .iter()
+                .map(|peer| Peer {
+                    preshared_key: None,
+                    ..peer.clone()
+                })
+                .collect(),
+            ..self.clone()
+        }
+    }
+
+    /// The configuration `wg showconf` writes for this device
+    ///
+    /// Replaces all peers and their allowed ips. A listen port, fwmark or
+    /// keepalive interval of 0 and a missing private or preshared key are left
+    /// out, so applied to another device these keep whatever that device has.
+    pub fn to_config(&self) -> DeviceConfig {
+        DeviceConfig {
+            private_key: self.private_key.clone(),
+            listen_port: (self.listen_port != 0).then_some(self.listen_port),
+            fwmark: (self.fwmark != 0).then_some(self.fwmark),
+            replace_peers: true,
+            peers: self.peers.iter().map(Peer::to_config).collect(),
+        }
+    }
+
+    /// A rust allocated raw device equal to the one wg_get_device hands out
+    ///
+    /// Used where a [`Backend`](crate::Backend) returned the snapshot but a raw
+    /// device is needed, e.g. [`WireguardControl::update_device`](crate::wireguard_device::WireguardControl::update_device).
+    pub(crate) fn to_raw(&self) -> WireguardDevice {
+        let mut raw: wg_device = unsafe { std::mem::zeroed() };
+
+        for (c, byte) in raw.name[..libc::IFNAMSIZ - 1].iter_mut().zip(self.name.bytes()) {
+            *c = byte as ::core::ffi::c_char;
+        }
+        raw.ifindex = self.ifindex;
+        if let Some(key) = &self.public_key {
+            raw.flags |= wg_device_flags::WGDEVICE_HAS_PUBLIC_KEY;
+            raw.public_key = *key.as_bytes();
+        }
+        if let Some(key) = &self.private_key {
+            raw.flags |= wg_device_flags::WGDEVICE_HAS_PRIVATE_KEY;
+            raw.private_key = *key.as_bytes();
+        }
+        raw.fwmark = self.fwmark;
+        raw.listen_port = self.listen_port;
+
+        let mut device = WireguardDevice::new(raw);
+        for peer in &self.peers {
+            device.push_peer(peer.to_raw(), peer.allowed_ips.iter().map(|ip| ip.to_raw()));
+        }
+
+        device
+    }
+
+    /// Copies a raw `wg_device` by walking its `next_peer` chain.
+    ///
+    /// The raw device is left untouched, releasing it is up to the caller.
+    ///
+    /// # Safety
+    ///
+    /// Every pointer of the peer chain and of each allowed ip chain must be
+    /// either NULL or point to a valid element.
+    pub(crate) unsafe fn from_raw(raw: &wg_device) -> Self {
+        let name = raw
+            .name
+            .iter()
+            .take_while(|c| **c != 0)
+            .map(|c| *c as u8)
+            .collect::<Vec<u8>>();
+
+        let mut peers = Vec::new();
+        let mut current = raw.first_peer;
+        while let Some(peer) = unsafe { current.as_ref() } {
+            peers.push(unsafe { Peer::from_raw(peer) });
+            current = peer.next_peer;
+        }
+
+        let has = |flag: wg_device_flags| raw.flags & flag == flag;
+
+        Self {
+            name: String::from_utf8_lossy(&name).into_owned(),
+            ifindex: raw.ifindex,
+            public_key: has(wg_device_flags::WGDEVICE_HAS_PUBLIC_KEY)
+                .then(|| PublicKey::from_bytes(raw.public_key)),
+            private_key: has(wg_device_flags::WGDEVICE_HAS_PRIVATE_KEY)
+                .then(|| PrivateKey::from_bytes(raw.private_key)),
+            fwmark: raw.fwmark,
+            listen_port: raw.listen_port,
+            peers,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_copies_the_linked_lists() {
+        let mut second: wg_allowedip = unsafe { std::mem::zeroed() };
+        second.family = libc::AF_INET6 as u16;
+        second.cidr = 64;
+        unsafe { second.__bindgen_anon_1.ip6.as_mut() }.s6_addr[0] = 0xfd;
+
+        let mut first: wg_allowedip = unsafe { std::mem::zeroed() };
+        first.family = libc::AF_INET as u16;
+        first.cidr = 24;
+        unsafe { first.__bindgen_anon_1.ip4.as_mut() }.s_addr = u32::to_be(0x0a00_0000);
+        first.next_allowedip = &mut second;
+
+        let mut peer: wg_peer = unsafe { std::mem::zeroed() };
+        peer.public_key = [7; 32];
+        peer.rx_bytes = 42;
+        peer.endpoint = endpoint::to_raw(&"192.0.2.1:51820".parse().unwrap());
+        peer.first_allowedip = &mut first;
+        peer.last_allowedip = &mut second;
+
+        let mut raw: wg_device = unsafe { std::mem::zeroed() };
+        raw.name[..3].copy_from_slice(&[b'w' as _, b'g' as _, b'0' as _]);
+        raw.flags = wg_device_flags::WGDEVICE_HAS_PUBLIC_KEY;
+        raw.public_key = [1; 32];
+        raw.first_peer = &mut peer;
+        raw.last_peer = &mut peer;
+
+        let device = unsafe { Device::from_raw(&raw) };
+
+        assert_eq!(device.name(), "wg0");
+        assert_eq!(device.public_key(), Some(&PublicKey::from_bytes([1; 32])));
+        assert_eq!(device.private_key(), None);
+        assert_eq!(device.peers().len(), 1);
+
+        let peer = &device.peers()[0];
+        assert_eq!(peer.public_key().as_bytes(), &[7; 32]);
+        assert!(peer.preshared_key().is_none());
+        assert_eq!(peer.rx_bytes(), 42);
+        assert_eq!(peer.endpoint(), Some("192.0.2.1:51820".parse().unwrap()));
+        assert_eq!(
+            peer.allowed_ips(),
+            &[
+                "10.0.0.0/24".parse().unwrap(),
+                "fd00::/64".parse().unwrap(),
+            ]
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn it_serializes_to_json() {
+        let mut raw: wg_peer = unsafe { std::mem::zeroed() };
+        raw.public_key = [7; 32];
+        raw.preshared_key = [3; 32];
+        raw.endpoint = endpoint::to_raw(&"192.0.2.1:51820".parse().unwrap());
+
+        let device = Device {
+            name: "wg0".into(),
+            ifindex: 3,
+            public_key: Some(PublicKey::from_bytes([1; 32])),
+            private_key: Some(PrivateKey::from_bytes([2; 32])),
+            fwmark: 0,
+            listen_port: 51820,
+            peers: vec![Peer {
+                allowed_ips: vec!["10.0.0.0/24".parse().unwrap()],
+                ..unsafe { Peer::from_raw(&raw) }
+            }],
+        };
+
+        let json = serde_json::to_value(&device).unwrap();
+        assert_eq!(json["private_key"], PrivateKey::from_bytes([2; 32]).to_base64());
+        assert_eq!(json["peers"][0]["endpoint"], "192.0.2.1:51820");
+        assert_eq!(json["peers"][0]["allowed_ips"][0], "10.0.0.0/24");
+
+        let parsed: Device = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed.private_key(), device.private_key());
+        assert_eq!(parsed.peers()[0].preshared_key(), device.peers()[0].preshared_key());
+
+        let json = serde_json::to_value(device.redacted()).unwrap();
+        assert!(json.get("private_key").is_none());
+        assert!(json["peers"][0].get("preshared_key").is_none());
+        assert_eq!(json["public_key"], PublicKey::from_bytes([1; 32]).to_base64());
+    }
+
+    #[test]
+    fn it_reports_handshake_and_keepalive() {
+        let mut raw: wg_peer = unsafe { std::mem::zeroed() };
+        raw.persistent_keepalive_interval = 25;
+        raw.tx_bytes = 1024;
+
+        let peer = unsafe { Peer::from_raw(&raw) };
+        assert_eq!(peer.last_handshake_time(), None);
+        assert_eq!(peer.time_since_last_handshake(), None);
+        assert!(peer.is_stale());
+        assert_eq!(peer.persistent_keepalive(), Some(Duration::from_secs(25)));
+        assert_eq!(peer.tx_bytes(), 1024);
+
+        raw.persistent_keepalive_interval = 0;
+        raw.last_handshake_time = timespec64 { tv_sec: 1_700_000_000, tv_nsec: 500 };
+        let peer = unsafe { Peer::from_raw(&raw) };
+        let handshake = UNIX_EPOCH + Duration::new(1_700_000_000, 500);
+        assert_eq!(peer.last_handshake_time(), Some(handshake));
+        assert_eq!(peer.persistent_keepalive(), None);
+
+        let now = handshake + Duration::from_secs(120);
+        assert_eq!(peer.time_since_last_handshake_at(now), Some(Duration::from_secs(120)));
+        assert!(!peer.is_stale_at(now));
+        assert!(peer.is_stale_at(handshake + REJECT_AFTER_TIME + Duration::from_secs(1)));
+        assert_eq!(peer.time_since_last_handshake_at(UNIX_EPOCH), Some(Duration::ZERO));
+    }
+}
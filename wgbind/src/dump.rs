@@ -0,0 +1,417 @@
+//! The output of `wg show <interface> dump`
+//!
+//! The dump format is meant for scripts: one tab separated line for the
+//! interface followed by one line per peer.
+//!
+//! ```text
+//! private-key  public-key  listen-port  fwmark
+//! public-key  preshared-key  endpoint  allowed-ips  latest-handshake  transfer-rx  transfer-tx  persistent-keepalive
+//! ```
+//!
+//! Missing keys, endpoints and allowed ips are written as `(none)`, a fwmark
+//! or keepalive of 0 as `off` and the handshake as seconds since the epoch.
+//! `wg show all dump` prefixes every line with the interface name, see
+//! [`write_all`] and [`parse_all`].
+//!
+//! The dump contains the private key of the device, handle it accordingly.
+
+use std::ffi::CString;
+use std::fmt::{self, Write};
+use std::net::{AddrParseError, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::num::ParseIntError;
+use std::str::FromStr;
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::allowed_ip::AllowedIp;
+use crate::device::{Device, Peer};
+use crate::interface_name::InterfaceName;
+use crate::key::{PresharedKey, PrivateKey, PublicKey};
+
+const NONE: &str = "(none)";
+const INTERFACE_FIELDS: usize = 4;
+const PEER_FIELDS: usize = 8;
+
+/// Returned if a dump cannot be parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DumpError {
+    /// 1 based line number
+    pub line: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for DumpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.reason)
+    }
+}
+
+impl std::error::Error for DumpError {}
+
+fn or_none(value: Option<impl fmt::Display>) -> String {
+    value.map_or_else(|| NONE.to_owned(), |value| value.to_string())
+}
+
+fn write_device(output: &mut String, prefix: &str, device: &Device) {
+    // writing to a String cannot fail
+    let _ = write!(
+        output,
+        "{}{}\t{}\t{}\t",
+        prefix,
+        or_none(device.private_key()),
+        or_none(device.public_key()),
+        device.listen_port()
+    );
+    let _ = match device.fwmark() {
+        0 => writeln!(output, "off"),
+        fwmark => writeln!(output, "0x{:x}", fwmark),
+    };
+
+    for peer in device.peers() {
+        let allowed_ips = match peer.allowed_ips() {
+            [] => NONE.to_owned(),
+            allowed_ips => allowed_ips.iter().map(|ip| ip.to_string()).collect::<Vec<_>>().join(","),
+        };
+        let _ = write!(
+            output,
+            "{}{}\t{}\t{}\t{}\t{}\t{}\t{}\t",
+            prefix,
+            peer.public_key(),
+            or_none(peer.preshared_key()),
+            or_none(peer.endpoint()),
+            allowed_ips,
+            peer.handshake_seconds(),
+            peer.rx_bytes(),
+            peer.tx_bytes()
+        );
+        let _ = match peer.persistent_keepalive_interval() {
+            0 => writeln!(output, "off"),
+            interval => writeln!(output, "{}", interval),
+        };
+    }
+}
+
+/// Writes a device like `wg show <interface> dump`
+pub fn write(device: &Device) -> String {
+    let mut output = String::new();
+    write_device(&mut output, "", device);
+    output
+}
+
+/// Writes devices like `wg show all dump`, every line starts with the interface name
+pub fn write_all(devices: &[Device]) -> String {
+    let mut output = String::new();
+    for device in devices {
+        write_device(&mut output, &format!("{}\t", device.name()), device);
+    }
+    output
+}
+
+/// Reads a dump field by field, keeping the line for errors
+struct Fields<'a> {
+    line: usize,
+    fields: std::str::Split<'a, char>,
+}
+
+impl<'a> Fields<'a> {
+    fn error(&self, reason: impl fmt::Display) -> DumpError {
+        DumpError {
+            line: self.line,
+            reason: reason.to_string(),
+        }
+    }
+
+    fn next(&mut self, name: &str) -> Result<&'a str, DumpError> {
+        self.fields.next().ok_or_else(|| self.error(format!("{} is missing", name)))
+    }
+
+    fn parse<T: FromStr>(&mut self, name: &str) -> Result<T, DumpError>
+    where
+        T::Err: fmt::Display,
+    {
+        let value = self.next(name)?;
+        value.parse().map_err(|error| self.error(format!("invalid {} {:?}: {}", name, value, error)))
+    }
+
+    /// `None` for `(none)`
+    fn optional<T: FromStr>(&mut self, name: &str) -> Result<Option<T>, DumpError>
+    where
+        T::Err: fmt::Display,
+    {
+        let value = self.next(name)?;
+        if value == NONE {
+            return Ok(None);
+        }
+        value
+            .parse()
+            .map(Some)
+            .map_err(|error| self.error(format!("invalid {} {:?}: {}", name, value, error)))
+    }
+
+    /// An endpoint or `(none)`, see [`parse_endpoint`]
+    fn endpoint(&mut self) -> Result<Option<SocketAddr>, DumpError> {
+        let value = self.next("endpoint")?;
+        if value == NONE {
+            return Ok(None);
+        }
+
+        parse_endpoint(value)
+            .map(Some)
+            .map_err(|error| self.error(format!("invalid endpoint {:?}: {}", value, error)))
+    }
+
+    /// A keepalive interval or `off`, which maps to 0
+    fn keepalive(&mut self) -> Result<u16, DumpError> {
+        let value = self.next("persistent keepalive")?;
+        if value == "off" {
+            return Ok(0);
+        }
+
+        value
+            .parse()
+            .map_err(|error| self.error(format!("invalid persistent keepalive {:?}: {}", value, error)))
+    }
+
+    /// A fwmark in hex or `off`, which maps to 0
+    fn fwmark(&mut self) -> Result<u32, DumpError> {
+        let value = self.next("fwmark")?;
+        if value == "off" {
+            return Ok(0);
+        }
+
+        value
+            .strip_prefix("0x")
+            .ok_or_else(|| "expected 0x".to_owned())
+            .and_then(|hex| u32::from_str_radix(hex, 16).map_err(|error| error.to_string()))
+            .map_err(|error| self.error(format!("invalid fwmark {:?}: {}", value, error)))
+    }
+
+    fn finish(mut self) -> Result<(), DumpError> {
+        match self.fields.next() {
+            Some(_) => Err(self.error("too many fields")),
+            None => Ok(()),
+        }
+    }
+}
+
+/// An endpoint, which may name the scope of a link-local address like `[fe80::1%eth0]:51820`
+///
+/// wg(8) writes the interface name, the scope of a [`SocketAddrV6`] is its index.
+fn parse_endpoint(value: &str) -> Result<SocketAddr, String> {
+    let scoped = value
+        .strip_prefix('[')
+        .and_then(|rest| rest.split_once("]:"))
+        .and_then(|(host, port)| host.split_once('%').map(|(ip, scope)| (ip, scope, port)));
+    let (ip, scope, port) = match scoped {
+        Some(scoped) => scoped,
+        None => return value.parse().map_err(|error: AddrParseError| error.to_string()),
+    };
+
+    let ip: Ipv6Addr = ip.parse().map_err(|error: AddrParseError| error.to_string())?;
+    let port: u16 = port.parse().map_err(|error: ParseIntError| error.to_string())?;
+    let scope_id = match scope.parse::<u32>() {
+        Ok(index) => index,
+        Err(_) => {
+            let name = CString::new(scope).map_err(|_| format!("unknown interface {:?}", scope))?;
+            match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+                0 => return Err(format!("unknown interface {:?}", scope)),
+                index => index,
+            }
+        }
+    };
+
+    Ok(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scope_id)))
+}
+
+fn parse_device(fields: &mut Fields<'_>, name: String) -> Result<Device, DumpError> {
+    Ok(Device {
+        name,
+        ifindex: 0,
+        private_key: fields.optional::<PrivateKey>("private key")?,
+        public_key: fields.optional::<PublicKey>("public key")?,
+        listen_port: fields.parse("listen port")?,
+        fwmark: fields.fwmark()?,
+        peers: Vec::new(),
+    })
+}
+
+fn parse_peer(fields: &mut Fields<'_>) -> Result<Peer, DumpError> {
+    let public_key = fields.parse::<PublicKey>("public key")?;
+    let preshared_key = fields.optional::<PresharedKey>("preshared key")?;
+    let endpoint = fields.endpoint()?;
+
+    let allowed_ips = match fields.next("allowed ips")? {
+        NONE => Vec::new(),
+        allowed_ips => allowed_ips
+            .split(',')
+            .map(|ip| ip.parse::<AllowedIp>().map_err(|error| fields.error(error)))
+            .collect::<Result<_, _>>()?,
+    };
+
+    let handshake: u64 = fields.parse("latest handshake")?;
+
+    Ok(Peer {
+        public_key,
+        preshared_key,
+        endpoint,
+        last_handshake_time: (handshake != 0).then(|| UNIX_EPOCH + Duration::from_secs(handshake)),
+        rx_bytes: fields.parse("transfer rx")?,
+        tx_bytes: fields.parse("transfer tx")?,
+        persistent_keepalive_interval: fields.keepalive()?,
+        allowed_ips,
+    })
+}
+
+fn lines(input: &str) -> impl Iterator<Item = (usize, &str)> {
+    input.lines().enumerate().map(|(index, line)| (index + 1, line)).filter(|(_, line)| !line.is_empty())
+}
+
+/// Parses the output of `wg show <interface> dump`
+///
+/// The dump does not contain the interface name, hence it has to be passed in.
+/// The interface index is not part of the dump either and is left at 0.
+pub fn parse(name: &InterfaceName, input: &str) -> Result<Device, DumpError> {
+    let mut lines = lines(input);
+    let Some((line, first)) = lines.next() else {
+        return Err(DumpError {
+            line: 1,
+            reason: "the interface line is missing".to_owned(),
+        });
+    };
+
+    let mut fields = Fields { line, fields: first.split('\t') };
+    let mut device = parse_device(&mut fields, name.to_string())?;
+    fields.finish()?;
+
+    for (line, content) in lines {
+        let mut fields = Fields { line, fields: content.split('\t') };
+        device.peers.push(parse_peer(&mut fields)?);
+        fields.finish()?;
+    }
+
+    Ok(device)
+}
+
+/// Parses the output of `wg show all dump`
+pub fn parse_all(input: &str) -> Result<Vec<Device>, DumpError> {
+    let mut devices: Vec<Device> = Vec::new();
+
+    for (line, content) in lines(input) {
+        let count = content.split('\t').count();
+        let mut fields = Fields { line, fields: content.split('\t') };
+        let name = fields.next("interface")?;
+
+        if count == INTERFACE_FIELDS + 1 {
+            let name = name.parse::<InterfaceName>().map_err(|error| fields.error(error))?;
+            devices.push(parse_device(&mut fields, name.to_string())?);
+        } else if count == PEER_FIELDS + 1 {
+            let device = match devices.last_mut() {
+                Some(device) if device.name() == name => device,
+                _ => return Err(fields.error(format!("peer of {:?} without its interface line", name))),
+            };
+            device.peers.push(parse_peer(&mut fields)?);
+        } else {
+            return Err(fields.error(format!("expected {} or {} fields", INTERFACE_FIELDS + 1, PEER_FIELDS + 1)));
+        }
+        fields.finish()?;
+    }
+
+    Ok(devices)
+}
+
+impl Peer {
+    /// Seconds since the epoch as shown by the dump, 0 if there was no handshake
+    fn handshake_seconds(&self) -> u64 {
+        self.last_handshake_time
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |since_epoch| since_epoch.as_secs())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRIVATE: &str = "yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=";
+    const PUBLIC: &str = "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=";
+    const PEER: &str = "TrMvSoP4jYQlY6RIzBgbssQqY3vxI2Pi+y71lOWWXX0=";
+
+    fn dump() -> String {
+        format!(
+            "{PRIVATE}\t{PUBLIC}\t51820\t0x1234\n\
+             {PEER}\t(none)\t[2001:db8::1]:51820\t10.0.0.0/24,fd00::/64\t1700000000\t1024\t2048\t25\n\
+             {PUBLIC}\t{PEER}\t(none)\t(none)\t0\t0\t0\toff\n"
+        )
+    }
+
+    #[test]
+    fn it_parses_and_writes_a_dump() {
+        let device = parse(&"wg0".parse().unwrap(), &dump()).unwrap();
+
+        assert_eq!(device.name(), "wg0");
+        assert_eq!(device.private_key(), Some(&PRIVATE.parse().unwrap()));
+        assert_eq!(device.listen_port(), 51820);
+        assert_eq!(device.fwmark(), 0x1234);
+
+        let peer = &device.peers()[0];
+        assert_eq!(peer.public_key(), &PEER.parse().unwrap());
+        assert!(peer.preshared_key().is_none());
+        assert_eq!(peer.endpoint(), Some("[2001:db8::1]:51820".parse().unwrap()));
+        assert_eq!(peer.allowed_ips().len(), 2);
+        assert_eq!(peer.handshake_seconds(), 1_700_000_000);
+        assert_eq!((peer.rx_bytes(), peer.tx_bytes()), (1024, 2048));
+        assert_eq!(peer.persistent_keepalive_interval(), 25);
+
+        let peer = &device.peers()[1];
+        assert_eq!(peer.preshared_key(), Some(&PEER.parse().unwrap()));
+        assert_eq!(peer.last_handshake_time(), None);
+        assert!(peer.allowed_ips().is_empty());
+
+        assert_eq!(write(&device), dump());
+    }
+
+    #[test]
+    fn it_parses_and_writes_all_devices() {
+        let input = dump()
+            .lines()
+            .map(|line| format!("wg0\t{}\n", line))
+            .chain(["wg1\t(none)\t(none)\t0\toff\n".to_owned()])
+            .collect::<String>();
+
+        let devices = parse_all(&input).unwrap();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].peers().len(), 2);
+        assert_eq!(devices[1].name(), "wg1");
+        assert_eq!(devices[1].private_key(), None);
+
+        assert_eq!(write_all(&devices), input);
+    }
+
+    #[test]
+    fn it_reports_the_line_of_an_error() {
+        let error = parse(&"wg0".parse().unwrap(), &dump().replace("\t25\n", "\tsoon\n")).unwrap_err();
+        assert_eq!(error.line, 2);
+
+        let error = parse(&"wg0".parse().unwrap(), &format!("{PRIVATE}\t{PUBLIC}\t51820")).unwrap_err();
+        assert_eq!(error.line, 1);
+
+        let error = parse_all(&format!("wg1\t{PEER}\t(none)\t(none)\t(none)\t0\t0\t0\toff")).unwrap_err();
+        assert_eq!(error.line, 1);
+    }
+
+    #[test]
+    fn it_parses_link_local_endpoints() {
+        let scoped = |scope_id| Some(SocketAddr::V6(SocketAddrV6::new("fe80::1".parse().unwrap(), 51820, 0, scope_id)));
+        let lo = unsafe { libc::if_nametoindex(c"lo".as_ptr()) };
+
+        let device = parse(&"wg0".parse().unwrap(), &dump().replace("[2001:db8::1]", "[fe80::1%lo]")).unwrap();
+        assert_eq!(device.peers()[0].endpoint(), scoped(lo));
+        // the index of the scope is written back
+        assert!(write(&device).contains(&format!("\t[fe80::1%{}]:51820\t", lo)));
+
+        let device = parse(&"wg0".parse().unwrap(), &dump().replace("[2001:db8::1]", "[fe80::1%7]")).unwrap();
+        assert_eq!(device.peers()[0].endpoint(), scoped(7));
+
+        let error = parse(&"wg0".parse().unwrap(), &dump().replace("[2001:db8::1]", "[fe80::1%nosuch0]")).unwrap_err();
+        assert_eq!(error.line, 2);
+    }
+}
@@ -0,0 +1,95 @@
+//! Conversion between `wg_endpoint` and [`SocketAddr`]
+//!
+//! `wg_endpoint` is a union of `sockaddr`, `sockaddr_in` and `sockaddr_in6`
+//! whose family decides which member is valid. Port and address are kept in
+//! network byte order, flowinfo and scope_id of IPv6 addresses are copied
+//! as they are. A zeroed endpoint, `sa_family == 0`, means no endpoint is set.
+
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+
+use wgbindraw_sys::*;
+
+/// Converts a socket address into the raw union
+pub fn to_raw(endpoint: &SocketAddr) -> wg_endpoint {
+    let mut raw: wg_endpoint = unsafe { std::mem::zeroed() };
+
+    match endpoint {
+        SocketAddr::V4(addr) => {
+            let addr4 = unsafe { raw.addr4.as_mut() };
+            addr4.sin_family = libc::AF_INET as libc::sa_family_t;
+            addr4.sin_port = addr.port().to_be();
+            addr4.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+        }
+        SocketAddr::V6(addr) => {
+            let addr6 = unsafe { raw.addr6.as_mut() };
+            addr6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            addr6.sin6_port = addr.port().to_be();
+            addr6.sin6_addr.s6_addr = addr.ip().octets();
+            addr6.sin6_flowinfo = addr.flowinfo();
+            addr6.sin6_scope_id = addr.scope_id();
+        }
+    }
+
+    raw
+}
+
+/// Reads the raw union
+///
+/// Returns `None` if no endpoint is set or the family is neither AF_INET nor AF_INET6.
+pub fn from_raw(raw: &wg_endpoint) -> Option<SocketAddr> {
+    // every member starts with the family, reading it through sockaddr is always fine
+    let family = unsafe { raw.addr.as_ref() }.sa_family as libc::c_int;
+
+    match family {
+        libc::AF_INET => {
+            let addr4 = unsafe { raw.addr4.as_ref() };
+            Some(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(addr4.sin_addr.s_addr)),
+                u16::from_be(addr4.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            let addr6 = unsafe { raw.addr6.as_ref() };
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(addr6.sin6_addr.s6_addr),
+                u16::from_be(addr6.sin6_port),
+                addr6.sin6_flowinfo,
+                addr6.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_round_trips_socket_addresses() {
+        let addresses: [SocketAddr; 3] = [
+            "192.0.2.1:51820".parse().unwrap(),
+            "[2001:db8::1]:443".parse().unwrap(),
+            SocketAddr::V6(SocketAddrV6::new("fe80::1".parse().unwrap(), 51820, 0x12345, 3)),
+        ];
+
+        for address in addresses {
+            assert_eq!(from_raw(&to_raw(&address)), Some(address));
+        }
+    }
+
+    #[test]
+    fn it_uses_network_byte_order() {
+        let raw = to_raw(&"192.0.2.1:51820".parse().unwrap());
+        let addr4 = unsafe { raw.addr4.as_ref() };
+
+        assert_eq!(addr4.sin_port.to_ne_bytes(), 51820u16.to_be_bytes());
+        assert_eq!(addr4.sin_addr.s_addr.to_ne_bytes(), [192, 0, 2, 1]);
+    }
+
+    #[test]
+    fn it_maps_an_unset_endpoint_to_none() {
+        let raw: wg_endpoint = unsafe { std::mem::zeroed() };
+        assert_eq!(from_raw(&raw), None);
+    }
+}
@@ -0,0 +1,245 @@
+//! Errors of all wgbind operations
+//!
+//! The c library reports failures as a negative errno. [`Error`] turns the
+//! common ones into dedicated variants and keeps the operation and the
+//! interface it was applied to, so "not root" can be told apart from
+//! "wrong interface" without looking at raw numbers.
+
+use std::fmt;
+
+/// The wgbind operation which failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operation {
+    ListDevices,
+    AddDevice,
+    DeleteDevice,
+    GetDevice,
+    SetDevice,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Operation::ListDevices => "list devices",
+            Operation::AddDevice => "add device",
+            Operation::DeleteDevice => "delete device",
+            Operation::GetDevice => "get device",
+            Operation::SetDevice => "set device",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Error {
+    /// The interface name cannot be handed to the kernel
+    InvalidName {
+        operation: Operation,
+        interface: Option<String>,
+        reason: &'static str,
+    },
+    /// ENODEV, there is no interface with that name
+    NoSuchDevice {
+        operation: Operation,
+        interface: Option<String>,
+        errno: i32,
+    },
+    /// EPERM or EACCES, most likely the process lacks CAP_NET_ADMIN
+    PermissionDenied {
+        operation: Operation,
+        interface: Option<String>,
+        errno: i32,
+    },
+    /// EEXIST, an interface with that name exists already
+    AlreadyExists {
+        operation: Operation,
+        interface: Option<String>,
+        errno: i32,
+    },
+    /// EOPNOTSUPP, the interface exists but is not of type wireguard
+    NotWireguard {
+        operation: Operation,
+        interface: Option<String>,
+        errno: i32,
+    },
+    /// The wireguard generic netlink family or link type is unknown to the kernel
+    ModuleUnavailable {
+        operation: Operation,
+        interface: Option<String>,
+        errno: i32,
+    },
+    /// Any other errno reported by netlink
+    Netlink {
+        operation: Operation,
+        interface: Option<String>,
+        errno: i32,
+    },
+    /// The network namespace of the operation cannot be opened or joined, see [`crate::netns`]
+    Namespace {
+        operation: Operation,
+        interface: Option<String>,
+        errno: i32,
+    },
+}
+
+impl Error {
+    /// Maps an errno as reported by the c library
+    ///
+    /// The sign does not matter, `-ENODEV` and `ENODEV` are treated the same.
+    pub fn from_errno(operation: Operation, interface: Option<&str>, errno: i32) -> Self {
+        let interface = interface.map(String::from);
+        let errno = errno.abs();
+
+        match errno {
+            libc::ENODEV => Error::NoSuchDevice { operation, interface, errno },
+            libc::EPERM | libc::EACCES => Error::PermissionDenied { operation, interface, errno },
+            libc::EEXIST => Error::AlreadyExists { operation, interface, errno },
+            // rtnetlink answers an unknown link kind with EOPNOTSUPP
+            libc::EOPNOTSUPP if operation == Operation::AddDevice => {
+                Error::ModuleUnavailable { operation, interface, errno }
+            }
+            libc::EOPNOTSUPP => Error::NotWireguard { operation, interface, errno },
+            // the c library turns an unknown generic netlink family into EPROTONOSUPPORT
+            libc::EPROTONOSUPPORT => Error::ModuleUnavailable { operation, interface, errno },
+            _ => Error::Netlink { operation, interface, errno },
+        }
+    }
+
+    /// Same as [`Error::from_errno`] but takes the errno of the calling thread
+    pub(crate) fn last_os_error(operation: Operation, interface: Option<&str>) -> Self {
+        let errno = std::io::Error::last_os_error().raw_os_error().unwrap_or(libc::EIO);
+
+        Self::from_errno(operation, interface, errno)
+    }
+
+    pub(crate) fn invalid_name(operation: Operation, interface: &str, reason: &'static str) -> Self {
+        Error::InvalidName {
+            operation,
+            interface: Some(interface.to_owned()),
+            reason,
+        }
+    }
+
+    pub fn operation(&self) -> Operation {
+        match self {
+            Error::InvalidName { operation, .. }
+            | Error::NoSuchDevice { operation, .. }
+            | Error::PermissionDenied { operation, .. }
+            | Error::AlreadyExists { operation, .. }
+            | Error::NotWireguard { operation, .. }
+            | Error::ModuleUnavailable { operation, .. }
+            | Error::Netlink { operation, .. }
+            | Error::Namespace { operation, .. } => *operation,
+        }
+    }
+
+    /// The interface the operation was applied to, `None` when listing devices
+    pub fn interface(&self) -> Option<&str> {
+        match self {
+            Error::InvalidName { interface, .. }
+            | Error::NoSuchDevice { interface, .. }
+            | Error::PermissionDenied { interface, .. }
+            | Error::AlreadyExists { interface, .. }
+            | Error::NotWireguard { interface, .. }
+            | Error::ModuleUnavailable { interface, .. }
+            | Error::Netlink { interface, .. }
+            | Error::Namespace { interface, .. } => interface.as_deref(),
+        }
+    }
+
+    /// The errno as reported, `None` if the error was detected before calling the kernel
+    pub fn errno(&self) -> Option<i32> {
+        match self {
+            Error::InvalidName { .. } => None,
+            Error::NoSuchDevice { errno, .. }
+            | Error::PermissionDenied { errno, .. }
+            | Error::AlreadyExists { errno, .. }
+            | Error::NotWireguard { errno, .. }
+            | Error::ModuleUnavailable { errno, .. }
+            | Error::Netlink { errno, .. }
+            | Error::Namespace { errno, .. } => Some(*errno),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.operation())?;
+        if let Some(interface) = self.interface() {
+            write!(f, " {:?}", interface)?;
+        }
+
+        match self {
+            Error::InvalidName { reason, .. } => write!(f, ": invalid interface name, {}", reason),
+            Error::NoSuchDevice { .. } => write!(f, ": no such device"),
+            Error::PermissionDenied { .. } => write!(f, ": permission denied, CAP_NET_ADMIN is required"),
+            Error::AlreadyExists { .. } => write!(f, ": device exists already"),
+            Error::NotWireguard { .. } => write!(f, ": not a wireguard device"),
+            Error::ModuleUnavailable { .. } => write!(f, ": wireguard is not available in the kernel"),
+            Error::Netlink { errno, .. } => {
+                write!(f, ": {}", std::io::Error::from_raw_os_error(*errno))
+            }
+            Error::Namespace { errno, .. } => {
+                write!(f, ": cannot enter network namespace, {}", std::io::Error::from_raw_os_error(*errno))
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Error> for std::io::Error {
+    fn from(error: Error) -> Self {
+        use std::io::ErrorKind;
+
+        let kind = match &error {
+            Error::InvalidName { .. } => ErrorKind::InvalidInput,
+            Error::NoSuchDevice { .. } => ErrorKind::NotFound,
+            Error::PermissionDenied { .. } => ErrorKind::PermissionDenied,
+            Error::AlreadyExists { .. } => ErrorKind::AlreadyExists,
+            Error::NotWireguard { .. } | Error::ModuleUnavailable { .. } => ErrorKind::Unsupported,
+            Error::Netlink { errno, .. } | Error::Namespace { errno, .. } => {
+                std::io::Error::from_raw_os_error(*errno).kind()
+            }
+        };
+
+        std::io::Error::new(kind, error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_maps_errno_values() {
+        let error = Error::from_errno(Operation::GetDevice, Some("wg0"), -libc::ENODEV);
+        assert_eq!(
+            error,
+            Error::NoSuchDevice {
+                operation: Operation::GetDevice,
+                interface: Some("wg0".into()),
+                errno: libc::ENODEV,
+            }
+        );
+        assert_eq!(error.to_string(), "get device \"wg0\": no such device");
+
+        assert!(matches!(
+            Error::from_errno(Operation::SetDevice, Some("eth0"), -libc::EOPNOTSUPP),
+            Error::NotWireguard { .. }
+        ));
+        let error = Error::from_errno(Operation::AddDevice, Some("wg0"), -libc::EOPNOTSUPP);
+        assert!(matches!(error, Error::ModuleUnavailable { .. }));
+        assert_eq!(error.errno(), Some(libc::EOPNOTSUPP));
+
+        let error = Error::from_errno(Operation::GetDevice, Some("wg0"), -libc::EACCES);
+        assert!(matches!(error, Error::PermissionDenied { .. }));
+        assert_eq!(error.errno(), Some(libc::EACCES));
+
+        let error = Error::from_errno(Operation::ListDevices, None, -libc::EPERM);
+        assert_eq!(error.interface(), None);
+        assert_eq!(std::io::Error::from(error).kind(), std::io::ErrorKind::PermissionDenied);
+
+        assert_eq!(Error::from_errno(Operation::GetDevice, None, -libc::EINTR).errno(), Some(libc::EINTR));
+    }
+}
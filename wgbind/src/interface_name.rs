@@ -0,0 +1,179 @@
+//! Validated network interface names
+//!
+//! The kernel keeps interface names in a `char[IFNAMSIZ]` including the
+//! terminating \0 and rejects names it considers ambiguous (see
+//! `dev_valid_name` in net/core/dev.c). [`InterfaceName`] applies the same
+//! rules up front, so a name is never truncated or rejected half way through
+//! an operation.
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::error::{Error, Operation};
+
+/// maximum length of an interface name without the trailing \0
+pub const MAX_LENGTH: usize = libc::IFNAMSIZ - 1;
+
+/// Returned if a string is not a valid interface name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidInterfaceName {
+    pub name: String,
+    pub reason: &'static str,
+}
+
+impl InvalidInterfaceName {
+    /// Attaches the operation which could not be run because of the name
+    pub fn into_error(self, operation: Operation) -> Error {
+        Error::invalid_name(operation, &self.name, self.reason)
+    }
+}
+
+impl fmt::Display for InvalidInterfaceName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid interface name {:?}: {}", self.name, self.reason)
+    }
+}
+
+impl std::error::Error for InvalidInterfaceName {}
+
+/// Name of a network interface e.g. wg0
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct InterfaceName(String);
+
+impl InterfaceName {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Copies the name into the `name` field layout of `wg_device`, padded with \0
+    pub fn to_raw(&self) -> [::core::ffi::c_char; libc::IFNAMSIZ] {
+        let mut raw = [0; libc::IFNAMSIZ];
+        for (c, byte) in raw.iter_mut().zip(self.0.bytes()) {
+            *c = byte as ::core::ffi::c_char;
+        }
+
+        raw
+    }
+
+    /// Reads the `name` field of `wg_device`
+    ///
+    /// Fails if there is no \0 within the field or the name itself is invalid.
+    pub fn from_raw(raw: &[::core::ffi::c_char; libc::IFNAMSIZ]) -> Result<Self, InvalidInterfaceName> {
+        let bytes = raw.iter().map(|c| *c as u8).collect::<Vec<u8>>();
+        let Some(length) = bytes.iter().position(|byte| *byte == 0) else {
+            return Err(InvalidInterfaceName {
+                name: String::from_utf8_lossy(&bytes).into_owned(),
+                reason: "not \\0 terminated",
+            });
+        };
+
+        match String::from_utf8(bytes[..length].to_vec()) {
+            Ok(name) => name.parse(),
+            Err(error) => Err(InvalidInterfaceName {
+                name: String::from_utf8_lossy(error.as_bytes()).into_owned(),
+                reason: "not valid utf-8",
+            }),
+        }
+    }
+
+    fn validate(name: &str) -> Result<(), &'static str> {
+        if name.is_empty() {
+            return Err("must not be empty");
+        }
+        if name.len() > MAX_LENGTH {
+            return Err("longer than 15 bytes");
+        }
+        if name == "." || name == ".." {
+            return Err("must not be . or ..");
+        }
+        if name.contains('/') {
+            return Err("must not contain '/'");
+        }
+        if name.contains(':') {
+            return Err("must not contain ':'");
+        }
+        if name.contains('\0') {
+            return Err("must not contain a NUL byte");
+        }
+        if name.chars().any(char::is_whitespace) {
+            return Err("must not contain whitespace");
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for InterfaceName {
+    type Err = InvalidInterfaceName;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        InterfaceName::validate(s).map_err(|reason| InvalidInterfaceName {
+            name: s.to_owned(),
+            reason,
+        })?;
+
+        Ok(InterfaceName(s.to_owned()))
+    }
+}
+
+impl TryFrom<&str> for InterfaceName {
+    type Error = InvalidInterfaceName;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl TryFrom<String> for InterfaceName {
+    type Error = InvalidInterfaceName;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl AsRef<str> for InterfaceName {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for InterfaceName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[cfg(feature = "serde")]
+serde_as_string!(InterfaceName);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_accepts_valid_names() {
+        for name in ["wg0", "wg-tenant_1", "a", "exactly15bytes_"] {
+            let parsed: InterfaceName = name.parse().unwrap();
+            assert_eq!(parsed.as_str(), name);
+        }
+    }
+
+    #[test]
+    fn it_rejects_invalid_names() {
+        for name in ["", "sixteen_bytes_xx", ".", "..", "wg/0", "wg 0", "wg\t0", "wg:0", "wg\x000"] {
+            assert!(name.parse::<InterfaceName>().is_err(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn it_converts_to_and_from_raw() {
+        let name: InterfaceName = "exactly15bytes_".parse().unwrap();
+        let raw = name.to_raw();
+        assert_eq!(raw[15], 0);
+        assert_eq!(InterfaceName::from_raw(&raw), Ok(name));
+
+        assert!(InterfaceName::from_raw(&[b'a' as _; libc::IFNAMSIZ]).is_err());
+        assert!(InterfaceName::from_raw(&[0; libc::IFNAMSIZ]).is_err());
+    }
+}
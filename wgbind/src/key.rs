@@ -0,0 +1,344 @@
+//! Typed wireguard keys
+//!
+//! A wireguard key is just 32 bytes. The newtypes in this module make sure a
+//! private key is never mistaken for a public one, encode and decode the base64
+//! representation used by wg(8) through the c library and compare in constant
+//! time. Secret keys are overwritten with zeros once they are dropped and never
+//! show up in `Debug` output.
+//!
+//! With the `serde` feature keys serialize to their base64 string.
+//!
+//! Keys can be generated in process, neither root nor the wg binary is required.
+//!
+//! ```
+//! use wgbind::key::{KeyPair, PresharedKey};
+//!
+//! let pair = KeyPair::generate();
+//! assert_eq!(pair.private.public_key(), pair.public);
+//!
+//! let psk = PresharedKey::generate();
+//! assert!(!psk.is_zero());
+//! ```
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{compiler_fence, Ordering};
+
+use wgbindraw_sys::*;
+#[cfg(feature = "netlink")]
+use crate::curve25519::{
+    wg_generate_preshared_key, wg_generate_private_key, wg_generate_public_key, wg_key_from_base64,
+    wg_key_is_zero, wg_key_to_base64,
+};
+
+/// length of a base64 encoded key without the trailing \0
+const BASE64_LENGTH: usize = std::mem::size_of::<wg_key_b64_string>() - 1;
+
+/// Returned if a string is not a base64 encoded wireguard key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidKey;
+
+impl fmt::Display for InvalidKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "expected {} characters of base64 encoding 32 bytes", BASE64_LENGTH)
+    }
+}
+
+impl std::error::Error for InvalidKey {}
+
+/// Overwrites the bytes in a way the compiler is not allowed to optimize away
+pub(crate) fn zeroize<T: Default>(values: &mut [T]) {
+    for value in values.iter_mut() {
+        unsafe { std::ptr::write_volatile(value, T::default()) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+fn is_zero(key: &wg_key) -> bool {
+    // wg_key_is_zero only reads the key, the binding just lost the const
+    unsafe { wg_key_is_zero(key as *const wg_key as *mut wg_key) }
+}
+
+/// Compares two keys without branching on their content
+fn constant_time_eq(a: &wg_key, b: &wg_key) -> bool {
+    let mut difference: wg_key = [0; 32];
+    for (d, (x, y)) in difference.iter_mut().zip(a.iter().zip(b)) {
+        *d = x ^ y;
+    }
+
+    let equal = is_zero(&difference);
+    zeroize(&mut difference);
+    equal
+}
+
+fn to_base64(key: &wg_key) -> String {
+    let mut base64: wg_key_b64_string = [0; 45];
+    unsafe { wg_key_to_base64(&mut base64, key as *const wg_key as *mut wg_key) };
+
+    let encoded = base64[..BASE64_LENGTH].iter().map(|c| *c as u8 as char).collect();
+    zeroize(&mut base64);
+    encoded
+}
+
+fn from_base64(encoded: &str) -> Result<wg_key, InvalidKey> {
+    if encoded.len() != BASE64_LENGTH {
+        return Err(InvalidKey);
+    }
+
+    let mut base64: wg_key_b64_string = [0; 45];
+    for (c, byte) in base64.iter_mut().zip(encoded.bytes()) {
+        *c = byte as ::core::ffi::c_char;
+    }
+
+    let mut key: wg_key = [0; 32];
+    let result = unsafe { wg_key_from_base64(&mut key, &mut base64) };
+    zeroize(&mut base64);
+
+    if result != 0 {
+        zeroize(&mut key);
+        return Err(InvalidKey);
+    }
+
+    Ok(key)
+}
+
+macro_rules! key_type {
+    ($(#[$doc:meta])* $name:ident) => {
+        $(#[$doc])*
+        #[derive(Clone)]
+        pub struct $name(wg_key);
+
+        impl $name {
+            pub fn from_bytes(bytes: wg_key) -> Self {
+                Self(bytes)
+            }
+
+            pub fn as_bytes(&self) -> &wg_key {
+                &self.0
+            }
+
+            /// true if all 32 bytes are zero, checked in constant time
+            pub fn is_zero(&self) -> bool {
+                is_zero(&self.0)
+            }
+
+            /// base64 representation as used by wg(8)
+            pub fn to_base64(&self) -> String {
+                to_base64(&self.0)
+            }
+        }
+
+        impl From<wg_key> for $name {
+            fn from(bytes: wg_key) -> Self {
+                Self::from_bytes(bytes)
+            }
+        }
+
+        impl PartialEq for $name {
+            fn eq(&self, other: &Self) -> bool {
+                constant_time_eq(&self.0, &other.0)
+            }
+        }
+
+        impl Eq for $name {}
+
+        impl FromStr for $name {
+            type Err = InvalidKey;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                from_base64(s).map(Self)
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(&self.to_base64())
+            }
+        }
+
+        #[cfg(feature = "serde")]
+        serde_as_string!($name);
+    };
+}
+
+macro_rules! secret_key_type {
+    ($(#[$doc:meta])* $name:ident) => {
+        key_type!($(#[$doc])* $name);
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(concat!(stringify!($name), "(<redacted>)"))
+            }
+        }
+
+        impl Drop for $name {
+            fn drop(&mut self) {
+                zeroize(&mut self.0);
+            }
+        }
+    };
+}
+
+secret_key_type!(
+    /// The private curve25519 key of a device
+    PrivateKey
+);
+
+secret_key_type!(
+    /// Optional symmetric key shared between a device and one of its peers
+    PresharedKey
+);
+
+key_type!(
+    /// The public curve25519 key identifying a device or a peer
+    PublicKey
+);
+
+impl PrivateKey {
+    /// Generates a new clamped curve25519 private key from the system's random source
+    pub fn generate() -> Self {
+        let mut key = Self([0; 32]);
+        unsafe { wg_generate_private_key(&mut key.0) };
+        key
+    }
+
+    /// Derives the public key belonging to this private key
+    pub fn public_key(&self) -> PublicKey {
+        let mut public_key: wg_key = [0; 32];
+        unsafe {
+            wg_generate_public_key(&mut public_key, &self.0 as *const wg_key as *mut wg_key)
+        };
+        PublicKey(public_key)
+    }
+}
+
+impl PresharedKey {
+    /// Generates 32 random bytes from the system's random source
+    pub fn generate() -> Self {
+        let mut key = Self([0; 32]);
+        unsafe { wg_generate_preshared_key(&mut key.0) };
+        key
+    }
+}
+
+/// A private key together with the public key derived from it
+///
+/// Deliberately not serializable, so the private key is never written out by
+/// accident. Serialize `public`, or `private` where storing it is intended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyPair {
+    pub private: PrivateKey,
+    pub public: PublicKey,
+}
+
+impl KeyPair {
+    /// Generates a fresh private key and derives its public key
+    pub fn generate() -> Self {
+        Self::from_private(PrivateKey::generate())
+    }
+
+    pub fn from_private(private: PrivateKey) -> Self {
+        let public = private.public_key();
+        Self { private, public }
+    }
+}
+
+impl From<PrivateKey> for KeyPair {
+    fn from(private: PrivateKey) -> Self {
+        Self::from_private(private)
+    }
+}
+
+impl Copy for PublicKey {}
+
+impl std::hash::Hash for PublicKey {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+    }
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("PublicKey").field(&self.to_base64()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENCODED: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+
+    fn bytes() -> wg_key {
+        std::array::from_fn(|i| i as u8)
+    }
+
+    #[test]
+    fn it_encodes_and_decodes_base64() {
+        let key: PublicKey = ENCODED.parse().unwrap();
+        assert_eq!(key.as_bytes(), &bytes());
+        assert_eq!(key.to_string(), ENCODED);
+
+        let key = PrivateKey::from_bytes(bytes());
+        assert_eq!(key.to_base64(), ENCODED);
+    }
+
+    #[test]
+    fn it_rejects_malformed_keys() {
+        assert_eq!("".parse::<PublicKey>(), Err(InvalidKey));
+        assert_eq!(ENCODED[1..].parse::<PublicKey>(), Err(InvalidKey));
+        assert_eq!(ENCODED.replace('=', "A").parse::<PublicKey>(), Err(InvalidKey));
+        assert_eq!(ENCODED.replace('A', "!").parse::<PublicKey>(), Err(InvalidKey));
+    }
+
+    #[test]
+    fn it_compares_and_checks_for_zero() {
+        assert!(PresharedKey::from_bytes([0; 32]).is_zero());
+        assert!(!PresharedKey::from_bytes(bytes()).is_zero());
+
+        assert_eq!(PublicKey::from_bytes(bytes()), ENCODED.parse().unwrap());
+        assert_ne!(PublicKey::from_bytes(bytes()), PublicKey::from_bytes([0; 32]));
+    }
+
+    #[test]
+    fn it_derives_public_keys() {
+        // RFC 7748 section 6.1
+        let private = PrivateKey::from_bytes([
+            0x77, 0x07, 0x6d, 0x0a, 0x73, 0x18, 0xa5, 0x7d, 0x3c, 0x16, 0xc1, 0x72, 0x51, 0xb2,
+            0x66, 0x45, 0xdf, 0x4c, 0x2f, 0x87, 0xeb, 0xc0, 0x99, 0x2a, 0xb1, 0x77, 0xfb, 0xa5,
+            0x1d, 0xb9, 0x2c, 0x2a,
+        ]);
+        let public = PublicKey::from_bytes([
+            0x85, 0x20, 0xf0, 0x09, 0x89, 0x30, 0xa7, 0x54, 0x74, 0x8b, 0x7d, 0xdc, 0xb4, 0x3e,
+            0xf7, 0x5a, 0x0d, 0xbf, 0x3a, 0x0d, 0x26, 0x38, 0x1a, 0xf4, 0xeb, 0xa4, 0xa9, 0x8e,
+            0xaa, 0x9b, 0x4e, 0x6a,
+        ]);
+
+        assert_eq!(private.public_key(), public);
+        assert_eq!(KeyPair::from(private).public, public);
+    }
+
+    #[test]
+    fn it_generates_distinct_keys() {
+        let first = KeyPair::generate();
+        let second = KeyPair::generate();
+
+        assert!(!first.private.is_zero());
+        assert_ne!(first, second);
+        assert_ne!(PresharedKey::generate(), PresharedKey::generate());
+
+        // generated private keys are clamped
+        let bytes = first.private.as_bytes();
+        assert_eq!(bytes[0] & 7, 0);
+        assert_eq!(bytes[31] & 0xc0, 0x40);
+    }
+
+    #[test]
+    fn it_redacts_secret_keys() {
+        let key = PrivateKey::from_bytes(bytes());
+        assert_eq!(format!("{:?}", key), "PrivateKey(<redacted>)");
+
+        let key = PublicKey::from_bytes(bytes());
+        assert_eq!(format!("{:?}", key), format!("PublicKey({:?})", ENCODED));
+    }
+}
@@ -6,35 +6,85 @@
 //! network settings. Most likely root rights! otherwise calling the methods will fail.
 //! 
 //! The same is true if you attempt to run any tests.
+//!
+//! By default the kernel is reached through the embedded c library. The `netlink`
+//! feature replaces it by a pure rust generic netlink implementation giving the
+//! same results, together with `--no-default-features` no c toolchain is needed.
 #![crate_name = "wgbind"]
 
-use std::{alloc::Layout, ffi::CString};
+use std::ffi::CStr;
 
 extern crate libc;
 extern crate wgbindraw_sys;
 
 use wgbindraw_sys::*;
+#[cfg(feature = "netlink")]
+use netlink::{wg_add_device, wg_del_device, wg_free_device, wg_get_device, wg_list_device_names, wg_set_device};
+
+#[cfg(not(any(feature = "c-library", feature = "netlink")))]
+compile_error!("either the `c-library` or the `netlink` feature is required to talk to the kernel");
+
+/// Serializes a type through its `Display` and deserializes it through its `FromStr`
+#[cfg(feature = "serde")]
+macro_rules! serde_as_string {
+    ($name:ty) => {
+        impl serde::Serialize for $name {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
 
-pub mod wireguard_device;
-use wireguard_device::{WireguardDevice,WireguardControl};
-
+        impl<'de> serde::Deserialize<'de> for $name {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let value = String::deserialize(deserializer)?;
+                value.parse().map_err(serde::de::Error::custom)
+            }
+        }
+    };
+}
 
-unsafe fn determine_length(  mut ptr :  *mut i8) -> usize {
-    let mut prev : i8 = 0;
-    let mut len   = 0; 
-    loop {
-    
-        let current = unsafe { ptr.read() };
-        println!("{}", current);
-        if current == 0 && prev == 0 {
-            break;
-        } 
-        len = len + 1;
-        ptr = unsafe { ptr.offset(1 as isize) };
-        prev = current
+pub mod allowed_ip;
+pub mod backend;
+pub mod conf;
+pub mod config;
+#[cfg(feature = "netlink")]
+mod curve25519;
+pub mod device;
+pub mod dump;
+pub mod endpoint;
+pub mod error;
+pub mod interface_name;
+pub mod key;
+pub mod lint;
+pub mod mock;
+#[cfg(feature = "netlink")]
+mod netlink;
+pub mod netns;
+pub mod network_manager;
+pub mod networkd;
+pub mod quick;
+pub mod sync;
+pub mod uapi;
+pub mod wireguard_device;
+pub use allowed_ip::{AllowedIp, InvalidAllowedIp};
+pub use backend::{Backend, Kernel, KernelOnly};
+pub use config::{DeviceConfig, PeerConfig};
+pub use device::{Device, Peer};
+pub use error::{Error, Operation};
+pub use interface_name::{InterfaceName, InvalidInterfaceName};
+pub use key::{InvalidKey, KeyPair, PresharedKey, PrivateKey, PublicKey};
+pub use mock::Mock;
+pub use uapi::Uapi;
+use wireguard_device::WireguardDevice;
+
+
+/// Turns the return value of the c library, 0 or -errno, into a Result
+fn check(operation: Operation, device_name: &InterfaceName, result: ::core::ffi::c_int) -> Result<(), Error> {
+    if result == 0 {
+        return Ok(())
     }
 
-    len
+    Err(Error::from_errno(operation, Some(device_name.as_str()), result))
 }
 
 
@@ -43,15 +93,16 @@ unsafe fn determine_length(  mut ptr :  *mut i8) -> usize {
 /// Returns a list of Strings. These are copies generated from the singular *mut i8 string 
 /// returned by the wgbindraw-sys crate. 
 /// 
-/// The copies are generated because we have no knowledge how long the c-string is valid. 
-/// Hence we copy the values and gain ownership of the information. 
-/// 
-/// Another reason is the original format looks like this:
+/// The original format looks like this:
 /// 
 /// "first\0second\0third\0forth\0last\0\0"
 /// 
 /// severval \0 terminated strings with in a \0 terminated string. We extract each substring
-/// and put it on the Heap. From that point on we are safe.
+/// and put it on the Heap. Afterwards the buffer, which was allocated by the c library
+/// with malloc, is released again.
+/// 
+/// Devices of userspace implementations, see [`uapi`], follow the kernel devices.
+/// A socket directory which cannot be read only leaves them out.
 /// 
 /// # Example 
 /// ```
@@ -71,32 +122,42 @@ unsafe fn determine_length(  mut ptr :  *mut i8) -> usize {
 /// 
 /// 
 /// 
-pub fn list_device_names() -> Option<Vec<String>> {
-    // The type behind the c_buffer pointer is a string containing several \0 terminated strings
-    // the caller does not own this string!!!
+pub fn list_device_names() -> Result<Vec<String>, Error> {
+    let mut names = kernel_device_names()?;
+
+    // like wg(8), userspace devices are a best effort addition to the kernel list
+    for name in Uapi::new().list().unwrap_or_default() {
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+
+    Ok(names)
+}
+
+/// Names of the devices of the kernel module only
+pub(crate) fn kernel_device_names() -> Result<Vec<String>, Error> {
     let c_buffer = unsafe { wg_list_device_names() };
     if c_buffer.is_null() {
-        return None
+        return Err(Error::last_os_error(Operation::ListDevices, None))
     } 
 
-    //To determine the length of the string it must be iterated through
-    //The string terminates if two \0 appear in sequence. if these do not
-    //occur the program loops for ever and might start reading from memory
-    //it does not own!!!
-    let c_buffer_length = unsafe { determine_length(c_buffer) };
-    if c_buffer_length == 0
-    {
-        return None
+    // Walk from one \0 terminated name to the next until the empty string marks the end
+    let mut names = Vec::new();
+    let mut current = c_buffer as *const ::core::ffi::c_char;
+    loop {
+        let name = unsafe { CStr::from_ptr(current) };
+        if name.is_empty() {
+            break;
+        }
+
+        names.push(name.to_string_lossy().into_owned());
+        current = unsafe { current.add(name.to_bytes_with_nul().len()) };
     }
 
-    // We have reached the point where we know the length of the string
-    // It is now possible to create an slice from the pointer with the
-    // determinent length and from that slice create a String on the 
-    // heap which we duplicated to own the data
-    let raw_characters = unsafe { std::slice::from_raw_parts_mut(c_buffer.cast() as *mut u8 , c_buffer_length)};
-    let result = unsafe { String::from_utf8_unchecked( raw_characters.to_owned()) };
- 
-    Some(Vec::from_iter(result.split_terminator('\0').map(|x| String::from(x))))
+    unsafe { libc::free(c_buffer.cast()) };
+
+    Ok(names)
 }
 
 /// Add a wireguard network interface device
@@ -113,71 +174,89 @@ pub fn list_device_names() -> Option<Vec<String>> {
 /// # Example
 /// 
 /// ```
-/// use wgbind::{add_device,delete_device};
+/// use wgbind::{add_device,delete_device,InterfaceName};
 /// 
-/// let actual = add_device("wg0");
+/// let name : InterfaceName = "wg0".parse().unwrap();
+/// let actual = add_device(&name);
 /// assert!(matches!(actual, Ok(())));
 /// 
 /// //clean up
-/// delete_device("wg0");
+/// delete_device(&name);
 /// ```
 /// 
 /// 
-pub fn add_device(device_name: &str) -> Result<(),std::io::Error>{
-    let name = CString::new(device_name).unwrap().into_raw().cast() as *const ::std::os::raw::c_char ;
-    let result = unsafe{ wg_add_device(name)};
-
-    if result == 0 {
-        return Ok(())
-    }
-
-    Err(std::io::Error::last_os_error())
+pub fn add_device(device_name: &InterfaceName) -> Result<(), Error>{
+    let name = device_name.to_raw();
+    let result = unsafe{ wg_add_device(name.as_ptr())};
 
+    check(Operation::AddDevice, device_name, result)
 }
 
 /// Removes a wireguard network interface device
-pub fn delete_device(device_name: &str) -> Result<(),std::io::Error>{
-    let name = CString::new(device_name).unwrap().into_raw().cast() as *const ::std::os::raw::c_char ;
-    let result = unsafe{ wg_del_device(name)};
-
-    if result == 0 {
-        return Ok(())
-    }
-
-    Err(std::io::Error::last_os_error())
+pub fn delete_device(device_name: &InterfaceName) -> Result<(), Error>{
+    let name = device_name.to_raw();
+    let result = unsafe{ wg_del_device(name.as_ptr())};
 
+    check(Operation::DeleteDevice, device_name, result)
 }
 
-/// Gets a new wg_device
+/// Gets a snapshot of a wireguard device
 /// 
-/// allocates a new object and fills it with the necessary data from the wg interface
+/// Reads the device from the kernel, copies the device, all of its peers and their
+/// allowed ips into owned rust values and releases the c allocation afterwards.
+/// The returned value contains no raw pointers.
 /// the device_name must match an network interface of type wireguard!
+/// A device with a socket of a userspace implementation, see [`uapi`], is read
+/// from that socket instead.
 /// 
 /// # Arguments
 /// 
 /// * `name` - Name of the Network Interface e.g. wg0
 /// 
+/// # Example
 /// 
-pub fn get_device(device_name: &str) -> Result<wg_device,std::io::Error>{
-    let name = CString::new(device_name).unwrap().into_raw().cast() as *const ::std::os::raw::c_char ;
-    //let structsize = std::mem::size_of::<wg_device>()+ std::mem::size_of::<wg_peer>()*2;
-
-    let layout = Layout::new::<wg_device>();
-    let ptr = unsafe { std::alloc::alloc_zeroed(layout)};
-    if ptr.is_null() {
-        return  Err(std::io::Error::last_os_error());
+/// ```no_run
+/// use wgbind::get_device;
+/// 
+/// let device = get_device(&"wg0".parse().unwrap()).unwrap();
+/// for peer in device.peers() {
+///     println!("{:?}", peer.allowed_ips());
+/// }
+/// ```
+/// 
+pub fn get_device(device_name: &InterfaceName) -> Result<Device, Error>{
+    let uapi = Uapi::new();
+    if uapi.has_device(device_name) {
+        return uapi.get(device_name)
     }
 
-    let mut device = ptr.cast() as *mut wg_device; 
-    let result = unsafe{ wg_get_device(&mut device,name)};
+    kernel_get_device(device_name)
+}
 
-    if result == 0 {
-        let new_device = unsafe { *device };
-        return Ok(new_device)
-    }
+/// Same as [`get_device`] without looking for a userspace implementation
+pub(crate) fn kernel_get_device(device_name: &InterfaceName) -> Result<Device, Error>{
+    let raw = get_raw_device(device_name)?;
 
-    Err(std::io::Error::last_os_error())
+    // wg_get_device only succeeds with a fully linked device, hence walking the
+    // peer and allowed ip lists is fine. Afterwards the c side owns nothing we use
+    let device = unsafe { Device::from_raw(&*raw) };
+    unsafe { wg_free_device(raw) };
 
+    Ok(device)
+}
+
+/// Reads the raw wg_device allocated by the c library
+/// 
+/// the returned pointer must be released with wg_free_device
+pub(crate) fn get_raw_device(device_name: &InterfaceName) -> Result<*mut wg_device, Error>{
+    let name = device_name.to_raw();
+
+    let mut device : *mut wg_device = std::ptr::null_mut();
+    let result = unsafe{ wg_get_device(&mut device,name.as_ptr())};
+
+    check(Operation::GetDevice, device_name, result)?;
+
+    Ok(device)
 }
 
 
@@ -185,92 +264,86 @@ pub fn get_device(device_name: &str) -> Result<wg_device,std::io::Error>{
 /// 
 /// A wireguard device is the corresponding kernel object. Values of this 
 /// device have an effect on the existing wireguard network interface device!
+/// Like [`get_device`] a userspace implementation is preferred over the kernel.
 /// 
 /// # Arguments
 /// 
 /// * `device` - Wireguard configuration data
 /// 
 /// 
-pub fn set_device(device : &mut WireguardDevice) -> Result<(), std::io::Error> {
-
-    let raw_ptr = device.raw_device_ptr();
-
-    // If the wireguard device has already a raw device then simply us that pointer 
-    // only if the pointer is NULL skip this and create new raw device
-    if raw_ptr.is_null() == false{
-        let error = unsafe { wg_set_device(raw_ptr.cast_mut())};
-    
-        if error != 0 {
-            return Err(std::io::Error::last_os_error());
-        }
-
-        return Ok(())
+pub fn set_device(device : &mut WireguardDevice) -> Result<(), Error> {
+    let name = device.name().map_err(|e| e.into_error(Operation::SetDevice))?;
+    let uapi = Uapi::new();
+    if uapi.has_device(&name) {
+        return uapi.set(&name, &unsafe { DeviceConfig::from_raw(device.raw()) })
     }
-    
-    // transform the name from &'static str into [i8;16]. The way to get there seems fucked up
-    // not sure if this the best way
-    let devicename = unsafe {
-        let devicename = CString::new(device.name().unwrap()).expect("CString::new failed");
-        let devicename = std::ffi::CStr::from_bytes_with_nul(devicename.to_bytes_with_nul()).expect("CStr::from_bytes_with_null failed");
-        let devicename = std::slice::from_raw_parts( devicename.as_ptr(), 16).as_ptr();
-        let devicename = *std::mem::transmute::<*const i8,&[i8;16]>(devicename);
-
-        devicename
-    };
-
-    // create 2 NULL ponter 
-    let (firstpeer,lastpeer) = {
-        let firstpeer =std::ptr::null::<*mut wg_peer>() as *mut wg_peer;
-        let lastpeer = std::ptr::null::<*mut wg_peer>() as *mut wg_peer;
-
-        (firstpeer,lastpeer)
-    };
 
-    // Create on the Heap the wg_device. The pointer to the heap will be forwarded into
-    // into wireguard c implementation
-    let  wgdevice  =  Box::new(wg_device { 
-        name: devicename,
-        ifindex: 0, 
-        flags: device.flags(), 
-         
-        fwmark: device.fwmark(), 
-        listen_port: 51820,
-       
-        first_peer: firstpeer,
-        last_peer: lastpeer,   
-        private_key: Default::default(),
-        public_key:  Default::default(),
+    kernel_set_device(device)
+}
 
+/// Same as [`set_device`] without looking for a userspace implementation
+pub(crate) fn kernel_set_device(device : &mut WireguardDevice) -> Result<(), Error> {
+    let name = device.name().map_err(|e| e.into_error(Operation::SetDevice))?;
+    let result = unsafe { wg_set_device(device.as_mut_ptr())};
 
-        
-    });
-
-    // Because the pointer is of type c_void, the destructor of Box has no effect on the value
-    // behind the pointer
-    let raw_ptr = &* wgdevice as *const wg_device;
-    let handle = raw_ptr as *const ::core::ffi::c_void;
-    device.raw_device = unsafe { Box::from(handle) };
+    check(Operation::SetDevice, &name, result)
+}
 
-    let error = unsafe { wg_set_device(device.raw_device_ptr().cast_mut())};
+/// Writes a configuration to a wireguard device
+/// 
+/// Builds the raw wg_device from the configuration, see [`DeviceConfig::build`],
+/// and passes it on to [`set_device`]. Values not set in the configuration keep
+/// their current value within the kernel.
+/// 
+/// # Arguments
+/// 
+/// * `device_name` - Name of the Network Interface e.g. wg0
+/// * `config` - the values to change
+/// 
+pub fn configure_device(device_name: &InterfaceName, config: &DeviceConfig) -> Result<(), Error> {
+    let mut device = config.build(device_name);
 
-    if error != 0 {
-        return Err(std::io::Error::last_os_error());
-    }
+    set_device(&mut device)
+}
 
-    Ok(())
-   
+/// Merges a configuration into a device like `wg addconf`
+/// 
+/// Writes [`DeviceConfig::incremental`] of the configuration: peers of the device
+/// are kept and allowed ips are added to those of existing peers. Use this to add
+/// peers to a running device without disturbing the others.
+/// 
+/// # Arguments
+/// 
+/// * `device_name` - Name of the Network Interface e.g. wg0
+/// * `config` - the peers and values to add
+/// 
+/// # Example
+/// 
+/// ```no_run
+/// use wgbind::{add_config, conf};
+/// 
+/// let batch = conf::parse_append("[Peer]\nPublicKey = xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=\nAllowedIPs = 10.0.0.2/32").unwrap();
+/// add_config(&"wg0".parse().unwrap(), &batch).unwrap();
+/// ```
+pub fn add_config(device_name: &InterfaceName, config: &DeviceConfig) -> Result<(), Error> {
+    configure_device(device_name, &config.incremental())
 }
 
-/// delete the wg_device allocated by get_device
-pub fn free_device(device: &mut WireguardDevice) {
-   unsafe {wg_free_device(device.raw_device_ptr().cast_mut())}
+/// Releases a device handle
+/// 
+/// The memory is given back to whichever allocator created it, see [`WireguardDevice`]
+pub fn free_device(device: WireguardDevice) {
+    drop(device)
 }
 
 #[cfg(test)]
 mod tests { 
-    use std::ffi::CStr;
     use super::*;
 
+    fn name(interface: &str) -> InterfaceName {
+        interface.parse().unwrap()
+    }
+
     struct Context {
         interfaces : Vec<&'static str>,
         create_interface : Box<dyn Fn(&Context)>
@@ -279,7 +352,7 @@ mod tests {
     impl Drop for Context{
         fn drop(&mut self) {
             self.interfaces.iter().for_each(|ele| {
-                let _ = delete_device(*ele) ;
+                let _ = delete_device(&name(ele)) ;
             });
         }
     }
@@ -289,8 +362,8 @@ mod tests {
             interfaces : vec![ "wg11", "wg10"],
             create_interface: Box::new(| this: &Context| {
                 for ele in this.interfaces.clone() {
-                    let _ = delete_device(ele).unwrap_or_default();
-                    let _ = add_device(ele).unwrap_or_else(|e| {
+                    delete_device(&name(ele)).unwrap_or_default();
+                    add_device(&name(ele)).unwrap_or_else(|e| {
                         panic!("{:?}",e)
                     });
                 }
@@ -310,7 +383,7 @@ mod tests {
         let device = *ctx.interfaces.first().unwrap();
 
         let result = list_device_names();
-        assert!((matches!(result, Some(_) )),"list should never return none");
+        assert!(result.is_ok(),"{:?}", result);
         assert_eq!(result.unwrap().first().unwrap().as_str(), device);
         drop(ctx)
     }
@@ -322,18 +395,18 @@ mod tests {
         let device  = *(device.first().unwrap());
         drop(ctx);
 
-        let result = add_device(device); 
+        let result = add_device(&name(device)); 
         match result {
             Ok(r) => {
                 assert!(matches!(r, ()),"{:?}",result );
             },
             Err(e) => {
-                assert_eq!(e.kind(), std::io::ErrorKind::AlreadyExists)
+                assert!(matches!(e, Error::AlreadyExists { .. }), "{:?}", e)
             }
         }
         
         
-        let result = delete_device(device); 
+        let result = delete_device(&name(device)); 
         assert!(matches!(result, Ok(())),"{:?}", result );
     }
 
@@ -344,18 +417,12 @@ mod tests {
 
         let device = *ctx.interfaces.first().unwrap();
 
-        let result = get_device( device);
-        let transform = unsafe {
-            |d: wg_device| {
-                let data =  d.name.as_ptr().cast() as *const ::std::os::raw::c_char ;
-                let c_buffer = CStr::from_ptr( data ) ;
-                c_buffer.to_str().to_owned()
-            }
-        };
-        let tmp: Result<wg_device, std::io::Error> = result.or_else(|e| {
+        let result = get_device( &name(device));
+        let tmp = result.unwrap_or_else(|e| {
             panic!("{:?}", e)
         });
-        assert!(matches!(transform(tmp.unwrap()), Ok(x) if x == device)); 
+        assert_eq!(tmp.name(), device);
+        assert!(tmp.peers().is_empty());
         drop(ctx);
     }
 }
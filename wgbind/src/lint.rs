@@ -0,0 +1,428 @@
+//! Semantic checks of a configuration before it is written to the kernel
+//!
+//! The kernel accepts a number of configurations which do not do what their
+//! author intended: two peers with the same allowed ip silently move the
+//! route to the one written last, a peer with the device's own public key is
+//! ignored and a device without a private key never completes a handshake.
+//! [`lint`] reports these as [`Finding`]s with a [`Severity`] and a
+//! [`Location`], for parsed configurations as well as for live devices via
+//! [`lint_device`].
+//!
+//! [`lint_conf`] checks a configuration file, which may contain what the
+//! configuration model cannot hold, such as a persistent keepalive interval
+//! above 65535.
+//!
+//! # Example
+//!
+//! ```
+//! use wgbind::lint::{lint_conf, Severity};
+//!
+//! let input = "[Interface]\nListenPort = 51820\n";
+//! let findings = lint_conf(input).unwrap();
+//! assert!(findings.iter().any(|finding| finding.severity == Severity::Warning));
+//! ```
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::allowed_ip::AllowedIp;
+use crate::conf::{self, ParseError, Token};
+use crate::config::DeviceConfig;
+use crate::device::Device;
+use crate::key::PublicKey;
+
+/// How bad a finding is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Severity {
+    /// worth knowing, most likely intended
+    Info,
+    /// works, but probably not as intended
+    Warning,
+    /// the kernel rejects or ignores this part of the configuration
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+/// Where a finding was made
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Location {
+    Interface,
+    /// the peer at `index` within the configuration
+    Peer { index: usize, public_key: PublicKey },
+    /// a position within a configuration file
+    Line { line: usize, column: usize },
+    /// a whole device, for checks across several devices
+    Device(String),
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Location::Interface => f.write_str("[Interface]"),
+            Location::Peer { index, public_key } => write!(f, "[Peer] #{} {}", index + 1, public_key),
+            Location::Line { line, column } => write!(f, "line {}, column {}", line, column),
+            Location::Device(name) => write!(f, "device {}", name),
+        }
+    }
+}
+
+/// What was found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Lint {
+    /// without a private key the device cannot complete any handshake
+    MissingPrivateKey,
+    /// a zero private key removes the private key of the device
+    ZeroPrivateKey,
+    /// port 0 picks a random port on every start, peers cannot initiate handshakes reliably
+    RandomListenPort,
+    /// the kernel rejects a peer with a zero public key
+    ZeroPublicKey,
+    /// the same public key as the peer at `first`, only one peer ends up on the device
+    DuplicatePublicKey { first: usize },
+    /// the public key of the device itself, the kernel ignores such a peer
+    PeerIsSelf,
+    /// the same allowed ip as the peer at `first`, the route moves to the later peer
+    DuplicateAllowedIp { allowed_ip: AllowedIp, first: usize },
+    /// the kernel clears the host bits, the entry will be reported differently
+    HostBitsSet(AllowedIp),
+    /// a hostname is resolved once when the configuration is applied, the kernel only knows addresses
+    EndpointHostname(String),
+    /// another device listens on the same port already, one of them fails to start
+    ListenPortCollision { port: u16, other: String },
+    /// a persistent keepalive interval above 65535 seconds, wg(8) rejects the file
+    KeepaliveOutOfRange(u64),
+}
+
+impl Lint {
+    pub fn severity(&self) -> Severity {
+        match self {
+            Lint::ZeroPublicKey | Lint::DuplicatePublicKey { .. } | Lint::PeerIsSelf => Severity::Error,
+            Lint::ListenPortCollision { .. } | Lint::KeepaliveOutOfRange(_) => Severity::Error,
+            Lint::MissingPrivateKey | Lint::ZeroPrivateKey | Lint::DuplicateAllowedIp { .. } => Severity::Warning,
+            Lint::EndpointHostname(_) => Severity::Warning,
+            Lint::RandomListenPort | Lint::HostBitsSet(_) => Severity::Info,
+        }
+    }
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Lint::MissingPrivateKey => write!(f, "no private key, handshakes cannot complete"),
+            Lint::ZeroPrivateKey => write!(f, "zero private key removes the private key"),
+            Lint::RandomListenPort => write!(f, "listen port 0 picks a random port"),
+            Lint::ZeroPublicKey => write!(f, "zero public key"),
+            Lint::DuplicatePublicKey { first } => write!(f, "same public key as peer #{}", first + 1),
+            Lint::PeerIsSelf => write!(f, "public key of the interface itself"),
+            Lint::DuplicateAllowedIp { allowed_ip, first } => {
+                write!(f, "allowed ip {} is routed to peer #{} already", allowed_ip, first + 1)
+            }
+            Lint::HostBitsSet(allowed_ip) => {
+                write!(f, "allowed ip {} has host bits set, stored as {}", allowed_ip, allowed_ip.normalized())
+            }
+            Lint::EndpointHostname(endpoint) => write!(f, "endpoint {} is a hostname", endpoint),
+            Lint::ListenPortCollision { port, other } => write!(f, "listen port {} is used by {} too", port, other),
+            Lint::KeepaliveOutOfRange(interval) => {
+                write!(f, "persistent keepalive {} exceeds {} seconds", interval, u16::MAX)
+            }
+        }
+    }
+}
+
+/// A single problem of a configuration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub severity: Severity,
+    pub location: Location,
+    pub lint: Lint,
+}
+
+impl Finding {
+    fn new(location: Location, lint: Lint) -> Self {
+        Self {
+            severity: lint.severity(),
+            location,
+            lint,
+        }
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}: {}", self.severity, self.location, self.lint)
+    }
+}
+
+/// Checks a configuration, findings are ordered by location
+pub fn lint(config: &DeviceConfig) -> Vec<Finding> {
+    check(config, None)
+}
+
+/// `public_key` of the device stands in for a private key which was not read
+fn check(config: &DeviceConfig, public_key: Option<PublicKey>) -> Vec<Finding> {
+    let mut findings = Vec::new();
+
+    let own_key = match &config.private_key {
+        None if public_key.is_some() => public_key,
+        None => {
+            findings.push(Finding::new(Location::Interface, Lint::MissingPrivateKey));
+            None
+        }
+        Some(key) if key.is_zero() => {
+            findings.push(Finding::new(Location::Interface, Lint::ZeroPrivateKey));
+            None
+        }
+        Some(key) => Some(key.public_key()),
+    };
+    if config.listen_port == Some(0) {
+        findings.push(Finding::new(Location::Interface, Lint::RandomListenPort));
+    }
+
+    let mut keys: HashMap<&PublicKey, usize> = HashMap::new();
+    let mut routes: HashMap<AllowedIp, usize> = HashMap::new();
+
+    for (index, peer) in config.peers.iter().enumerate().filter(|(_, peer)| !peer.remove) {
+        let location = Location::Peer {
+            index,
+            public_key: peer.public_key,
+        };
+        let mut found = |lint| findings.push(Finding::new(location.clone(), lint));
+
+        if peer.public_key.is_zero() {
+            found(Lint::ZeroPublicKey);
+        }
+        if own_key.as_ref() == Some(&peer.public_key) {
+            found(Lint::PeerIsSelf);
+        }
+        match keys.get(&peer.public_key) {
+            Some(first) => found(Lint::DuplicatePublicKey { first: *first }),
+            None => {
+                keys.insert(&peer.public_key, index);
+            }
+        }
+
+        for allowed_ip in &peer.allowed_ips {
+            if !allowed_ip.is_normalized() {
+                found(Lint::HostBitsSet(*allowed_ip));
+            }
+            match routes.get(&allowed_ip.normalized()) {
+                Some(first) if *first != index => found(Lint::DuplicateAllowedIp {
+                    allowed_ip: *allowed_ip,
+                    first: *first,
+                }),
+                Some(_) => {}
+                None => {
+                    routes.insert(allowed_ip.normalized(), index);
+                }
+            }
+        }
+    }
+
+    findings
+}
+
+/// Checks the configuration of a live device, see [`get_device`](crate::get_device)
+///
+/// A device read without its private key is checked against its public key.
+pub fn lint_device(device: &Device) -> Vec<Finding> {
+    check(&device.to_config(), device.public_key().copied())
+}
+
+/// Parses and checks a configuration file meant for `wg setconf`
+///
+/// In addition to [`lint`] this finds endpoints given as hostname, which are
+/// not resolved here, and keepalive intervals too large for the kernel.
+pub fn lint_conf(input: &str) -> Result<Vec<Finding>, ParseError> {
+    let mut findings = Vec::new();
+
+    // out of range intervals are blanked, so the rest of the file is still checked
+    let mut lines = input.lines().collect::<Vec<_>>();
+    let mut peer = false;
+    for token in conf::tokenize(input) {
+        match token {
+            Ok(Token::Section { name, .. }) => peer = name.eq_ignore_ascii_case("Peer"),
+            Ok(Token::Entry(entry)) if peer && entry.key.eq_ignore_ascii_case("PersistentKeepalive") => {
+                let Some(interval) = entry.value.parse::<u64>().ok().filter(|interval| *interval > u16::MAX.into())
+                else {
+                    continue;
+                };
+                let location = Location::Line {
+                    line: entry.line,
+                    column: entry.value_column,
+                };
+                findings.push(Finding::new(location, Lint::KeepaliveOutOfRange(interval)));
+                lines[entry.line - 1] = "";
+            }
+            // errors are reported by parsing
+            _ => {}
+        }
+    }
+
+    let (config, hosts) = conf::parse_keeping_hosts(&lines.join("\n"), |_| Ok(false))?;
+    let config = conf::replace_all(config);
+
+    for host in hosts {
+        let index = config.peers.iter().position(|peer| peer.public_key == host.public_key).unwrap_or_default();
+        let location = Location::Peer {
+            index,
+            public_key: host.public_key,
+        };
+        findings.push(Finding::new(location, Lint::EndpointHostname(host.to_string())));
+    }
+    findings.extend(lint(&config));
+
+    Ok(findings)
+}
+
+/// Checks the listen ports of several devices against each other
+pub fn lint_listen_ports<'a>(devices: impl IntoIterator<Item = (&'a str, &'a DeviceConfig)>) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    let mut ports: HashMap<u16, &str> = HashMap::new();
+
+    for (name, config) in devices {
+        let Some(port) = config.listen_port.filter(|port| *port != 0) else {
+            continue;
+        };
+        match ports.get(&port) {
+            Some(other) => findings.push(Finding::new(
+                Location::Device(name.to_owned()),
+                Lint::ListenPortCollision {
+                    port,
+                    other: (*other).to_owned(),
+                },
+            )),
+            None => {
+                ports.insert(port, name);
+            }
+        }
+    }
+
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PeerConfig;
+    use crate::key::PrivateKey;
+
+    fn peer(key: u8, allowed_ips: &[&str]) -> PeerConfig {
+        PeerConfig::new(PublicKey::from_bytes([key; 32])).allowed_ips(allowed_ips.iter().map(|ip| ip.parse().unwrap()))
+    }
+
+    fn lints(findings: &[Finding]) -> Vec<&Lint> {
+        findings.iter().map(|finding| &finding.lint).collect()
+    }
+
+    #[test]
+    fn it_accepts_a_sane_configuration() {
+        let config = DeviceConfig::new()
+            .private_key(PrivateKey::from_bytes([1; 32]))
+            .listen_port(51820)
+            .peer(peer(2, &["10.0.0.2/32"]))
+            .peer(peer(3, &["10.0.0.3/32", "10.0.0.0/24"]));
+
+        assert_eq!(lint(&config), []);
+    }
+
+    #[test]
+    fn it_finds_problems_of_the_interface_and_peers() {
+        let private = PrivateKey::from_bytes([1; 32]);
+        let own = private.public_key();
+        let config = DeviceConfig::new()
+            .private_key(private)
+            .listen_port(0)
+            .peer(peer(2, &["10.0.0.0/24"]))
+            .peer(peer(2, &["10.0.0.1/24"]))
+            .peer(PeerConfig::new(own))
+            .peer(peer(0, &[]))
+            .peer(peer(4, &["10.0.0.0/24"]).remove(true));
+
+        let findings = lint(&config);
+        assert_eq!(
+            lints(&findings),
+            [
+                &Lint::RandomListenPort,
+                &Lint::DuplicatePublicKey { first: 0 },
+                &Lint::HostBitsSet("10.0.0.1/24".parse().unwrap()),
+                &Lint::DuplicateAllowedIp {
+                    allowed_ip: "10.0.0.1/24".parse().unwrap(),
+                    first: 0
+                },
+                &Lint::PeerIsSelf,
+                &Lint::ZeroPublicKey,
+            ]
+        );
+        assert_eq!(findings[1].severity, Severity::Error);
+        assert_eq!(
+            findings[1].location,
+            Location::Peer {
+                index: 1,
+                public_key: PublicKey::from_bytes([2; 32])
+            }
+        );
+
+        assert_eq!(lints(&lint(&DeviceConfig::new())), [&Lint::MissingPrivateKey]);
+
+        // a device read without privileges still has its public key
+        let input = format!(
+            "{}\t{}\t51820\toff\n{}\t(none)\t(none)\t(none)\t0\t0\t0\toff\n",
+            PrivateKey::from_bytes([1; 32]),
+            own,
+            own
+        );
+        let device = crate::dump::parse(&"wg0".parse().unwrap(), &input).unwrap().redacted();
+        assert_eq!(device.private_key(), None);
+        assert_eq!(lints(&lint_device(&device)), [&Lint::PeerIsSelf]);
+    }
+
+    #[test]
+    fn it_finds_problems_of_files_and_port_collisions() {
+        let input = format!(
+            "[Interface]\nPrivateKey = {}\n[Peer]\nPublicKey = {}\nEndpoint = vpn.example.invalid:51820\n",
+            PrivateKey::from_bytes([1; 32]),
+            PublicKey::from_bytes([2; 32])
+        );
+
+        let findings = lint_conf(&input).unwrap();
+        // never resolved, an unknown host is a finding rather than an error
+        assert_eq!(lints(&findings), [&Lint::EndpointHostname("vpn.example.invalid:51820".into())]);
+        let public_key = PublicKey::from_bytes([2; 32]);
+        assert_eq!(findings[0].location, Location::Peer { index: 0, public_key });
+        assert!(findings[0].to_string().starts_with("warning: [Peer] #1"));
+
+        let input = format!(
+            "[Interface]\nPrivateKey = {}\n[Peer]\nPublicKey = {}\nPersistentKeepalive = 65536\n",
+            PrivateKey::from_bytes([1; 32]),
+            PublicKey::from_bytes([2; 32])
+        );
+        let findings = lint_conf(&input).unwrap();
+        assert_eq!(lints(&findings), [&Lint::KeepaliveOutOfRange(65536)]);
+        assert_eq!(findings[0].location, Location::Line { line: 5, column: 23 });
+        assert_eq!(findings[0].severity, Severity::Error);
+
+        let first = DeviceConfig::new().listen_port(51820);
+        let second = DeviceConfig::new().listen_port(51820);
+        let random = DeviceConfig::new().listen_port(0);
+        let findings = lint_listen_ports([("wg0", &first), ("wg1", &second), ("wg2", &random), ("wg3", &random)]);
+        assert_eq!(
+            findings,
+            [Finding::new(
+                Location::Device("wg1".into()),
+                Lint::ListenPortCollision {
+                    port: 51820,
+                    other: "wg0".into()
+                }
+            )]
+        );
+    }
+}
@@ -0,0 +1,326 @@
+//! Devices kept in memory, for tests without root or the kernel module
+//!
+//! [`Mock`] implements [`Backend`] the way the kernel does: names are unique,
+//! missing devices answer `ENODEV`, and a configuration is applied in the order
+//! of the kernel's set_device, including `replace_peers`, removing peers,
+//! `replace_allowed_ips` and allowed ips moving from one peer to another.
+//! Faults can be injected to check how callers deal with failing calls.
+//!
+//! ```
+//! use wgbind::{Backend, DeviceConfig, Mock, PeerConfig, PrivateKey};
+//!
+//! let mock = Mock::new();
+//! let name = "wg0".parse().unwrap();
+//! mock.add(&name).unwrap();
+//!
+//! let peer = PrivateKey::generate().public_key();
+//! let config = DeviceConfig::new().peer(PeerConfig::new(peer).allowed_ip("10.0.0.2/32".parse().unwrap()));
+//! mock.set(&name, &config).unwrap();
+//! assert_eq!(mock.get(&name).unwrap().peers().len(), 1);
+//!
+//! // the next call fails as if interrupted by a signal
+//! mock.fail_call(mock.calls() + 1, libc::EINTR);
+//! assert_eq!(mock.list().unwrap_err().errno(), Some(libc::EINTR));
+//! ```
+
+use std::sync::{Mutex, MutexGuard};
+
+use crate::backend::Backend;
+use crate::config::{DeviceConfig, PeerConfig};
+use crate::device::{Device, Peer};
+use crate::error::{Error, Operation};
+use crate::interface_name::InterfaceName;
+
+#[derive(Debug, Default)]
+struct State {
+    devices: Vec<Device>,
+    last_ifindex: u32,
+    calls: usize,
+    /// call number and the errno it fails with
+    faults: Vec<(usize, i32)>,
+}
+
+/// An in memory [`Backend`] following the semantics of the kernel module
+///
+/// Peers never handshake, their handshake time and transfer counters stay zero.
+#[derive(Debug, Default)]
+pub struct Mock {
+    state: Mutex<State>,
+}
+
+impl Mock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of calls handled so far, including failed ones
+    pub fn calls(&self) -> usize {
+        self.state().calls
+    }
+
+    /// Lets the `nth` call fail with `errno` without any effect, counting from 1
+    ///
+    /// Calls are counted across all operations, see [`Mock::calls`].
+    pub fn fail_call(&self, nth: usize, errno: i32) {
+        self.state().faults.push((nth, errno));
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        // a panicking test must not poison the devices for the others
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Counts the call and applies an injected fault
+    fn enter(&self, operation: Operation, name: Option<&InterfaceName>) -> Result<MutexGuard<'_, State>, Error> {
+        let mut state = self.state();
+        state.calls += 1;
+
+        let calls = state.calls;
+        match state.faults.iter().position(|(nth, _)| *nth == calls) {
+            Some(index) => {
+                let (_, errno) = state.faults.remove(index);
+                Err(Error::from_errno(operation, name.map(InterfaceName::as_str), errno))
+            }
+            None => Ok(state),
+        }
+    }
+}
+
+impl State {
+    fn device(&mut self, operation: Operation, name: &InterfaceName) -> Result<&mut Device, Error> {
+        self.devices
+            .iter_mut()
+            .find(|device| device.name == name.as_str())
+            .ok_or_else(|| Error::from_errno(operation, Some(name.as_str()), libc::ENODEV))
+    }
+}
+
+impl Backend for Mock {
+    fn list(&self) -> Result<Vec<String>, Error> {
+        let state = self.enter(Operation::ListDevices, None)?;
+
+        Ok(state.devices.iter().map(|device| device.name.clone()).collect())
+    }
+
+    fn get(&self, name: &InterfaceName) -> Result<Device, Error> {
+        let mut state = self.enter(Operation::GetDevice, Some(name))?;
+
+        state.device(Operation::GetDevice, name).cloned()
+    }
+
+    fn set(&self, name: &InterfaceName, config: &DeviceConfig) -> Result<(), Error> {
+        let mut state = self.enter(Operation::SetDevice, Some(name))?;
+
+        apply(state.device(Operation::SetDevice, name)?, config);
+        Ok(())
+    }
+
+    fn add(&self, name: &InterfaceName) -> Result<(), Error> {
+        let mut state = self.enter(Operation::AddDevice, Some(name))?;
+        if state.device(Operation::AddDevice, name).is_ok() {
+            return Err(Error::from_errno(Operation::AddDevice, Some(name.as_str()), libc::EEXIST));
+        }
+
+        state.last_ifindex += 1;
+        let device = Device {
+            name: name.as_str().to_owned(),
+            ifindex: state.last_ifindex,
+            public_key: None,
+            private_key: None,
+            fwmark: 0,
+            listen_port: 0,
+            peers: Vec::new(),
+        };
+        state.devices.push(device);
+
+        Ok(())
+    }
+
+    fn delete(&self, name: &InterfaceName) -> Result<(), Error> {
+        let mut state = self.enter(Operation::DeleteDevice, Some(name))?;
+        state.device(Operation::DeleteDevice, name)?;
+
+        state.devices.retain(|device| device.name != name.as_str());
+        Ok(())
+    }
+}
+
+/// Same order as set_device of the kernel
+fn apply(device: &mut Device, config: &DeviceConfig) {
+    if let Some(key) = &config.private_key {
+        if key.is_zero() {
+            device.private_key = None;
+            device.public_key = None;
+        } else {
+            let public_key = key.public_key();
+            // a device cannot be its own peer
+            device.peers.retain(|peer| peer.public_key != public_key);
+            device.private_key = Some(key.clone());
+            device.public_key = Some(public_key);
+        }
+    }
+    if let Some(port) = config.listen_port {
+        device.listen_port = port;
+    }
+    if let Some(fwmark) = config.fwmark {
+        device.fwmark = fwmark;
+    }
+    if config.replace_peers {
+        device.peers.clear();
+    }
+
+    for peer in &config.peers {
+        apply_peer(device, peer);
+    }
+}
+
+fn apply_peer(device: &mut Device, config: &PeerConfig) {
+    let existing = device.peers.iter().position(|peer| peer.public_key == config.public_key);
+    if config.remove {
+        if let Some(index) = existing {
+            device.peers.remove(index);
+        }
+        return;
+    }
+
+    let index = match existing {
+        Some(index) => index,
+        // the kernel silently skips a peer with the key of the device itself
+        None if device.public_key.as_ref() == Some(&config.public_key) => return,
+        None => {
+            device.peers.push(Peer {
+                public_key: config.public_key,
+                preshared_key: None,
+                endpoint: None,
+                last_handshake_time: None,
+                rx_bytes: 0,
+                tx_bytes: 0,
+                persistent_keepalive_interval: 0,
+                allowed_ips: Vec::new(),
+            });
+            device.peers.len() - 1
+        }
+    };
+
+    let peer = &mut device.peers[index];
+    if let Some(key) = &config.preshared_key {
+        peer.preshared_key = (!key.is_zero()).then(|| key.clone());
+    }
+    if let Some(endpoint) = config.endpoint {
+        peer.endpoint = Some(endpoint);
+    }
+    if let Some(interval) = config.persistent_keepalive_interval {
+        peer.persistent_keepalive_interval = interval;
+    }
+    if config.replace_allowed_ips {
+        peer.allowed_ips.clear();
+    }
+
+    for allowed_ip in config.allowed_ips.iter().map(|allowed_ip| allowed_ip.normalized()) {
+        // an allowed ip belongs to a single peer, the last one it was given to
+        for (other, peer) in device.peers.iter_mut().enumerate() {
+            if other != index {
+                peer.allowed_ips.retain(|existing| *existing != allowed_ip);
+            }
+        }
+
+        let allowed_ips = &mut device.peers[index].allowed_ips;
+        if !allowed_ips.contains(&allowed_ip) {
+            allowed_ips.push(allowed_ip);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::{PresharedKey, PrivateKey, PublicKey};
+
+    fn name(interface: &str) -> InterfaceName {
+        interface.parse().unwrap()
+    }
+
+    fn public_key(byte: u8) -> PublicKey {
+        PublicKey::from_bytes([byte; 32])
+    }
+
+    #[test]
+    fn it_follows_the_kernel_semantics() {
+        let mock = Mock::new();
+        assert!(matches!(mock.get(&name("wg0")), Err(Error::NoSuchDevice { .. })));
+        assert!(matches!(mock.set(&name("wg0"), &DeviceConfig::new()), Err(Error::NoSuchDevice { .. })));
+
+        mock.add(&name("wg0")).unwrap();
+        mock.add(&name("wg1")).unwrap();
+        assert!(matches!(mock.add(&name("wg0")), Err(Error::AlreadyExists { .. })));
+        assert_eq!(mock.list().unwrap(), vec!["wg0", "wg1"]);
+        assert_eq!(mock.get(&name("wg1")).unwrap().ifindex(), 2);
+
+        let private_key = PrivateKey::from_bytes([9; 32]);
+        let config = DeviceConfig::new()
+            .private_key(private_key.clone())
+            .listen_port(51820)
+            .peer(PeerConfig::new(public_key(1)).allowed_ips(["10.0.0.1/24".parse().unwrap(), "10.1.0.1/32".parse().unwrap()]))
+            .peer(PeerConfig::new(public_key(2)).preshared_key(PresharedKey::from_bytes([5; 32])))
+            // skipped, it is the device itself
+            .peer(PeerConfig::new(private_key.public_key()));
+        mock.set(&name("wg0"), &config).unwrap();
+
+        // the second peer steals an allowed ip, the first gets another one added
+        let config = DeviceConfig::new()
+            .peer(PeerConfig::new(public_key(2)).allowed_ip("10.1.0.1/32".parse().unwrap()))
+            .peer(PeerConfig::new(public_key(1)).allowed_ip("10.2.0.0/16".parse().unwrap()));
+        mock.set(&name("wg0"), &config).unwrap();
+
+        let device = mock.get(&name("wg0")).unwrap();
+        assert_eq!(device.public_key(), Some(&private_key.public_key()));
+        assert_eq!(device.listen_port(), 51820);
+        assert_eq!(device.peers().len(), 2);
+        assert_eq!(device.peers()[0].allowed_ips(), &["10.0.0.0/24".parse().unwrap(), "10.2.0.0/16".parse().unwrap()]);
+        assert_eq!(device.peers()[1].allowed_ips(), &["10.1.0.1/32".parse().unwrap()]);
+        assert_eq!(device.peers()[1].preshared_key(), Some(&PresharedKey::from_bytes([5; 32])));
+
+        let config = DeviceConfig::new()
+            .peer(PeerConfig::new(public_key(1)).remove(true))
+            .peer(
+                PeerConfig::new(public_key(2))
+                    .preshared_key(PresharedKey::from_bytes([0; 32]))
+                    .replace_allowed_ips(true)
+                    .allowed_ip("10.3.0.0/16".parse().unwrap()),
+            );
+        mock.set(&name("wg0"), &config).unwrap();
+        let device = mock.get(&name("wg0")).unwrap();
+        assert_eq!(device.peers().len(), 1);
+        assert_eq!(device.peers()[0].preshared_key(), None);
+        assert_eq!(device.peers()[0].allowed_ips(), &["10.3.0.0/16".parse().unwrap()]);
+
+        let config = DeviceConfig::new().replace_peers(true).peer(PeerConfig::new(public_key(3)));
+        mock.set(&name("wg0"), &config).unwrap();
+        let device = mock.get(&name("wg0")).unwrap();
+        assert_eq!(device.peers().iter().map(|peer| peer.public_key()).collect::<Vec<_>>(), vec![&public_key(3)]);
+
+        mock.delete(&name("wg0")).unwrap();
+        assert!(matches!(mock.delete(&name("wg0")), Err(Error::NoSuchDevice { .. })));
+        assert_eq!(mock.list().unwrap(), vec!["wg1"]);
+    }
+
+    #[test]
+    fn it_injects_faults() {
+        let mock = Mock::new();
+        mock.fail_call(1, libc::EPERM);
+        mock.fail_call(3, libc::EINTR);
+
+        let result = mock.add(&name("wg0"));
+        assert!(matches!(result, Err(Error::PermissionDenied { operation: Operation::AddDevice, .. })), "{:?}", result);
+        assert!(mock.list().unwrap().is_empty());
+
+        mock.add(&name("wg0")).unwrap_err();
+        mock.add(&name("wg0")).unwrap();
+        assert_eq!(mock.calls(), 4);
+
+        // a failed set leaves the device untouched
+        mock.fail_call(mock.calls() + 1, libc::EINTR);
+        assert_eq!(mock.set(&name("wg0"), &DeviceConfig::new().fwmark(1)).unwrap_err().errno(), Some(libc::EINTR));
+        assert_eq!(mock.get(&name("wg0")).unwrap().fwmark(), 0);
+    }
+}
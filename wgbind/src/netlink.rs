@@ -0,0 +1,1081 @@
+//! Rust implementation of the device functions of the c library
+//!
+//! Used instead of the c functions with the `netlink` feature, which spares the
+//! build from bindgen, libclang and a c compiler. The functions speak the
+//! wireguard generic netlink family and rtnetlink directly and follow wireguard.c
+//! step by step: same messages, same splitting of large peer sets, same return
+//! values. They even allocate through calloc, so a device read here is released
+//! by [`wg_free_device`] just like one of the c library and the callers in this
+//! crate work unchanged.
+
+use std::ffi::CStr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::ptr;
+
+use ::core::ffi::{c_char, c_int};
+use wgbindraw_sys::*;
+
+use crate::curve25519::wg_key_is_zero;
+
+const WG_GENL_NAME: &CStr = c"wireguard";
+const WG_GENL_VERSION: u8 = 1;
+
+const WG_CMD_GET_DEVICE: u8 = 0;
+const WG_CMD_SET_DEVICE: u8 = 1;
+
+const WGDEVICE_A_IFINDEX: u16 = 1;
+const WGDEVICE_A_IFNAME: u16 = 2;
+const WGDEVICE_A_PRIVATE_KEY: u16 = 3;
+const WGDEVICE_A_PUBLIC_KEY: u16 = 4;
+const WGDEVICE_A_FLAGS: u16 = 5;
+const WGDEVICE_A_LISTEN_PORT: u16 = 6;
+const WGDEVICE_A_FWMARK: u16 = 7;
+const WGDEVICE_A_PEERS: u16 = 8;
+
+const WGPEER_A_PUBLIC_KEY: u16 = 1;
+const WGPEER_A_PRESHARED_KEY: u16 = 2;
+const WGPEER_A_FLAGS: u16 = 3;
+const WGPEER_A_ENDPOINT: u16 = 4;
+const WGPEER_A_PERSISTENT_KEEPALIVE_INTERVAL: u16 = 5;
+const WGPEER_A_LAST_HANDSHAKE_TIME: u16 = 6;
+const WGPEER_A_RX_BYTES: u16 = 7;
+const WGPEER_A_TX_BYTES: u16 = 8;
+const WGPEER_A_ALLOWEDIPS: u16 = 9;
+
+const WGALLOWEDIP_A_FAMILY: u16 = 1;
+const WGALLOWEDIP_A_IPADDR: u16 = 2;
+const WGALLOWEDIP_A_CIDR_MASK: u16 = 3;
+
+const WGDEVICE_F_REPLACE_PEERS: u32 = 1;
+const WGPEER_F_REMOVE_ME: u32 = 1;
+const WGPEER_F_REPLACE_ALLOWEDIPS: u32 = 2;
+
+/// size of struct nlmsghdr
+const HEADER_LENGTH: usize = 16;
+/// size of struct nlattr
+const ATTRIBUTE_HEADER_LENGTH: usize = 4;
+/// size of struct genlmsghdr
+const GENERIC_HEADER_LENGTH: usize = 4;
+/// size of struct ifinfomsg
+const IFINFO_LENGTH: usize = 16;
+
+/// errno values, 0 stands for a failure which did not set errno
+type Errno = c_int;
+
+fn align(length: usize) -> usize {
+    (length + 3) & !3
+}
+
+/// The page size but at most 8k, used for sending as well as receiving
+fn buffer_size() -> usize {
+    let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    (size as usize).min(8192)
+}
+
+fn last_errno() -> Errno {
+    std::io::Error::last_os_error().raw_os_error().unwrap_or(libc::EINVAL)
+}
+
+fn set_errno(errno: Errno) {
+    unsafe { *libc::__errno_location() = errno };
+}
+
+/// Turns a result into the 0 or -errno of the c library, errno is set as well
+fn to_c(result: Result<(), Errno>) -> c_int {
+    let ret = match result {
+        Ok(()) => 0,
+        Err(errno) => -errno,
+    };
+    set_errno(-ret);
+    ret
+}
+
+fn now() -> u32 {
+    unsafe { libc::time(ptr::null_mut()) as u32 }
+}
+
+fn has_peer_flag(peer: &wg_peer, flag: wg_peer_flags) -> bool {
+    peer.flags & flag == flag
+}
+
+fn has_device_flag(device: &wg_device, flag: wg_device_flags) -> bool {
+    device.flags & flag == flag
+}
+
+/// Bytes of a raw value, used for keys, addresses and sockaddrs
+fn bytes_of<T>(value: &T) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(value as *const T as *const u8, std::mem::size_of::<T>()) }
+}
+
+/// Copies an attribute payload into a raw value of at least the same size
+fn copy_into<T>(value: &mut T, payload: &[u8]) {
+    debug_assert!(payload.len() <= std::mem::size_of::<T>());
+    unsafe { ptr::copy_nonoverlapping(payload.as_ptr(), value as *mut T as *mut u8, payload.len()) };
+}
+
+/// The name of a raw device, the array need not be \0 terminated
+fn device_name(name: &[c_char]) -> Vec<u8> {
+    name.iter().take_while(|c| **c != 0).map(|c| *c as u8).collect()
+}
+
+/// A netlink message under construction
+///
+/// The `_check` variants refuse to grow the message beyond `limit`, which is how
+/// wg_set_device splits a large device over several messages.
+struct Message {
+    buffer: Vec<u8>,
+    limit: usize,
+}
+
+impl Message {
+    fn new(kind: u16, flags: u16, seq: u32, limit: usize) -> Self {
+        let mut buffer = Vec::with_capacity(limit);
+        buffer.extend_from_slice(&0u32.to_ne_bytes());
+        buffer.extend_from_slice(&kind.to_ne_bytes());
+        buffer.extend_from_slice(&flags.to_ne_bytes());
+        buffer.extend_from_slice(&seq.to_ne_bytes());
+        buffer.extend_from_slice(&0u32.to_ne_bytes());
+
+        Self { buffer, limit }
+    }
+
+    fn put_extra_header(&mut self, header: &[u8]) {
+        self.buffer.extend_from_slice(header);
+        self.buffer.resize(align(self.buffer.len()), 0);
+    }
+
+    fn put(&mut self, kind: u16, payload: &[u8]) {
+        let length = (ATTRIBUTE_HEADER_LENGTH + payload.len()) as u16;
+        self.buffer.extend_from_slice(&length.to_ne_bytes());
+        self.buffer.extend_from_slice(&kind.to_ne_bytes());
+        self.buffer.extend_from_slice(payload);
+        self.buffer.resize(align(self.buffer.len()), 0);
+    }
+
+    fn put_check(&mut self, kind: u16, payload: &[u8]) -> bool {
+        if self.buffer.len() + ATTRIBUTE_HEADER_LENGTH + align(payload.len()) > self.limit {
+            return false;
+        }
+
+        self.put(kind, payload);
+        true
+    }
+
+    fn put_strz(&mut self, kind: u16, value: &[u8]) {
+        let mut payload = value.to_vec();
+        payload.push(0);
+        self.put(kind, &payload);
+    }
+
+    fn nest_start(&mut self, kind: u16) -> usize {
+        let start = self.buffer.len();
+        self.put(kind | libc::NLA_F_NESTED as u16, &[]);
+        start
+    }
+
+    fn nest_start_check(&mut self, kind: u16) -> Option<usize> {
+        if self.buffer.len() + ATTRIBUTE_HEADER_LENGTH > self.limit {
+            return None;
+        }
+
+        Some(self.nest_start(kind))
+    }
+
+    fn nest_end(&mut self, start: usize) {
+        let length = (self.buffer.len() - start) as u16;
+        self.buffer[start..start + 2].copy_from_slice(&length.to_ne_bytes());
+    }
+
+    fn nest_cancel(&mut self, start: usize) {
+        self.buffer.truncate(start);
+    }
+
+    /// The finished message with its length filled in
+    fn as_bytes(&mut self) -> &[u8] {
+        let length = self.buffer.len() as u32;
+        self.buffer[..4].copy_from_slice(&length.to_ne_bytes());
+        &self.buffer
+    }
+}
+
+fn read_u16(bytes: &[u8]) -> u16 {
+    u16::from_ne_bytes([bytes[0], bytes[1]])
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// Walks the attributes within a payload as (type, payload)
+///
+/// Stops at the first malformed attribute, the nested and byte order bits are
+/// removed from the type.
+fn attributes(mut data: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        if data.len() < ATTRIBUTE_HEADER_LENGTH {
+            return None;
+        }
+        let length = read_u16(data) as usize;
+        if length < ATTRIBUTE_HEADER_LENGTH || length > data.len() {
+            return None;
+        }
+
+        let kind = read_u16(&data[2..]) & libc::NLA_TYPE_MASK as u16;
+        let payload = &data[ATTRIBUTE_HEADER_LENGTH..length];
+        data = &data[align(length).min(data.len())..];
+        Some((kind, payload))
+    })
+}
+
+/// The string of an attribute, up to the first \0
+fn attribute_str(payload: &[u8]) -> &[u8] {
+    payload.split(|b| *b == 0).next().unwrap_or_default()
+}
+
+struct Header {
+    kind: u16,
+    flags: u16,
+    seq: u32,
+    pid: u32,
+}
+
+/// Walks the netlink messages of a received buffer as (header, payload)
+fn messages(mut data: &[u8]) -> impl Iterator<Item = (Header, &[u8])> {
+    std::iter::from_fn(move || {
+        if data.len() < HEADER_LENGTH {
+            return None;
+        }
+        let length = read_u32(data) as usize;
+        if length < HEADER_LENGTH || length > data.len() {
+            return None;
+        }
+
+        let header = Header {
+            kind: read_u16(&data[4..]),
+            flags: read_u16(&data[6..]),
+            seq: read_u32(&data[8..]),
+            pid: read_u32(&data[12..]),
+        };
+        let payload = &data[HEADER_LENGTH..length];
+        data = &data[align(length).min(data.len())..];
+        Some((header, payload))
+    })
+}
+
+/// What to do after a message was handled
+#[derive(Debug, PartialEq, Eq)]
+enum Flow {
+    /// the answer goes on, receive the next buffer
+    Continue,
+    /// the answer is complete
+    Stop,
+}
+
+fn error_payload(payload: &[u8]) -> Result<Flow, Errno> {
+    if payload.len() < 4 {
+        return Err(libc::EBADMSG);
+    }
+
+    // netlink subsystems return the errno with different signedness
+    match (read_u32(payload) as i32).abs() {
+        0 => Ok(Flow::Stop),
+        errno => Err(errno),
+    }
+}
+
+/// Runs the messages of a received buffer through `data`, mirrors mnl_cb_run
+///
+/// Generic netlink additionally reports errors within NLMSG_DONE of a dump.
+fn run(
+    buffer: &[u8],
+    seq: u32,
+    portid: u32,
+    generic: bool,
+    mut data: impl FnMut(&Header, &[u8]) -> Result<Flow, Errno>,
+) -> Result<Flow, Errno> {
+    let mut flow = Flow::Continue;
+    for (header, payload) in messages(buffer) {
+        if header.pid != 0 && portid != 0 && header.pid != portid {
+            return Err(libc::ESRCH);
+        }
+        if header.seq != 0 && seq != 0 && header.seq != seq {
+            return Err(libc::EPROTO);
+        }
+        if header.flags & libc::NLM_F_DUMP_INTR as u16 != 0 {
+            return Err(libc::EINTR);
+        }
+
+        flow = match header.kind as c_int {
+            libc::NLMSG_ERROR => {
+                // the error code is followed by the offending message
+                if payload.len() < 4 + HEADER_LENGTH {
+                    return Err(libc::EBADMSG);
+                }
+                error_payload(payload)?
+            }
+            libc::NLMSG_DONE if generic && header.flags & libc::NLM_F_MULTI as u16 != 0 && payload.len() == 4 => {
+                error_payload(payload)?
+            }
+            libc::NLMSG_DONE => Flow::Stop,
+            kind if kind >= libc::NLMSG_MIN_TYPE => data(&header, payload)?,
+            _ => Flow::Continue,
+        };
+        if flow == Flow::Stop {
+            break;
+        }
+    }
+
+    Ok(flow)
+}
+
+/// A bound netlink socket
+struct Socket {
+    fd: OwnedFd,
+    portid: u32,
+    buffer: Vec<u8>,
+}
+
+impl Socket {
+    fn open(bus: c_int) -> Result<Self, Errno> {
+        let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_RAW, bus) };
+        if fd < 0 {
+            return Err(last_errno());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut address: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        let mut length = std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t;
+
+        let address_ptr = &mut address as *mut libc::sockaddr_nl as *mut libc::sockaddr;
+        if unsafe { libc::bind(fd.as_raw_fd(), address_ptr, length) } < 0 {
+            return Err(last_errno());
+        }
+        if unsafe { libc::getsockname(fd.as_raw_fd(), address_ptr, &mut length) } < 0 {
+            return Err(last_errno());
+        }
+        if length as usize != std::mem::size_of::<libc::sockaddr_nl>() || address.nl_family != libc::AF_NETLINK as libc::sa_family_t {
+            return Err(libc::EINVAL);
+        }
+
+        Ok(Self { fd, portid: address.nl_pid, buffer: vec![0; buffer_size()] })
+    }
+
+    fn send(&self, message: &[u8]) -> Result<(), Errno> {
+        let mut address: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+
+        let sent = unsafe {
+            libc::sendto(
+                self.fd.as_raw_fd(),
+                message.as_ptr().cast(),
+                message.len(),
+                0,
+                &address as *const libc::sockaddr_nl as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if sent < 0 {
+            return Err(last_errno());
+        }
+
+        Ok(())
+    }
+
+    /// Receives into the internal buffer and returns the number of bytes read
+    fn receive(&mut self) -> Result<usize, Errno> {
+        let mut address: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        let mut iov = libc::iovec { iov_base: self.buffer.as_mut_ptr().cast(), iov_len: self.buffer.len() };
+        let mut header: libc::msghdr = unsafe { std::mem::zeroed() };
+        header.msg_name = (&mut address as *mut libc::sockaddr_nl).cast();
+        header.msg_namelen = std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t;
+        header.msg_iov = &mut iov;
+        header.msg_iovlen = 1;
+
+        let received = unsafe { libc::recvmsg(self.fd.as_raw_fd(), &mut header, 0) };
+        if received < 0 {
+            return Err(last_errno());
+        }
+        if header.msg_flags & libc::MSG_TRUNC != 0 {
+            return Err(libc::ENOSPC);
+        }
+        if header.msg_namelen as usize != std::mem::size_of::<libc::sockaddr_nl>() {
+            return Err(libc::EINVAL);
+        }
+
+        Ok(received as usize)
+    }
+
+    /// Receives and runs buffers until the answer to `seq` is complete
+    fn receive_run(
+        &mut self,
+        seq: u32,
+        generic: bool,
+        mut data: impl FnMut(&Header, &[u8]) -> Result<Flow, Errno>,
+    ) -> Result<(), Errno> {
+        loop {
+            let received = self.receive()?;
+            if received == 0 {
+                return Ok(());
+            }
+            if run(&self.buffer[..received], seq, self.portid, generic, &mut data)? == Flow::Stop {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// A socket talking to one generic netlink family
+struct GenericSocket {
+    socket: Socket,
+    id: u16,
+    seq: u32,
+}
+
+impl GenericSocket {
+    /// Opens the socket and resolves the id of the family by its name
+    fn open(name: &CStr) -> Result<Self, Errno> {
+        let socket = Socket::open(libc::NETLINK_GENERIC)?;
+        let mut this = Self { socket, id: 0, seq: 0 };
+
+        let mut message = this.prepare(libc::GENL_ID_CTRL as u16, libc::CTRL_CMD_GETFAMILY as u8, 1, libc::NLM_F_REQUEST as u16 | libc::NLM_F_ACK as u16);
+        message.put_strz(libc::CTRL_ATTR_FAMILY_NAME as u16, name.to_bytes());
+        this.socket.send(message.as_bytes())?;
+
+        let mut id = 0;
+        let resolved = this.socket.receive_run(this.seq, true, |_, payload| {
+            let attribute = attributes(payload.get(GENERIC_HEADER_LENGTH..).unwrap_or_default())
+                .find(|(kind, _)| *kind == libc::CTRL_ATTR_FAMILY_ID as u16)
+                .ok_or(0)?;
+            if attribute.1.len() != 2 {
+                return Err(libc::ERANGE);
+            }
+
+            id = read_u16(attribute.1);
+            Ok(Flow::Continue)
+        });
+
+        match resolved {
+            Ok(()) => {
+                this.id = id;
+                Ok(this)
+            }
+            Err(libc::ENOENT) => Err(libc::EPROTONOSUPPORT),
+            Err(0) => Err(libc::ENOSYS),
+            Err(errno) => Err(errno),
+        }
+    }
+
+    fn prepare(&mut self, id: u16, command: u8, version: u8, flags: u16) -> Message {
+        self.seq = now();
+
+        let mut message = Message::new(id, flags, self.seq, buffer_size());
+        message.put_extra_header(&[command, version, 0, 0]);
+        message
+    }
+
+    fn prepare_wireguard(&mut self, command: u8, flags: u16) -> Message {
+        self.prepare(self.id, command, WG_GENL_VERSION, flags)
+    }
+
+    fn send_and_run(&mut self, message: &mut Message, data: impl FnMut(&Header, &[u8]) -> Result<Flow, Errno>) -> Result<(), Errno> {
+        self.socket.send(message.as_bytes())?;
+        self.socket.receive_run(self.seq, true, data)
+    }
+}
+
+/// Where the next message of wg_set_device picks up
+struct Cursor {
+    peer: *mut wg_peer,
+    allowedip: *mut wg_allowedip,
+}
+
+/// Fills one WG_CMD_SET_DEVICE message and advances the cursor
+///
+/// Device attributes only go into the first message. Once the message is full
+/// the current peer is either dropped or, if its allowed ips did not fit, closed
+/// early. The next message starts over with that peer and only repeats its
+/// public key and flags while the remaining allowed ips are sent.
+unsafe fn fill_set_device(message: &mut Message, device: &wg_device, cursor: &mut Cursor) {
+    message.put_strz(WGDEVICE_A_IFNAME, &device_name(&device.name));
+
+    if cursor.peer.is_null() {
+        let mut flags = 0;
+        if has_device_flag(device, wg_device_flags::WGDEVICE_HAS_PRIVATE_KEY) {
+            message.put(WGDEVICE_A_PRIVATE_KEY, &device.private_key);
+        }
+        if has_device_flag(device, wg_device_flags::WGDEVICE_HAS_LISTEN_PORT) {
+            message.put(WGDEVICE_A_LISTEN_PORT, &device.listen_port.to_ne_bytes());
+        }
+        if has_device_flag(device, wg_device_flags::WGDEVICE_HAS_FWMARK) {
+            message.put(WGDEVICE_A_FWMARK, &device.fwmark.to_ne_bytes());
+        }
+        if has_device_flag(device, wg_device_flags::WGDEVICE_REPLACE_PEERS) {
+            flags |= WGDEVICE_F_REPLACE_PEERS;
+        }
+        if flags != 0 {
+            message.put(WGDEVICE_A_FLAGS, &flags.to_ne_bytes());
+        }
+    }
+    if device.first_peer.is_null() {
+        return;
+    }
+
+    let peers_nest = message.nest_start(WGDEVICE_A_PEERS);
+    if cursor.peer.is_null() {
+        cursor.peer = device.first_peer;
+    }
+    while let Some(peer) = unsafe { cursor.peer.as_ref() } {
+        let Some(peer_nest) = message.nest_start_check(0) else {
+            break;
+        };
+        if !fill_peer(message, peer, cursor) {
+            // nothing of the peer fit, it starts the next message
+            message.nest_cancel(peer_nest);
+            break;
+        }
+        message.nest_end(peer_nest);
+        if !cursor.allowedip.is_null() {
+            // the remaining allowed ips of the peer go into the next message
+            break;
+        }
+        cursor.peer = peer.next_peer;
+    }
+    message.nest_end(peers_nest);
+}
+
+/// Writes one peer into its nest, false if the peer itself did not fit
+///
+/// On return the cursor's allowed ip is null if all allowed ips were written.
+unsafe fn fill_peer(message: &mut Message, peer: &wg_peer, cursor: &mut Cursor) -> bool {
+    let mut flags = 0;
+
+    if !message.put_check(WGPEER_A_PUBLIC_KEY, &peer.public_key) {
+        return false;
+    }
+    if has_peer_flag(peer, wg_peer_flags::WGPEER_REMOVE_ME) {
+        flags |= WGPEER_F_REMOVE_ME;
+    }
+    if cursor.allowedip.is_null() {
+        if has_peer_flag(peer, wg_peer_flags::WGPEER_REPLACE_ALLOWEDIPS) {
+            flags |= WGPEER_F_REPLACE_ALLOWEDIPS;
+        }
+        if has_peer_flag(peer, wg_peer_flags::WGPEER_HAS_PRESHARED_KEY)
+            && !message.put_check(WGPEER_A_PRESHARED_KEY, &peer.preshared_key)
+        {
+            return false;
+        }
+
+        let family = unsafe { peer.endpoint.addr.as_ref().sa_family } as c_int;
+        let endpoint = match family {
+            libc::AF_INET => Some(bytes_of(unsafe { peer.endpoint.addr4.as_ref() })),
+            libc::AF_INET6 => Some(bytes_of(unsafe { peer.endpoint.addr6.as_ref() })),
+            _ => None,
+        };
+        if let Some(endpoint) = endpoint {
+            if !message.put_check(WGPEER_A_ENDPOINT, endpoint) {
+                return false;
+            }
+        }
+        if has_peer_flag(peer, wg_peer_flags::WGPEER_HAS_PERSISTENT_KEEPALIVE_INTERVAL)
+            && !message.put_check(WGPEER_A_PERSISTENT_KEEPALIVE_INTERVAL, &peer.persistent_keepalive_interval.to_ne_bytes())
+        {
+            return false;
+        }
+    }
+    if flags != 0 && !message.put_check(WGPEER_A_FLAGS, &flags.to_ne_bytes()) {
+        return false;
+    }
+    if peer.first_allowedip.is_null() {
+        return true;
+    }
+
+    if cursor.allowedip.is_null() {
+        cursor.allowedip = peer.first_allowedip;
+    }
+    let Some(allowedips_nest) = message.nest_start_check(WGPEER_A_ALLOWEDIPS) else {
+        return true;
+    };
+    while let Some(allowedip) = unsafe { cursor.allowedip.as_ref() } {
+        let Some(allowedip_nest) = message.nest_start_check(0) else {
+            break;
+        };
+        if !fill_allowedip(message, allowedip) {
+            message.nest_cancel(allowedip_nest);
+            break;
+        }
+        message.nest_end(allowedip_nest);
+        cursor.allowedip = allowedip.next_allowedip;
+    }
+    message.nest_end(allowedips_nest);
+
+    true
+}
+
+fn fill_allowedip(message: &mut Message, allowedip: &wg_allowedip) -> bool {
+    if !message.put_check(WGALLOWEDIP_A_FAMILY, &allowedip.family.to_ne_bytes()) {
+        return false;
+    }
+
+    let address = &allowedip.__bindgen_anon_1;
+    let address = match allowedip.family as c_int {
+        libc::AF_INET => Some(bytes_of(unsafe { address.ip4.as_ref() })),
+        libc::AF_INET6 => Some(bytes_of(unsafe { address.ip6.as_ref() })),
+        _ => None,
+    };
+    if let Some(address) = address {
+        if !message.put_check(WGALLOWEDIP_A_IPADDR, address) {
+            return false;
+        }
+    }
+
+    message.put_check(WGALLOWEDIP_A_CIDR_MASK, &[allowedip.cidr])
+}
+
+pub(crate) unsafe fn wg_set_device(dev: *mut wg_device) -> c_int {
+    let device = unsafe { &*dev };
+
+    let result = GenericSocket::open(WG_GENL_NAME).and_then(|mut socket| {
+        let mut cursor = Cursor { peer: ptr::null_mut(), allowedip: ptr::null_mut() };
+        loop {
+            let mut message = socket.prepare_wireguard(WG_CMD_SET_DEVICE, libc::NLM_F_REQUEST as u16 | libc::NLM_F_ACK as u16);
+            unsafe { fill_set_device(&mut message, device, &mut cursor) };
+            socket.send_and_run(&mut message, |_, _| Ok(Flow::Continue))?;
+
+            if cursor.peer.is_null() {
+                return Ok(());
+            }
+        }
+    });
+
+    to_c(result)
+}
+
+/// Allocates a zeroed T the way the c library does, so wg_free_device can release it
+fn calloc<T>() -> Result<*mut T, Errno> {
+    let value = unsafe { libc::calloc(1, std::mem::size_of::<T>()) } as *mut T;
+    if value.is_null() {
+        return Err(libc::ENOMEM);
+    }
+
+    Ok(value)
+}
+
+fn parse_allowedip(allowedip: &mut wg_allowedip, payload: &[u8]) {
+    for (kind, value) in attributes(payload) {
+        match kind {
+            WGALLOWEDIP_A_FAMILY if value.len() == 2 => allowedip.family = read_u16(value),
+            WGALLOWEDIP_A_IPADDR if value.len() == 4 || value.len() == 16 => {
+                copy_into(&mut allowedip.__bindgen_anon_1, value)
+            }
+            WGALLOWEDIP_A_CIDR_MASK if value.len() == 1 => allowedip.cidr = value[0],
+            _ => {}
+        }
+    }
+}
+
+fn parse_allowedips(peer: &mut wg_peer, payload: &[u8]) -> Result<(), Errno> {
+    for (_, value) in attributes(payload) {
+        // link first, a half parsed entry is still released with the device
+        let new_allowedip = calloc::<wg_allowedip>()?;
+        if peer.first_allowedip.is_null() {
+            peer.first_allowedip = new_allowedip;
+        } else {
+            unsafe { (*peer.last_allowedip).next_allowedip = new_allowedip };
+        }
+        peer.last_allowedip = new_allowedip;
+
+        let allowedip = unsafe { &mut *new_allowedip };
+        parse_allowedip(allowedip, value);
+        let valid = match allowedip.family as c_int {
+            libc::AF_INET => allowedip.cidr <= 32,
+            libc::AF_INET6 => allowedip.cidr <= 128,
+            _ => false,
+        };
+        if !valid {
+            return Err(libc::EAFNOSUPPORT);
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_peer(peer: &mut wg_peer, payload: &[u8]) -> Result<(), Errno> {
+    for (kind, value) in attributes(payload) {
+        match kind {
+            WGPEER_A_PUBLIC_KEY if value.len() == 32 => {
+                copy_into(&mut peer.public_key, value);
+                peer.flags |= wg_peer_flags::WGPEER_HAS_PUBLIC_KEY;
+            }
+            WGPEER_A_PRESHARED_KEY if value.len() == 32 => {
+                copy_into(&mut peer.preshared_key, value);
+                if !unsafe { wg_key_is_zero(&mut peer.preshared_key) } {
+                    peer.flags |= wg_peer_flags::WGPEER_HAS_PRESHARED_KEY;
+                }
+            }
+            WGPEER_A_ENDPOINT if value.len() >= std::mem::size_of::<libc::sockaddr>() => {
+                let length = match read_u16(value) as c_int {
+                    libc::AF_INET => std::mem::size_of::<libc::sockaddr_in>(),
+                    libc::AF_INET6 => std::mem::size_of::<libc::sockaddr_in6>(),
+                    _ => 0,
+                };
+                if value.len() == length {
+                    copy_into(&mut peer.endpoint, value);
+                }
+            }
+            WGPEER_A_PERSISTENT_KEEPALIVE_INTERVAL if value.len() == 2 => {
+                peer.persistent_keepalive_interval = read_u16(value)
+            }
+            WGPEER_A_LAST_HANDSHAKE_TIME if value.len() == std::mem::size_of::<timespec64>() => {
+                copy_into(&mut peer.last_handshake_time, value)
+            }
+            WGPEER_A_RX_BYTES if value.len() == 8 => peer.rx_bytes = u64::from_ne_bytes(value.try_into().unwrap()),
+            WGPEER_A_TX_BYTES if value.len() == 8 => peer.tx_bytes = u64::from_ne_bytes(value.try_into().unwrap()),
+            WGPEER_A_ALLOWEDIPS => parse_allowedips(peer, value)?,
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_peers(device: &mut wg_device, payload: &[u8]) -> Result<(), Errno> {
+    for (_, value) in attributes(payload) {
+        let new_peer = calloc::<wg_peer>()?;
+        if device.first_peer.is_null() {
+            device.first_peer = new_peer;
+        } else {
+            unsafe { (*device.last_peer).next_peer = new_peer };
+        }
+        device.last_peer = new_peer;
+
+        let peer = unsafe { &mut *new_peer };
+        // Like the c library a broken allowed ip only ends the peer early, it
+        // does not fail the whole device. A missing public key does.
+        let _ = parse_peer(peer, value);
+        if !has_peer_flag(peer, wg_peer_flags::WGPEER_HAS_PUBLIC_KEY) {
+            return Err(libc::ENXIO);
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads the attributes of one WG_CMD_GET_DEVICE answer into the device
+fn parse_device(device: &mut wg_device, payload: &[u8]) -> Result<(), Errno> {
+    for (kind, value) in attributes(payload.get(GENERIC_HEADER_LENGTH..).unwrap_or_default()) {
+        match kind {
+            WGDEVICE_A_IFINDEX if value.len() == 4 => device.ifindex = read_u32(value),
+            WGDEVICE_A_IFNAME if !value.is_empty() => {
+                device.name = [0; 16];
+                for (c, byte) in device.name[..15].iter_mut().zip(attribute_str(value)) {
+                    *c = *byte as c_char;
+                }
+            }
+            WGDEVICE_A_PRIVATE_KEY if value.len() == 32 => {
+                copy_into(&mut device.private_key, value);
+                device.flags |= wg_device_flags::WGDEVICE_HAS_PRIVATE_KEY;
+            }
+            WGDEVICE_A_PUBLIC_KEY if value.len() == 32 => {
+                copy_into(&mut device.public_key, value);
+                device.flags |= wg_device_flags::WGDEVICE_HAS_PUBLIC_KEY;
+            }
+            WGDEVICE_A_LISTEN_PORT if value.len() == 2 => device.listen_port = read_u16(value),
+            WGDEVICE_A_FWMARK if value.len() == 4 => device.fwmark = read_u32(value),
+            WGDEVICE_A_PEERS => parse_peers(device, value)?,
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Merges consecutive peers with the same public key
+///
+/// The kernel splits a peer with many allowed ips over several messages of
+/// the dump, each of them repeats the public key.
+unsafe fn coalesce_peers(device: &mut wg_device) {
+    let mut peer = device.first_peer;
+    while let Some(current) = unsafe { peer.as_mut() } {
+        let Some(next) = (unsafe { current.next_peer.as_mut() }) else {
+            break;
+        };
+        if current.public_key != next.public_key {
+            peer = current.next_peer;
+            continue;
+        }
+
+        if current.first_allowedip.is_null() {
+            current.first_allowedip = next.first_allowedip;
+        } else {
+            unsafe { (*current.last_allowedip).next_allowedip = next.first_allowedip };
+        }
+        if !next.first_allowedip.is_null() {
+            current.last_allowedip = next.last_allowedip;
+        }
+        let old_next_peer = current.next_peer;
+        current.next_peer = next.next_peer;
+        unsafe { libc::free(old_next_peer.cast()) };
+    }
+}
+
+unsafe fn read_device(device: &mut wg_device, device_name: &CStr) -> Result<(), Errno> {
+    let mut socket = GenericSocket::open(WG_GENL_NAME)?;
+
+    let flags = libc::NLM_F_REQUEST | libc::NLM_F_ACK | libc::NLM_F_DUMP;
+    let mut message = socket.prepare_wireguard(WG_CMD_GET_DEVICE, flags as u16);
+    message.put_strz(WGDEVICE_A_IFNAME, device_name.to_bytes());
+    socket.send_and_run(&mut message, |_, payload| parse_device(device, payload).map(|()| Flow::Continue))?;
+
+    unsafe { coalesce_peers(device) };
+    Ok(())
+}
+
+pub(crate) unsafe fn wg_get_device(dev: *mut *mut wg_device, device_name: *const c_char) -> c_int {
+    let device_name = unsafe { CStr::from_ptr(device_name) };
+
+    loop {
+        let device = match calloc::<wg_device>() {
+            Ok(device) => device,
+            Err(errno) => return to_c(Err(errno)),
+        };
+
+        let result = unsafe { read_device(&mut *device, device_name) };
+        if result.is_ok() {
+            unsafe { *dev = device };
+            return to_c(result);
+        }
+
+        unsafe { wg_free_device(device) };
+        // the set of peers changed during the dump, read it again
+        if result != Err(libc::EINTR) {
+            unsafe { *dev = ptr::null_mut() };
+            return to_c(result);
+        }
+    }
+}
+
+pub(crate) unsafe fn wg_free_device(dev: *mut wg_device) {
+    let Some(device) = (unsafe { dev.as_mut() }) else {
+        return;
+    };
+
+    let mut peer = device.first_peer;
+    while !peer.is_null() {
+        let mut allowedip = unsafe { (*peer).first_allowedip };
+        while !allowedip.is_null() {
+            let next = unsafe { (*allowedip).next_allowedip };
+            unsafe { libc::free(allowedip.cast()) };
+            allowedip = next;
+        }
+
+        let next = unsafe { (*peer).next_peer };
+        unsafe { libc::free(peer.cast()) };
+        peer = next;
+    }
+    unsafe { libc::free(dev.cast()) };
+}
+
+/// Name and kind of a link in the RTM_GETLINK dump, None for other links
+fn wireguard_link_name(payload: &[u8]) -> Option<&[u8]> {
+    let mut name = None;
+    let mut is_wireguard = false;
+    for (kind, value) in attributes(payload.get(IFINFO_LENGTH..).unwrap_or_default()) {
+        match kind {
+            libc::IFLA_IFNAME => name = Some(attribute_str(value)),
+            libc::IFLA_LINKINFO => {
+                is_wireguard |= attributes(value).any(|(kind, value)| {
+                    kind == libc::IFLA_INFO_KIND && attribute_str(value) == WG_GENL_NAME.to_bytes()
+                })
+            }
+            _ => {}
+        }
+    }
+
+    name.filter(|_| is_wireguard)
+}
+
+fn fetch_device_names() -> Result<Vec<u8>, Errno> {
+    let mut socket = Socket::open(libc::NETLINK_ROUTE)?;
+
+    let seq = now();
+    let flags = libc::NLM_F_REQUEST | libc::NLM_F_ACK | libc::NLM_F_DUMP;
+    let mut message = Message::new(libc::RTM_GETLINK, flags as u16, seq, buffer_size());
+    message.put_extra_header(&[libc::AF_UNSPEC as u8; IFINFO_LENGTH]);
+    socket.send(message.as_bytes())?;
+
+    let mut names = Vec::new();
+    let result = socket.receive_run(seq, false, |_, payload| {
+        if let Some(name) = wireguard_link_name(payload) {
+            names.extend_from_slice(name);
+            names.push(0);
+        }
+        Ok(Flow::Continue)
+    });
+
+    // The dump gets interrupted if the set of links changes meanwhile. That is
+    // common on busy systems, the c library keeps the partial result as well.
+    match result {
+        Ok(()) | Err(libc::EINTR) => Ok(names),
+        Err(errno) => Err(errno),
+    }
+}
+
+pub(crate) unsafe fn wg_list_device_names() -> *mut c_char {
+    let mut names = match fetch_device_names() {
+        Ok(names) => names,
+        Err(errno) => {
+            set_errno(errno);
+            return ptr::null_mut();
+        }
+    };
+    names.push(0);
+
+    let buffer = unsafe { libc::malloc(names.len()) } as *mut c_char;
+    if buffer.is_null() {
+        return ptr::null_mut();
+    }
+    unsafe { ptr::copy_nonoverlapping(names.as_ptr(), buffer.cast(), names.len()) };
+
+    buffer
+}
+
+unsafe fn add_del_iface(device_name: *const c_char, add: bool) -> c_int {
+    let device_name = unsafe { CStr::from_ptr(device_name) };
+
+    let result = Socket::open(libc::NETLINK_ROUTE).and_then(|mut socket| {
+        let (kind, flags) = if add {
+            (libc::RTM_NEWLINK, libc::NLM_F_CREATE | libc::NLM_F_EXCL)
+        } else {
+            (libc::RTM_DELLINK, 0)
+        };
+        let seq = now();
+
+        let flags = libc::NLM_F_REQUEST | libc::NLM_F_ACK | flags;
+        let mut message = Message::new(kind, flags as u16, seq, buffer_size());
+        message.put_extra_header(&[libc::AF_UNSPEC as u8; IFINFO_LENGTH]);
+        message.put_strz(libc::IFLA_IFNAME, device_name.to_bytes());
+        let nest = message.nest_start(libc::IFLA_LINKINFO);
+        message.put_strz(libc::IFLA_INFO_KIND, WG_GENL_NAME.to_bytes());
+        message.nest_end(nest);
+        socket.send(message.as_bytes())?;
+
+        // a single buffer carries the acknowledgement
+        let received = socket.receive()?;
+        run(&socket.buffer[..received], seq, socket.portid, false, |_, _| Ok(Flow::Continue))?;
+        Ok(())
+    });
+
+    to_c(result)
+}
+
+pub(crate) unsafe fn wg_add_device(device_name: *const c_char) -> c_int {
+    unsafe { add_del_iface(device_name, true) }
+}
+
+pub(crate) unsafe fn wg_del_device(device_name: *const c_char) -> c_int {
+    unsafe { add_del_iface(device_name, false) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Device, DeviceConfig, PeerConfig, PresharedKey, PrivateKey, PublicKey};
+
+    fn config() -> DeviceConfig {
+        let peers = (0..3u8).map(|i| {
+            PeerConfig::new(PublicKey::from_bytes([i + 2; 32]))
+                .preshared_key(PresharedKey::from_bytes([9; 32]))
+                .endpoint("[2001:db8::1]:51820".parse().unwrap())
+                .replace_allowed_ips(true)
+                .allowed_ips((0..600u16).map(|j| format!("10.{}.{}.{}/32", i, j >> 8, j & 255).parse().unwrap()))
+        });
+
+        DeviceConfig::new().private_key(PrivateKey::from_bytes([1; 32])).listen_port(51820).peers(peers)
+    }
+
+    /// The messages wg_set_device sends for the device, without sending them
+    fn set_device_messages(device: &wg_device) -> Vec<Vec<u8>> {
+        let mut cursor = Cursor { peer: ptr::null_mut(), allowedip: ptr::null_mut() };
+        let mut messages = Vec::new();
+        loop {
+            let mut message = Message::new(libc::NLMSG_MIN_TYPE as u16, 0, 0, buffer_size());
+            message.put_extra_header(&[WG_CMD_SET_DEVICE, WG_GENL_VERSION, 0, 0]);
+            unsafe { fill_set_device(&mut message, device, &mut cursor) };
+            messages.push(message.as_bytes().to_vec());
+
+            if cursor.peer.is_null() {
+                return messages;
+            }
+        }
+    }
+
+    fn device_attributes(message: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+        attributes(&message[HEADER_LENGTH + GENERIC_HEADER_LENGTH..])
+    }
+
+    #[test]
+    fn it_splits_large_peer_sets() {
+        let config = config();
+        let mut device = config.build(&"wg0".parse().unwrap());
+        let messages = set_device_messages(unsafe { &*device.as_mut_ptr() });
+
+        assert!(messages.len() > 3);
+        assert!(messages.iter().all(|message| message.len() <= buffer_size()));
+        for (i, message) in messages.iter().enumerate() {
+            let has_private_key = device_attributes(message).any(|(kind, _)| kind == WGDEVICE_A_PRIVATE_KEY);
+            assert_eq!(has_private_key, i == 0);
+        }
+
+        // the replace flag goes out once per peer, continuations only add allowed ips
+        let replacing = messages
+            .iter()
+            .flat_map(|message| device_attributes(message).filter(|(kind, _)| *kind == WGDEVICE_A_PEERS))
+            .flat_map(|(_, peers)| attributes(peers))
+            .filter(|(_, peer)| {
+                attributes(peer).any(|(kind, value)| kind == WGPEER_A_FLAGS && read_u32(value) & WGPEER_F_REPLACE_ALLOWEDIPS != 0)
+            })
+            .count();
+        assert_eq!(replacing, 3);
+
+        // the same attributes make up a dump, reading them back gives the configuration
+        let raw = calloc::<wg_device>().unwrap();
+        for message in &messages {
+            parse_device(unsafe { &mut *raw }, &message[HEADER_LENGTH..]).unwrap();
+        }
+        unsafe { coalesce_peers(&mut *raw) };
+        let parsed = unsafe { Device::from_raw(&*raw) };
+        unsafe { wg_free_device(raw) };
+
+        assert_eq!(parsed.name(), "wg0");
+        assert_eq!(parsed.private_key(), config.private_key.as_ref());
+        assert_eq!(parsed.peers().len(), 3);
+        for (peer, expected) in parsed.peers().iter().zip(&config.peers) {
+            assert_eq!(peer.public_key(), &expected.public_key);
+            assert_eq!(peer.preshared_key(), expected.preshared_key.as_ref());
+            assert_eq!(peer.endpoint(), expected.endpoint);
+            assert_eq!(peer.allowed_ips(), expected.allowed_ips.as_slice());
+        }
+    }
+
+    fn error_message(seq: u32, flags: u16, errno: c_int) -> Vec<u8> {
+        let mut message = Message::new(libc::NLMSG_ERROR as u16, flags, seq, buffer_size());
+        message.put_extra_header(&errno.to_ne_bytes());
+        message.put_extra_header(&[0; HEADER_LENGTH]);
+        message.as_bytes().to_vec()
+    }
+
+    #[test]
+    fn it_runs_acknowledgements_and_errors() {
+        let run = |buffer: &[u8]| run(buffer, 7, 0, true, |_, _| Ok(Flow::Continue));
+
+        assert_eq!(run(&error_message(7, 0, 0)), Ok(Flow::Stop));
+        assert_eq!(run(&error_message(7, 0, -libc::EEXIST)), Err(libc::EEXIST));
+        assert_eq!(run(&error_message(8, 0, 0)), Err(libc::EPROTO));
+        assert_eq!(run(&error_message(7, libc::NLM_F_DUMP_INTR as u16, 0)), Err(libc::EINTR));
+
+        let mut done = Message::new(libc::NLMSG_DONE as u16, libc::NLM_F_MULTI as u16, 7, buffer_size());
+        done.put_extra_header(&(-libc::ENODEV).to_ne_bytes());
+        assert_eq!(run(done.as_bytes()), Err(libc::ENODEV));
+    }
+}
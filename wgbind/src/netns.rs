@@ -0,0 +1,258 @@
+//! Running operations within another network namespace
+//!
+//! A wireguard device lives in the network namespace it was created in, and the
+//! kernel answers netlink requests for the namespace of the calling thread.
+//! [`InNetns`] runs each operation on a short lived helper thread which joins the
+//! target namespace with `setns(2)` first, so the namespace of the caller and of
+//! every other thread stays as it is. Joining a namespace requires
+//! `CAP_SYS_ADMIN`.
+//!
+//! Sockets of userspace implementations live in the file system, which is not
+//! scoped by the network namespace, so by default [`InNetns`] only talks to the
+//! kernel module through [`KernelOnly`].
+//!
+//! ```no_run
+//! use wgbind::netns::{InNetns, Netns};
+//! use wgbind::Backend;
+//!
+//! // created with `ip netns add tenant`
+//! let tenant = InNetns::new(Netns::named("tenant"));
+//! tenant.add(&"wg0".parse().unwrap()).unwrap();
+//! println!("{:?}", tenant.list().unwrap());
+//! ```
+
+use std::fs::File;
+use std::io;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::backend::{Backend, KernelOnly};
+use crate::config::DeviceConfig;
+use crate::device::Device;
+use crate::error::{Error, Operation};
+use crate::interface_name::InterfaceName;
+
+/// Where `ip netns add` mounts named namespaces
+pub const NETNS_DIR: &str = "/run/netns";
+
+#[derive(Debug, Clone)]
+enum Target {
+    Path(PathBuf),
+    Fd(Arc<OwnedFd>),
+    Pid(u32),
+}
+
+/// A network namespace, opened anew for each operation
+#[derive(Debug, Clone)]
+pub struct Netns(Target);
+
+impl Netns {
+    /// A namespace created with `ip netns add`, `/run/netns/<name>`
+    pub fn named(name: &str) -> Self {
+        Self::path(PathBuf::from(NETNS_DIR).join(name))
+    }
+
+    /// A namespace file such as a bind mount or `/proc/<pid>/ns/net`
+    pub fn path(path: impl Into<PathBuf>) -> Self {
+        Self(Target::Path(path.into()))
+    }
+
+    /// A file descriptor referring to a namespace, kept open as long as the value lives
+    pub fn fd(fd: OwnedFd) -> Self {
+        Self(Target::Fd(Arc::new(fd)))
+    }
+
+    /// The namespace a process runs in
+    pub fn pid(pid: u32) -> Self {
+        Self(Target::Pid(pid))
+    }
+
+    fn open(&self) -> io::Result<OwnedFd> {
+        match &self.0 {
+            Target::Path(path) => File::open(path).map(OwnedFd::from),
+            Target::Fd(fd) => fd.try_clone(),
+            Target::Pid(pid) => File::open(format!("/proc/{}/ns/net", pid)).map(OwnedFd::from),
+        }
+    }
+
+    /// Calls `f` on a helper thread within the namespace
+    ///
+    /// Fails if the namespace cannot be opened or joined, a panic of `f` is
+    /// passed on to the caller.
+    pub fn run<T: Send>(&self, f: impl FnOnce() -> T + Send) -> io::Result<T> {
+        let target = self.open()?;
+
+        let joined = std::thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    if unsafe { libc::setns(target.as_raw_fd(), libc::CLONE_NEWNET) } != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    Ok(f())
+                })
+                .join()
+        });
+
+        joined.unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    }
+}
+
+/// A [`Backend`] whose operations all run within one network namespace
+///
+/// An operation fails with [`Error::Namespace`] if the namespace cannot be
+/// opened or joined.
+#[derive(Debug, Clone)]
+pub struct InNetns<B = KernelOnly> {
+    netns: Netns,
+    backend: B,
+}
+
+impl InNetns {
+    /// The kernel module within `netns`, userspace devices are not included
+    pub fn new(netns: Netns) -> Self {
+        Self::with_backend(netns, KernelOnly)
+    }
+}
+
+impl<B> InNetns<B> {
+    pub fn with_backend(netns: Netns, backend: B) -> Self {
+        Self { netns, backend }
+    }
+
+    pub fn netns(&self) -> &Netns {
+        &self.netns
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+}
+
+impl<B: Backend + Sync> InNetns<B> {
+    fn run<T: Send>(
+        &self,
+        operation: Operation,
+        name: Option<&InterfaceName>,
+        f: impl FnOnce(&B) -> Result<T, Error> + Send,
+    ) -> Result<T, Error> {
+        let backend = &self.backend;
+
+        self.netns.run(|| f(backend)).unwrap_or_else(|e| {
+            Err(Error::Namespace {
+                operation,
+                interface: name.map(|name| name.as_str().to_owned()),
+                errno: e.raw_os_error().unwrap_or(libc::EIO),
+            })
+        })
+    }
+}
+
+impl<B: Backend + Sync> Backend for InNetns<B> {
+    fn list(&self) -> Result<Vec<String>, Error> {
+        self.run(Operation::ListDevices, None, |backend| backend.list())
+    }
+
+    fn get(&self, name: &InterfaceName) -> Result<Device, Error> {
+        self.run(Operation::GetDevice, Some(name), |backend| backend.get(name))
+    }
+
+    fn set(&self, name: &InterfaceName, config: &DeviceConfig) -> Result<(), Error> {
+        self.run(Operation::SetDevice, Some(name), |backend| backend.set(name, config))
+    }
+
+    fn add(&self, name: &InterfaceName) -> Result<(), Error> {
+        self.run(Operation::AddDevice, Some(name), |backend| backend.add(name))
+    }
+
+    fn delete(&self, name: &InterfaceName) -> Result<(), Error> {
+        self.run(Operation::DeleteDevice, Some(name), |backend| backend.delete(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::MetadataExt;
+
+    use super::*;
+    use crate::mock::Mock;
+
+    /// Device and inode of the namespace of the calling thread
+    fn current() -> (u64, u64) {
+        let metadata = std::fs::metadata("/proc/thread-self/ns/net").unwrap();
+        (metadata.dev(), metadata.ino())
+    }
+
+    /// A fresh namespace like `unshare -n` creates, kept alive by the returned fd
+    fn unshared() -> OwnedFd {
+        std::thread::spawn(|| {
+            assert_eq!(unsafe { libc::unshare(libc::CLONE_NEWNET) }, 0, "{}", io::Error::last_os_error());
+            File::open("/proc/thread-self/ns/net").map(OwnedFd::from).unwrap()
+        })
+        .join()
+        .unwrap()
+    }
+
+    #[test]
+    fn it_runs_within_the_namespace_only() {
+        let before = current();
+        let fd = unshared();
+        let target = std::fs::metadata(format!("/proc/self/fd/{}", fd.as_raw_fd())).unwrap();
+        let netns = Netns::fd(fd);
+
+        assert_eq!(netns.run(current).unwrap(), (target.dev(), target.ino()));
+        assert_ne!(netns.run(current).unwrap(), before);
+        assert_eq!(current(), before);
+
+        // a new namespace has no wireguard devices
+        assert_eq!(InNetns::new(netns).list().unwrap(), Vec::<String>::new());
+
+        assert_eq!(Netns::pid(std::process::id()).run(current).unwrap(), before);
+        let missing = Netns::named("wgbind-missing").run(current);
+        assert_eq!(missing.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn it_leaves_out_userspace_sockets() {
+        let dir = std::env::temp_dir().join(format!("wgbind-netns-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let name: InterfaceName = "wg0".parse().unwrap();
+        let uapi = crate::Uapi::with_socket_dir(&dir);
+        let _socket = std::os::unix::net::UnixListener::bind(uapi.socket_path(&name)).unwrap();
+
+        // the socket directory is shared with every namespace
+        let tenant = InNetns::new(Netns::fd(unshared()));
+        let seen = tenant.netns().run(|| uapi.has_device(&name)).unwrap();
+        let listed = tenant.list();
+        let got = tenant.get(&name);
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert!(seen);
+        assert_eq!(listed.unwrap(), Vec::<String>::new());
+        // the kernel answers, the socket would have timed out
+        let kernel = matches!(got, Err(Error::NoSuchDevice { .. } | Error::ModuleUnavailable { .. }));
+        assert!(kernel, "{:?}", got);
+    }
+
+    #[test]
+    fn it_passes_operations_to_the_backend() {
+        let tenant = InNetns::with_backend(Netns::fd(unshared()), Mock::new());
+        let name: InterfaceName = "wg0".parse().unwrap();
+
+        tenant.add(&name).unwrap();
+        tenant.set(&name, &DeviceConfig::new().listen_port(51820)).unwrap();
+        assert_eq!(tenant.get(&name).unwrap().listen_port(), 51820);
+        assert_eq!(tenant.list().unwrap(), vec!["wg0"]);
+        tenant.delete(&name).unwrap();
+        assert_eq!(tenant.backend().calls(), 5);
+
+        let missing = InNetns::new(Netns::path("/nonexistent/netns"));
+        let result = missing.get(&name);
+        assert!(matches!(result, Err(Error::Namespace { .. })), "{:?}", result);
+        assert_eq!(result.as_ref().unwrap_err().errno(), Some(libc::ENOENT));
+        let error = io::Error::from(result.unwrap_err());
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert!(error.to_string().starts_with("get device \"wg0\": cannot enter network namespace"), "{}", error);
+    }
+}
@@ -0,0 +1,540 @@
+//! The `.nmconnection` keyfiles of NetworkManager
+//!
+//! A WireGuard connection keeps the interface in a `[wireguard]` group and
+//! every peer in a `[wireguard-peer.<public key>]` group, the addresses of the
+//! interface live in `[ipv4]` and `[ipv6]`:
+//!
+//! ```text
+//! [connection]
+//! id=office
+//! type=wireguard
+//! interface-name=wg0
+//!
+//! [wireguard]
+//! private-key=yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=
+//!
+//! [wireguard-peer.xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=]
+//! endpoint=192.0.2.1:51820
+//! allowed-ips=10.0.0.0/24;fd00::/64;
+//!
+//! [ipv4]
+//! method=manual
+//! address1=10.0.0.2/24
+//! ```
+//!
+//! [`NmConnection`] maps these to a [`DeviceConfig`]. Keys and group names are
+//! case sensitive. NetworkManager writes many keys which have no counterpart
+//! here, such as timestamps or `[proxy]`, these are skipped while parsing and
+//! not written back. Invalid values of known keys are errors as usual.
+//! Endpoints given by hostname are kept as written in
+//! [`NmConnection::endpoint_hosts`], NetworkManager resolves them itself.
+//!
+//! NetworkManager only loads keyfiles owned by root with mode 0600, written to
+//! `/etc/NetworkManager/system-connections/` or imported with
+//! `nmcli connection load`.
+//!
+//! # Example
+//!
+//! ```
+//! use wgbind::network_manager::NmConnection;
+//! use wgbind::{DeviceConfig, PeerConfig, PrivateKey};
+//!
+//! let device = DeviceConfig::new()
+//!     .private_key(PrivateKey::generate())
+//!     .peer(PeerConfig::new("xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=".parse().unwrap()));
+//! let mut connection = NmConnection::new("wg0".parse().unwrap(), device);
+//! connection.address.push("10.0.0.2/24".parse().unwrap());
+//!
+//! let keyfile = connection.to_string();
+//! let parsed: NmConnection = keyfile.parse().unwrap();
+//! assert_eq!(parsed.address, connection.address);
+//! assert_eq!(parsed.device.peers[0].public_key, connection.device.peers[0].public_key);
+//! ```
+
+use std::fmt;
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::allowed_ip::AllowedIp;
+use crate::conf::{self, EndpointValue, Entry, HostEndpoint, ParseError, ParseErrorKind, Token};
+use crate::config::{DeviceConfig, PeerConfig};
+use crate::interface_name::InterfaceName;
+use crate::quick::{Table, WgQuickConfig};
+
+/// A WireGuard connection of NetworkManager
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NmConnection {
+    /// name of the connection as shown to the user
+    pub id: String,
+    /// NetworkManager derives one from the file name if it is missing
+    pub uuid: Option<String>,
+    pub interface_name: InterfaceName,
+    pub autoconnect: Option<bool>,
+    /// everything NetworkManager hands to the kernel, replacing all peers
+    pub device: DeviceConfig,
+    pub mtu: Option<u32>,
+    /// whether routes for the allowed ips of the peers are added, true if not set
+    pub peer_routes: Option<bool>,
+    /// `address1`, `address2`... of `[ipv4]` and `[ipv6]`, host bits are kept
+    pub address: Vec<AllowedIp>,
+    pub dns: Vec<IpAddr>,
+    pub dns_search: Vec<String>,
+    /// endpoints given by hostname, the peers in `device` have none until resolved
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub endpoint_hosts: Vec<HostEndpoint>,
+}
+
+impl NmConnection {
+    /// A connection named after the interface
+    pub fn new(interface_name: InterfaceName, device: DeviceConfig) -> Self {
+        Self {
+            id: interface_name.to_string(),
+            uuid: None,
+            interface_name,
+            autoconnect: None,
+            device,
+            mtu: None,
+            peer_routes: None,
+            address: Vec::new(),
+            dns: Vec::new(),
+            dns_search: Vec::new(),
+            endpoint_hosts: Vec::new(),
+        }
+    }
+
+    /// A copy without private and preshared keys, e.g. for logging
+    pub fn redacted(&self) -> Self {
+        Self {
+            device: self.device.redacted(),
+            ..self.clone()
+        }
+    }
+
+    /// Converts a wg-quick configuration, dropping its hooks and `SaveConfig`
+    ///
+    /// NetworkManager has no routing tables of its own, only `Table = off`
+    /// carries over as `peer-routes=false`.
+    pub fn from_wg_quick(interface_name: InterfaceName, config: &WgQuickConfig) -> Self {
+        Self {
+            mtu: config.mtu,
+            peer_routes: (config.table == Some(Table::Off)).then_some(false),
+            address: config.address.clone(),
+            dns: config.dns.clone(),
+            dns_search: config.dns_search.clone(),
+            endpoint_hosts: config.endpoint_hosts.clone(),
+            ..Self::new(interface_name, config.device.clone())
+        }
+    }
+
+    /// Converts to a wg-quick configuration
+    pub fn to_wg_quick(&self) -> WgQuickConfig {
+        WgQuickConfig {
+            address: self.address.clone(),
+            dns: self.dns.clone(),
+            dns_search: self.dns_search.clone(),
+            mtu: self.mtu,
+            table: (self.peer_routes == Some(false)).then_some(Table::Off),
+            endpoint_hosts: self.endpoint_hosts.clone(),
+            ..WgQuickConfig::new(self.device.clone())
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Group {
+    Connection,
+    WireGuard,
+    Peer,
+    Ip,
+    /// groups without any key of interest
+    Other,
+}
+
+fn boolean(entry: &Entry<'_>, key: &'static str) -> Result<bool, ParseError> {
+    match entry.value {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(entry.invalid(key, "expected true or false")),
+    }
+}
+
+/// Parses the `;` separated lists of keyfiles, a trailing `;` is optional
+fn items<T: FromStr>(entry: &Entry<'_>, key: &'static str) -> Result<Vec<T>, ParseError>
+where
+    T::Err: fmt::Display,
+{
+    entry
+        .split(';')
+        .map(|(column, item)| item.parse().map_err(|error| entry.invalid_at(column, key, error)))
+        .collect()
+}
+
+fn apply_connection(connection: &mut NmConnection, entry: &Entry<'_>) -> Result<(), ParseError> {
+    match entry.key {
+        "id" => connection.id = entry.value.to_owned(),
+        "uuid" => connection.uuid = Some(entry.value.to_owned()),
+        "type" if entry.value != "wireguard" => return Err(entry.invalid("type", "only wireguard is supported")),
+        "interface-name" => connection.interface_name = conf::value(entry, "interface-name")?,
+        "autoconnect" => connection.autoconnect = Some(boolean(entry, "autoconnect")?),
+        _ => {}
+    }
+
+    Ok(())
+}
+
+fn apply_wireguard(connection: &mut NmConnection, entry: &Entry<'_>) -> Result<(), ParseError> {
+    let device = &mut connection.device;
+    match entry.key {
+        "private-key" => device.private_key = Some(conf::key(entry, "private-key")?),
+        "listen-port" => device.listen_port = Some(conf::value(entry, "listen-port")?),
+        "fwmark" => device.fwmark = Some(conf::value(entry, "fwmark")?),
+        "mtu" => connection.mtu = Some(conf::value(entry, "mtu")?),
+        "peer-routes" => connection.peer_routes = Some(boolean(entry, "peer-routes")?),
+        _ => {}
+    }
+
+    Ok(())
+}
+
+fn apply_peer(peer: &mut PeerConfig, hosts: &mut Vec<HostEndpoint>, entry: &Entry<'_>) -> Result<(), ParseError> {
+    match entry.key {
+        "preshared-key" => peer.preshared_key = Some(conf::key(entry, "preshared-key")?),
+        "endpoint" => match conf::endpoint_value(entry)? {
+            EndpointValue::Addr(endpoint) => peer.endpoint = Some(endpoint),
+            EndpointValue::Host(host, port) => hosts.push(HostEndpoint {
+                public_key: peer.public_key,
+                host,
+                port,
+            }),
+        },
+        "persistent-keepalive" => {
+            peer.persistent_keepalive_interval = Some(conf::value(entry, "persistent-keepalive")?)
+        }
+        "allowed-ips" => peer.allowed_ips.extend(items::<AllowedIp>(entry, "allowed-ips")?),
+        _ => {}
+    }
+
+    Ok(())
+}
+
+fn apply_ip(connection: &mut NmConnection, entry: &Entry<'_>) -> Result<(), ParseError> {
+    match entry.key {
+        // `address1=10.0.0.2/24,10.0.0.1` carries an optional gateway
+        key if key.starts_with("address") && key[7..].bytes().all(|b| b.is_ascii_digit()) => {
+            let address = entry.value.split(',').next().unwrap_or_default();
+            let address = address.parse().map_err(|error| entry.invalid("address", error))?;
+            connection.address.push(address);
+        }
+        "dns" => connection.dns.extend(items::<IpAddr>(entry, "dns")?),
+        "dns-search" => connection.dns_search.extend(entry.split(';').map(|(_, domain)| domain.to_owned())),
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Parses a WireGuard keyfile
+///
+/// The configuration replaces all peers of the device and the allowed ips of
+/// every peer, like NetworkManager does when it activates the connection.
+pub fn parse(input: &str) -> Result<NmConnection, ParseError> {
+    // the name is only known once [connection] has been read
+    let mut connection = NmConnection::new(InterfaceName::try_from("wg0").unwrap(), DeviceConfig::new());
+    let mut header = None;
+    let mut id = false;
+    let mut interface_name = false;
+    let mut group = None;
+
+    for token in conf::tokenize_keyfile(input) {
+        match token? {
+            Token::Section { line, column, name } => {
+                group = Some(match name {
+                    "connection" => {
+                        header = Some((line, column));
+                        Group::Connection
+                    }
+                    "wireguard" => Group::WireGuard,
+                    "ipv4" | "ipv6" => Group::Ip,
+                    _ => match name.strip_prefix("wireguard-peer.") {
+                        Some(key) => {
+                            let public_key = key.parse().map_err(|_| {
+                                let reason = "not a base64 encoded 32 byte key";
+                                let kind = ParseErrorKind::InvalidValue {
+                                    key: "wireguard-peer",
+                                    reason: reason.to_owned(),
+                                };
+                                ParseError::new(line, column + "[wireguard-peer.".len(), kind)
+                            })?;
+                            connection.device.peers.push(PeerConfig::new(public_key));
+                            Group::Peer
+                        }
+                        None => Group::Other,
+                    },
+                });
+            }
+            Token::Entry(entry) => match group {
+                None => return Err(ParseError::new(entry.line, entry.column, ParseErrorKind::OutsideSection)),
+                Some(Group::Connection) => {
+                    id |= entry.key == "id";
+                    interface_name |= entry.key == "interface-name";
+                    apply_connection(&mut connection, &entry)?
+                }
+                Some(Group::WireGuard) => apply_wireguard(&mut connection, &entry)?,
+                Some(Group::Peer) => match connection.device.peers.last_mut() {
+                    Some(peer) => apply_peer(peer, &mut connection.endpoint_hosts, &entry)?,
+                    None => unreachable!("a peer group always adds a peer"),
+                },
+                Some(Group::Ip) => apply_ip(&mut connection, &entry)?,
+                Some(Group::Other) => {}
+            },
+        }
+    }
+
+    let (line, column) = header.unwrap_or((1, 1));
+    if !id {
+        return Err(ParseError::new(line, column, ParseErrorKind::MissingKey("id")));
+    }
+    if !interface_name {
+        return Err(ParseError::new(line, column, ParseErrorKind::MissingKey("interface-name")));
+    }
+    connection.device = conf::replace_all(connection.device);
+
+    Ok(connection)
+}
+
+impl FromStr for NmConnection {
+    type Err = ParseError;
+
+    /// Same as [`parse`]
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse(s)
+    }
+}
+
+/// Writes the `[ipv4]` or `[ipv6]` group, disabled if it has neither addresses nor name servers
+///
+/// Search domains are written either way, so they survive a round trip.
+fn write_ip(output: &mut String, group: &str, address: &[&AllowedIp], dns: &[&IpAddr], dns_search: &[String]) {
+    // writing to a String cannot fail
+    let _ = writeln!(output, "\n[{}]", group);
+    match address.is_empty() && dns.is_empty() {
+        true => output.push_str("method=disabled\n"),
+        false => output.push_str("method=manual\n"),
+    }
+    for (index, address) in address.iter().enumerate() {
+        let _ = writeln!(output, "address{}={}", index + 1, address);
+    }
+    if !dns.is_empty() {
+        let dns = dns.iter().map(|server| format!("{};", server)).collect::<String>();
+        let _ = writeln!(output, "dns={}", dns);
+    }
+    if !dns_search.is_empty() {
+        let _ = writeln!(output, "dns-search={};", dns_search.join(";"));
+    }
+}
+
+impl fmt::Display for NmConnection {
+    /// Writes the keyfile, secrets are stored in the file itself
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut output = String::from("[connection]\n");
+
+        // writing to a String cannot fail
+        let _ = writeln!(output, "id={}", self.id);
+        if let Some(uuid) = &self.uuid {
+            let _ = writeln!(output, "uuid={}", uuid);
+        }
+        let _ = writeln!(output, "type=wireguard\ninterface-name={}", self.interface_name);
+        if let Some(autoconnect) = self.autoconnect {
+            let _ = writeln!(output, "autoconnect={}", autoconnect);
+        }
+
+        output.push_str("\n[wireguard]\n");
+        if let Some(key) = &self.device.private_key {
+            let _ = writeln!(output, "private-key={}", key);
+        }
+        if let Some(port) = self.device.listen_port {
+            let _ = writeln!(output, "listen-port={}", port);
+        }
+        if let Some(fwmark) = self.device.fwmark {
+            let _ = writeln!(output, "fwmark={}", fwmark);
+        }
+        if let Some(mtu) = self.mtu {
+            let _ = writeln!(output, "mtu={}", mtu);
+        }
+        if let Some(peer_routes) = self.peer_routes {
+            let _ = writeln!(output, "peer-routes={}", peer_routes);
+        }
+
+        for peer in self.device.peers.iter().filter(|peer| !peer.remove) {
+            let _ = writeln!(output, "\n[wireguard-peer.{}]", peer.public_key);
+            let host = self.endpoint_hosts.iter().find(|host| host.public_key == peer.public_key);
+            match (peer.endpoint, host) {
+                (Some(endpoint), _) => {
+                    let _ = writeln!(output, "endpoint={}", endpoint);
+                }
+                (None, Some(host)) => {
+                    let _ = writeln!(output, "endpoint={}", host);
+                }
+                (None, None) => {}
+            }
+            if let Some(key) = &peer.preshared_key {
+                let _ = writeln!(output, "preshared-key={}\npreshared-key-flags=0", key);
+            }
+            if let Some(interval) = peer.persistent_keepalive_interval {
+                let _ = writeln!(output, "persistent-keepalive={}", interval);
+            }
+            if !peer.allowed_ips.is_empty() {
+                let allowed_ips = peer.allowed_ips.iter().map(|ip| format!("{};", ip)).collect::<String>();
+                let _ = writeln!(output, "allowed-ips={}", allowed_ips);
+            }
+        }
+
+        let (v4, v6): (Vec<_>, Vec<_>) = self.address.iter().partition(|address| address.addr().is_ipv4());
+        let (dns4, dns6): (Vec<_>, Vec<_>) = self.dns.iter().partition(|server| server.is_ipv4());
+        // search domains go along with the first family in use
+        let search_v4 = !v4.is_empty() || !dns4.is_empty() || (v6.is_empty() && dns6.is_empty());
+        let (search4, search6) = match search_v4 {
+            true => (&self.dns_search[..], &[][..]),
+            false => (&[][..], &self.dns_search[..]),
+        };
+        write_ip(&mut output, "ipv4", &v4, &dns4, search4);
+        write_ip(&mut output, "ipv6", &v6, &dns6, search6);
+
+        f.write_str(&output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEYFILE: &str = "\
+[connection]
+id=office
+uuid=3f2c1d9e-6a8b-4c1f-9d1e-2b7a5c4e8f10
+type=wireguard
+interface-name=wg-office
+autoconnect=false
+
+[wireguard]
+private-key=yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=
+listen-port=51820
+fwmark=4660
+mtu=1420
+peer-routes=true
+
+[wireguard-peer.xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=]
+endpoint=192.0.2.1:51820
+preshared-key=FpCyhws9cxwWoV4xELtfJvjJN+zQVRPISllRWgeopVE=
+preshared-key-flags=0
+persistent-keepalive=25
+allowed-ips=10.0.0.0/24;fd00::/64;
+
+[ipv4]
+method=manual
+address1=10.0.0.2/24
+dns=10.0.0.53;
+dns-search=corp.example;
+
+[ipv6]
+method=manual
+address1=fd00::2/64
+";
+
+    #[test]
+    fn it_parses_and_writes_keyfiles() {
+        let connection = parse(KEYFILE).unwrap();
+
+        assert_eq!(connection.id, "office");
+        assert_eq!(connection.interface_name.as_str(), "wg-office");
+        assert_eq!(connection.autoconnect, Some(false));
+        assert_eq!(connection.mtu, Some(1420));
+        assert_eq!(connection.device.fwmark, Some(0x1234));
+        assert!(connection.device.replace_peers);
+
+        let peer = &connection.device.peers[0];
+        assert!(peer.replace_allowed_ips);
+        assert_eq!(peer.allowed_ips.len(), 2);
+        assert_eq!(peer.persistent_keepalive_interval, Some(25));
+
+        assert_eq!(connection.address, ["10.0.0.2/24", "fd00::2/64"].map(|ip| ip.parse().unwrap()));
+        assert_eq!(connection.dns, ["10.0.0.53".parse::<IpAddr>().unwrap()]);
+        assert_eq!(connection.dns_search, ["corp.example"]);
+
+        assert_eq!(connection.to_string(), KEYFILE);
+
+        // search domains without addresses or name servers
+        let mut connection = NmConnection::new("wg0".parse().unwrap(), DeviceConfig::new());
+        connection.dns_search.push("corp.example".into());
+        let keyfile = connection.to_string();
+        assert!(keyfile.ends_with("[ipv4]\nmethod=disabled\ndns-search=corp.example;\n\n[ipv6]\nmethod=disabled\n"));
+        assert_eq!(parse(&keyfile).unwrap().dns_search, connection.dns_search);
+    }
+
+    #[test]
+    fn it_keeps_endpoint_hostnames() {
+        let keyfile = KEYFILE.replace("endpoint=192.0.2.1:51820", "endpoint=vpn.example.com:51820");
+        let connection = parse(&keyfile).unwrap();
+
+        assert_eq!(connection.device.peers[0].endpoint, None);
+        assert_eq!(connection.endpoint_hosts[0].to_string(), "vpn.example.com:51820");
+        assert_eq!(connection.endpoint_hosts[0].public_key, connection.device.peers[0].public_key);
+        assert_eq!(connection.to_string(), keyfile);
+
+        let quick = connection.to_wg_quick();
+        assert_eq!(quick.endpoint_hosts, connection.endpoint_hosts);
+        assert!(quick.to_string().contains("Endpoint = vpn.example.com:51820\n"));
+        let back = NmConnection::from_wg_quick(connection.interface_name.clone(), &quick);
+        assert_eq!(back.endpoint_hosts, connection.endpoint_hosts);
+    }
+
+    #[test]
+    fn it_skips_unrelated_keys() {
+        let input = "\
+# written by hand
+[connection]
+id=office #2
+type=wireguard
+interface-name=wg0
+; no autoconnect
+timestamp=1700000000
+
+[wireguard]
+ip4-auto-default-route=0
+
+[ipv4]
+method=manual
+address1=10.0.0.2/24,10.0.0.1
+never-default=true
+
+[proxy]
+";
+        let connection = parse(input).unwrap();
+        assert_eq!(connection.id, "office #2");
+        assert_eq!(connection.address, ["10.0.0.2/24".parse().unwrap()]);
+
+        let quick = connection.to_wg_quick();
+        assert_eq!(quick.table, None);
+        let converted = NmConnection::from_wg_quick("wg0".parse().unwrap(), &quick);
+        assert_eq!(NmConnection { id: connection.id.clone(), ..converted }, connection);
+    }
+
+    #[test]
+    fn it_reports_keyfile_errors() {
+        let error = parse("[connection]\nid=wg0\ntype=vpn\n").unwrap_err();
+        assert_eq!((error.line, error.column), (3, 6));
+
+        let error = parse("[connection]\nid=wg0\ntype=wireguard\n").unwrap_err();
+        assert_eq!(error.kind, ParseErrorKind::MissingKey("interface-name"));
+
+        let error = parse("[connection]\nid=wg0\ninterface-name=wg0\n[wireguard-peer.nokey]\n").unwrap_err();
+        assert_eq!((error.line, error.column), (4, 17));
+
+        let input = "[connection]\nid=wg0\ninterface-name=wg0\n\
+                     [wireguard-peer.xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=]\nallowed-ips=10.0.0.0/24;10.0.0.0/33;\n";
+        let error = parse(input).unwrap_err();
+        assert_eq!((error.line, error.column), (5, 25));
+    }
+}
@@ -0,0 +1,644 @@
+//! The `.netdev` and `.network` files of systemd-networkd
+//!
+//! networkd configures WireGuard through the `[WireGuard]` and
+//! `[WireGuardPeer]` sections of a `.netdev` file, while the addresses of the
+//! interface live in the `[Network]` section of a matching `.network` file.
+//! [`NetworkdConfig`] keeps both next to the [`DeviceConfig`], so the same
+//! configuration can be written for networkd hosts and, through
+//! [`NetworkdConfig::to_wg_quick`] and [`NetworkdConfig::from_wg_quick`], for
+//! wg-quick hosts.
+//!
+//! Unlike the files of wg(8), keys and section names are case sensitive and a
+//! line starting with `;` is a comment too. Endpoint hostnames are kept as
+//! written in [`NetworkdConfig::endpoint_hosts`], networkd itself resolves them
+//! when it brings the interface up.
+//!
+//! Only the keys relevant to WireGuard are understood: `Name`, `Kind`,
+//! `Description` and `MTUBytes` of `[NetDev]`, `Name` of `[Match]` and
+//! `Address`, `DNS` and `Domains` of `[Network]`. Other keys and sections,
+//! such as `[Route]` or `IPForward=`, are skipped, while an invalid value of a
+//! known key is still an error.
+//!
+//! # Example
+//!
+//! ```
+//! use wgbind::networkd;
+//!
+//! let netdev = "\
+//! [NetDev]
+//! Name=wg0
+//! Kind=wireguard
+//!
+//! [WireGuard]
+//! PrivateKeyFile=/etc/systemd/network/wg0.key
+//! ListenPort=51820
+//! ";
+//!
+//! let mut config = networkd::parse_netdev(netdev).unwrap();
+//! config.parse_network("[Match]\nName=wg0\n\n[Network]\nAddress=10.0.0.1/24\n").unwrap();
+//!
+//! assert_eq!(config.device.listen_port, Some(51820));
+//! assert_eq!(config.to_wg_quick().address, ["10.0.0.1/24".parse().unwrap()]);
+//! ```
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use crate::allowed_ip::AllowedIp;
+use crate::conf::{self, Entry, HostEndpoint, ParseError, ParseErrorKind, PendingPeer, Token};
+use crate::config::DeviceConfig;
+use crate::interface_name::InterfaceName;
+use crate::key::PublicKey;
+use crate::quick::{Table, WgQuickConfig};
+
+/// Keys of a `[WireGuardPeer]` section which have no place in [`PeerConfig`](crate::PeerConfig)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(default))]
+pub struct PeerOptions {
+    /// read by networkd instead of an inline `PresharedKey`
+    pub preshared_key_file: Option<PathBuf>,
+    /// overrides the route table of the interface for the allowed ips of this peer
+    pub route_table: Option<Table>,
+    pub route_metric: Option<u32>,
+}
+
+impl PeerOptions {
+    fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// A WireGuard interface as configured by systemd-networkd
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NetworkdConfig {
+    pub name: InterfaceName,
+    pub description: Option<String>,
+    pub mtu: Option<u32>,
+    /// everything networkd hands to the kernel, replacing all peers
+    pub device: DeviceConfig,
+    /// read by networkd instead of an inline `PrivateKey`
+    pub private_key_file: Option<PathBuf>,
+    /// table the routes of the allowed ips are added to, networkd adds none without it
+    pub route_table: Option<Table>,
+    pub route_metric: Option<u32>,
+    /// options of peers by public key, peers without any are left out
+    pub peer_options: HashMap<PublicKey, PeerOptions>,
+    /// `Address` of the `.network` file, host bits are kept e.g. `10.0.0.1/24`
+    pub address: Vec<AllowedIp>,
+    pub dns: Vec<IpAddr>,
+    /// `Domains` of the `.network` file
+    pub domains: Vec<String>,
+    /// endpoints given by hostname, the peers in `device` have none until resolved
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub endpoint_hosts: Vec<HostEndpoint>,
+}
+
+impl NetworkdConfig {
+    pub fn new(name: InterfaceName, device: DeviceConfig) -> Self {
+        Self {
+            name,
+            description: None,
+            mtu: None,
+            device,
+            private_key_file: None,
+            route_table: None,
+            route_metric: None,
+            peer_options: HashMap::new(),
+            address: Vec::new(),
+            dns: Vec::new(),
+            domains: Vec::new(),
+            endpoint_hosts: Vec::new(),
+        }
+    }
+
+    /// A copy without private and preshared keys, e.g. for logging
+    ///
+    /// Paths of key files are kept, they are no secret.
+    pub fn redacted(&self) -> Self {
+        Self {
+            device: self.device.redacted(),
+            ..self.clone()
+        }
+    }
+
+    /// Adds the `[Match]` and `[Network]` sections of a `.network` file
+    ///
+    /// `Name` of `[Match]` has to list the name of the interface.
+    pub fn parse_network(&mut self, input: &str) -> Result<(), ParseError> {
+        let input = strip_comments(input);
+        let mut section = None;
+
+        for token in conf::tokenize(&input) {
+            match token? {
+                Token::Section { name, .. } => {
+                    section = Some(match name {
+                        "Match" => NetworkSection::Match,
+                        "Network" => NetworkSection::Network,
+                        _ => NetworkSection::Other,
+                    })
+                }
+                Token::Entry(entry) => match (section, entry.key) {
+                    (None, _) => {
+                        return Err(ParseError::new(entry.line, entry.column, ParseErrorKind::OutsideSection))
+                    }
+                    (Some(NetworkSection::Match), "Name")
+                        if !entry.split(' ').any(|(_, name)| name == self.name.as_str()) =>
+                    {
+                        return Err(entry.invalid("Name", format!("does not match {}", self.name)));
+                    }
+                    (Some(NetworkSection::Network), "Address") => self.address.push(conf::value(&entry, "Address")?),
+                    (Some(NetworkSection::Network), "DNS") => {
+                        for (column, item) in entry.split(' ') {
+                            let server = item.parse().map_err(|error| entry.invalid_at(column, "DNS", error))?;
+                            self.dns.push(server);
+                        }
+                    }
+                    (Some(NetworkSection::Network), "Domains") => {
+                        self.domains.extend(entry.split(' ').map(|(_, domain)| domain.to_owned()))
+                    }
+                    _ => {}
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes the `.netdev` file
+    ///
+    /// networkd refuses inline keys in files readable by others, keep the
+    /// file at mode 0640 owned by `root:systemd-network` or use key files.
+    pub fn netdev(&self) -> String {
+        let mut output = String::from("[NetDev]\n");
+
+        // writing to a String cannot fail
+        let _ = writeln!(output, "Name={}\nKind=wireguard", self.name);
+        if let Some(description) = &self.description {
+            let _ = writeln!(output, "Description={}", description);
+        }
+        if let Some(mtu) = self.mtu {
+            let _ = writeln!(output, "MTUBytes={}", mtu);
+        }
+
+        output.push_str("\n[WireGuard]\n");
+        if let Some(key) = &self.device.private_key {
+            let _ = writeln!(output, "PrivateKey={}", key);
+        }
+        if let Some(path) = &self.private_key_file {
+            let _ = writeln!(output, "PrivateKeyFile={}", path.display());
+        }
+        match self.device.listen_port {
+            Some(0) => output.push_str("ListenPort=auto\n"),
+            Some(port) => {
+                let _ = writeln!(output, "ListenPort={}", port);
+            }
+            None => {}
+        }
+        if let Some(fwmark) = self.device.fwmark {
+            let _ = writeln!(output, "FirewallMark={}", fwmark);
+        }
+        write_routing(&mut output, self.route_table.as_ref(), self.route_metric);
+
+        for peer in self.device.peers.iter().filter(|peer| !peer.remove) {
+            let _ = writeln!(output, "\n[WireGuardPeer]\nPublicKey={}", peer.public_key);
+            let options = self.peer_options.get(&peer.public_key);
+
+            if let Some(key) = &peer.preshared_key {
+                let _ = writeln!(output, "PresharedKey={}", key);
+            }
+            if let Some(path) = options.and_then(|options| options.preshared_key_file.as_ref()) {
+                let _ = writeln!(output, "PresharedKeyFile={}", path.display());
+            }
+            if !peer.allowed_ips.is_empty() {
+                let allowed_ips = peer.allowed_ips.iter().map(|ip| ip.to_string()).collect::<Vec<_>>();
+                let _ = writeln!(output, "AllowedIPs={}", allowed_ips.join(","));
+            }
+            let host = self.endpoint_hosts.iter().find(|host| host.public_key == peer.public_key);
+            match (peer.endpoint, host) {
+                (Some(endpoint), _) => {
+                    let _ = writeln!(output, "Endpoint={}", endpoint);
+                }
+                (None, Some(host)) => {
+                    let _ = writeln!(output, "Endpoint={}", host);
+                }
+                (None, None) => {}
+            }
+            match peer.persistent_keepalive_interval {
+                Some(0) => output.push_str("PersistentKeepalive=off\n"),
+                Some(interval) => {
+                    let _ = writeln!(output, "PersistentKeepalive={}", interval);
+                }
+                None => {}
+            }
+            if let Some(options) = options {
+                write_routing(&mut output, options.route_table.as_ref(), options.route_metric);
+            }
+        }
+
+        output
+    }
+
+    /// Writes the `.network` file matching the interface by name
+    pub fn network(&self) -> String {
+        let mut output = String::new();
+
+        // writing to a String cannot fail
+        let _ = writeln!(output, "[Match]\nName={}\n\n[Network]", self.name);
+        for address in &self.address {
+            let _ = writeln!(output, "Address={}", address);
+        }
+        if !self.dns.is_empty() {
+            let dns = self.dns.iter().map(|server| server.to_string()).collect::<Vec<_>>();
+            let _ = writeln!(output, "DNS={}", dns.join(" "));
+        }
+        if !self.domains.is_empty() {
+            let _ = writeln!(output, "Domains={}", self.domains.join(" "));
+        }
+
+        output
+    }
+
+    /// Converts a wg-quick configuration, dropping its hooks and `SaveConfig`
+    ///
+    /// wg-quick adds routes for the allowed ips by default while networkd does
+    /// not, hence a missing or `auto` table becomes `RouteTable=main`.
+    pub fn from_wg_quick(name: InterfaceName, config: &WgQuickConfig) -> Self {
+        let route_table = match &config.table {
+            None | Some(Table::Auto) => Some(Table::Name("main".to_owned())),
+            Some(Table::Off) => None,
+            Some(table) => Some(table.clone()),
+        };
+
+        Self {
+            mtu: config.mtu,
+            route_table,
+            address: config.address.clone(),
+            dns: config.dns.clone(),
+            domains: config.dns_search.clone(),
+            endpoint_hosts: config.endpoint_hosts.clone(),
+            ..Self::new(name, config.device.clone())
+        }
+    }
+
+    /// Converts to a wg-quick configuration
+    ///
+    /// Key files are loaded by `PostUp` commands calling wg(8), route metrics
+    /// and the route tables of single peers have no counterpart in wg-quick.
+    pub fn to_wg_quick(&self) -> WgQuickConfig {
+        let table = match &self.route_table {
+            None | Some(Table::Off) => Some(Table::Off),
+            Some(Table::Name(name)) if name == "main" => None,
+            Some(table) => Some(table.clone()),
+        };
+
+        let mut post_up = Vec::new();
+        if let Some(path) = &self.private_key_file {
+            post_up.push(format!("wg set %i private-key {}", path.display()));
+        }
+        for peer in &self.device.peers {
+            let path = self.peer_options.get(&peer.public_key).and_then(|options| options.preshared_key_file.as_ref());
+            if let Some(path) = path {
+                post_up.push(format!("wg set %i peer {} preshared-key {}", peer.public_key, path.display()));
+            }
+        }
+
+        WgQuickConfig {
+            address: self.address.clone(),
+            dns: self.dns.clone(),
+            dns_search: self.domains.clone(),
+            mtu: self.mtu,
+            table,
+            post_up,
+            endpoint_hosts: self.endpoint_hosts.clone(),
+            ..WgQuickConfig::new(self.device.clone())
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NetdevSection {
+    NetDev,
+    WireGuard,
+    WireGuardPeer,
+    /// e.g. `[Match]`, whose keys are skipped
+    Other,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NetworkSection {
+    Match,
+    Network,
+    /// e.g. `[Route]` or `[Link]`, whose keys are skipped
+    Other,
+}
+
+/// Blanks lines starting with `;` so line numbers stay intact
+fn strip_comments(input: &str) -> String {
+    input
+        .lines()
+        .map(|line| if line.trim_start().starts_with(';') { "" } else { line })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn write_routing(output: &mut String, table: Option<&Table>, metric: Option<u32>) {
+    // networkd has no auto table, the closest is main
+    match table {
+        Some(Table::Auto) => output.push_str("RouteTable=main\n"),
+        Some(table) => {
+            let _ = writeln!(output, "RouteTable={}", table);
+        }
+        None => {}
+    }
+    if let Some(metric) = metric {
+        let _ = writeln!(output, "RouteMetric={}", metric);
+    }
+}
+
+fn apply_wireguard(config: &mut NetworkdConfig, entry: &Entry<'_>) -> Result<(), ParseError> {
+    match entry.key {
+        "PrivateKey" => config.device.private_key = Some(conf::key(entry, "PrivateKey")?),
+        "PrivateKeyFile" => config.private_key_file = Some(entry.value.into()),
+        "ListenPort" if entry.value == "auto" => config.device.listen_port = Some(0),
+        "ListenPort" => config.device.listen_port = Some(conf::value(entry, "ListenPort")?),
+        "FirewallMark" => config.device.fwmark = Some(conf::value(entry, "FirewallMark")?),
+        "RouteTable" => config.route_table = Some(conf::value(entry, "RouteTable")?),
+        "RouteMetric" => config.route_metric = Some(conf::value(entry, "RouteMetric")?),
+        _ => {}
+    }
+
+    Ok(())
+}
+
+fn apply_peer(peer: &mut PendingPeer, options: &mut PeerOptions, entry: &Entry<'_>) -> Result<(), ParseError> {
+    match entry.key {
+        "PresharedKeyFile" => options.preshared_key_file = Some(entry.value.into()),
+        "RouteTable" => options.route_table = Some(conf::value(entry, "RouteTable")?),
+        "RouteMetric" => options.route_metric = Some(conf::value(entry, "RouteMetric")?),
+        // PendingPeer ignores the case of keys, networkd does not
+        "PublicKey" | "PresharedKey" | "AllowedIPs" | "Endpoint" | "PersistentKeepalive" => {
+            peer.apply_keeping_host(entry)?;
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+fn finish_peer(config: &mut NetworkdConfig, peer: Option<(PendingPeer, PeerOptions)>) -> Result<(), ParseError> {
+    if let Some((peer, options)) = peer {
+        config.endpoint_hosts.extend(peer.host_endpoint());
+        let peer = peer.finish()?;
+        if !options.is_empty() {
+            config.peer_options.insert(peer.public_key, options);
+        }
+        config.device.peers.push(peer);
+    }
+
+    Ok(())
+}
+
+/// Parses a `.netdev` file of kind `wireguard`
+///
+/// The configuration replaces all peers of the device and the allowed ips of
+/// every peer, like networkd does when it creates the interface.
+pub fn parse_netdev(input: &str) -> Result<NetworkdConfig, ParseError> {
+    let input = strip_comments(input);
+
+    // the name is only known once [NetDev] is complete
+    let mut config = NetworkdConfig::new(InterfaceName::try_from("wg0").unwrap(), DeviceConfig::new());
+    let mut netdev: Option<(usize, usize)> = None;
+    let mut name = None;
+    let mut kind = None;
+    let mut section = None;
+    let mut peer: Option<(PendingPeer, PeerOptions)> = None;
+
+
+    for token in conf::tokenize(&input) {
+        match token? {
+            Token::Section { line, column, name } => {
+                let next = match name {
+                    "NetDev" => NetdevSection::NetDev,
+                    "WireGuard" => NetdevSection::WireGuard,
+                    "WireGuardPeer" => NetdevSection::WireGuardPeer,
+                    _ => NetdevSection::Other,
+                };
+                finish_peer(&mut config, peer.take())?;
+                match next {
+                    NetdevSection::NetDev => netdev = Some((line, column)),
+                    NetdevSection::WireGuardPeer => peer = Some((PendingPeer::new(line, column), PeerOptions::default())),
+                    NetdevSection::WireGuard | NetdevSection::Other => {}
+                }
+                section = Some(next);
+            }
+            Token::Entry(entry) => {
+                match (section, peer.as_mut()) {
+                    (None, _) => {
+                        return Err(ParseError::new(entry.line, entry.column, ParseErrorKind::OutsideSection))
+                    }
+                    (Some(NetdevSection::NetDev), _) => match entry.key {
+                        "Name" => name = Some(conf::value::<InterfaceName>(&entry, "Name")?),
+                        "Kind" => {
+                            if entry.value != "wireguard" {
+                                return Err(entry.invalid("Kind", "only wireguard is supported"));
+                            }
+                            kind = Some(());
+                        }
+                        "Description" => config.description = Some(entry.value.to_owned()),
+                        "MTUBytes" => config.mtu = Some(conf::value(&entry, "MTUBytes")?),
+                        _ => {}
+                    },
+                    (Some(NetdevSection::WireGuardPeer), Some((peer, options))) => apply_peer(peer, options, &entry)?,
+                    (Some(NetdevSection::WireGuard), _) => apply_wireguard(&mut config, &entry)?,
+                    _ => {}
+                }
+            }
+        }
+    }
+    finish_peer(&mut config, peer)?;
+
+    let (line, column) = netdev.unwrap_or((1, 1));
+    match (name, kind) {
+        (Some(name), Some(())) => config.name = name,
+        (None, _) => return Err(ParseError::new(line, column, ParseErrorKind::MissingKey("Name"))),
+        (_, None) => return Err(ParseError::new(line, column, ParseErrorKind::MissingKey("Kind"))),
+    }
+    config.device = conf::replace_all(config.device);
+
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quick;
+
+    const NETDEV: &str = "\
+[NetDev]
+Name=wg0
+Kind=wireguard
+Description=site to site
+MTUBytes=1420
+
+[WireGuard]
+PrivateKeyFile=/etc/systemd/network/wg0.key
+ListenPort=51820
+FirewallMark=4660
+RouteTable=1234
+
+[WireGuardPeer]
+PublicKey=xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=
+PresharedKeyFile=/etc/systemd/network/wg0.psk
+AllowedIPs=10.0.0.2/32,fd00::2/128
+Endpoint=192.0.2.1:51820
+PersistentKeepalive=25
+RouteTable=off
+
+[WireGuardPeer]
+PublicKey=TrMvSoP4jYQlY6RIzBgbssQqY3vxI2Pi+y71lOWWXX0=
+AllowedIPs=10.0.1.0/24
+";
+
+    const NETWORK: &str = "\
+[Match]
+Name=wg0
+
+[Network]
+Address=10.0.0.1/24
+Address=fd00::1/64
+DNS=10.0.0.53 fd00::53
+Domains=corp.example
+";
+
+    fn parsed() -> NetworkdConfig {
+        let mut config = parse_netdev(NETDEV).unwrap();
+        config.parse_network(NETWORK).unwrap();
+        config
+    }
+
+    #[test]
+    fn it_parses_and_writes_netdev_and_network_files() {
+        let config = parsed();
+
+        assert_eq!(config.name.as_str(), "wg0");
+        assert_eq!(config.mtu, Some(1420));
+        assert_eq!(config.private_key_file, Some("/etc/systemd/network/wg0.key".into()));
+        assert_eq!(config.route_table, Some(Table::Id(1234)));
+        assert_eq!(config.device.fwmark, Some(0x1234));
+        assert!(config.device.replace_peers);
+        assert_eq!(config.device.peers.len(), 2);
+        assert_eq!(config.device.peers[0].persistent_keepalive_interval, Some(25));
+        assert_eq!(config.peer_options.len(), 1);
+        assert_eq!(config.peer_options[&config.device.peers[0].public_key].route_table, Some(Table::Off));
+        assert_eq!(config.address.len(), 2);
+        assert_eq!(config.domains, ["corp.example"]);
+
+        assert_eq!(config.netdev(), NETDEV);
+        assert_eq!(config.network(), NETWORK);
+    }
+
+    #[test]
+    fn it_converts_from_and_to_wg_quick() {
+        let quick = parsed().to_wg_quick();
+
+        assert_eq!(quick.table, Some(Table::Id(1234)));
+        assert_eq!(quick.dns_search, ["corp.example"]);
+        assert_eq!(
+            quick.post_up,
+            [
+                "wg set %i private-key /etc/systemd/network/wg0.key",
+                "wg set %i peer xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg= preshared-key /etc/systemd/network/wg0.psk",
+            ]
+        );
+
+        let quick = quick::parse("[Interface]\nAddress = 10.0.0.1/24\nPostUp = true\nListenPort = 51820\n").unwrap();
+        let config = NetworkdConfig::from_wg_quick("wg1".parse().unwrap(), &quick);
+        assert_eq!(config.route_table, Some(Table::Name("main".into())));
+        assert_eq!(config.device.listen_port, Some(51820));
+        assert_eq!(config.network(), "[Match]\nName=wg1\n\n[Network]\nAddress=10.0.0.1/24\n");
+
+        // routes stay in the main table on the way back
+        let back = config.to_wg_quick();
+        assert_eq!(back.table, None);
+        assert_eq!(back.address, quick.address);
+    }
+
+    #[test]
+    fn it_skips_unrelated_keys() {
+        let netdev = "\
+[Match]
+Host=gateway
+
+[NetDev]
+Name=wg0
+Kind=wireguard
+MACAddress=none
+
+[WireGuard]
+listenport=1
+ListenPort=51820
+";
+        let config = parse_netdev(netdev).unwrap();
+        assert_eq!(config.device.listen_port, Some(51820));
+
+        // keys are case sensitive within [WireGuardPeer] too
+        let peer = "[WireGuardPeer]\nPublicKey=xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=\nallowedips=10.0.0.0/24\n";
+        let config = parse_netdev(&format!("{}\n{}", netdev, peer)).unwrap();
+        assert!(config.device.peers[0].allowed_ips.is_empty());
+        let error = parse_netdev(&format!("{}\n[WireGuardPeer]\npublickey=x\n", netdev)).unwrap_err();
+        assert_eq!(error.kind, ParseErrorKind::MissingPublicKey);
+
+        let network = "\
+[Match]
+Name=wg0
+
+[Link]
+RequiredForOnline=no
+
+[Network]
+Address=10.0.0.1/24
+IPForward=yes
+ConfigureWithoutCarrier=yes
+
+[Route]
+Destination=10.1.0.0/16
+";
+        let mut config = parse_netdev(NETDEV).unwrap();
+        config.parse_network(network).unwrap();
+        assert_eq!(config.address, ["10.0.0.1/24".parse().unwrap()]);
+        assert!(config.dns.is_empty());
+    }
+
+    #[test]
+    fn it_keeps_endpoint_hostnames() {
+        let netdev = NETDEV.replace("Endpoint=192.0.2.1:51820", "Endpoint=vpn.example.invalid:51820");
+        let config = parse_netdev(&netdev).unwrap();
+
+        assert_eq!(config.device.peers[0].endpoint, None);
+        assert_eq!(config.endpoint_hosts[0].to_string(), "vpn.example.invalid:51820");
+        assert_eq!(config.endpoint_hosts[0].public_key, config.device.peers[0].public_key);
+        assert_eq!(config.netdev(), netdev);
+        assert!(config.to_wg_quick().to_string().contains("Endpoint = vpn.example.invalid:51820\n"));
+    }
+
+    #[test]
+    fn it_reports_networkd_errors() {
+        let error = parse_netdev("; comment\n[NetDev]\nName=wg0\nKind=bridge\n").unwrap_err();
+        assert_eq!((error.line, error.column), (4, 6));
+
+        let error = parse_netdev("[NetDev]\nKind=wireguard\n").unwrap_err();
+        assert_eq!(error.kind, ParseErrorKind::MissingKey("Name"));
+
+        let error = parse_netdev("[NetDev]\nName=wg0\nKind=wireguard\n[WireGuard]\nListenPort=none\n").unwrap_err();
+        assert_eq!((error.line, error.column), (5, 12));
+
+        let mut config = parsed();
+        let error = config.parse_network("[Match]\nName=wg1 wg2\n").unwrap_err();
+        assert_eq!((error.line, error.column), (2, 6));
+        let error = config.parse_network("[Network]\nDNS=10.0.0.53 resolver\n").unwrap_err();
+        assert_eq!((error.line, error.column), (2, 15));
+    }
+}
@@ -0,0 +1,591 @@
+//! The configuration files of wg-quick(8)
+//!
+//! wg-quick extends the format of [`conf`](crate::conf) by keys it handles
+//! itself before calling `wg setconf`: interface addresses, DNS, MTU, the
+//! routing table, hooks and SaveConfig. [`WgQuickConfig`] keeps these next to
+//! the [`DeviceConfig`] which goes to [`set_device`](crate::set_device), so a
+//! file can be read, changed and written back without losing any of its keys.
+//!
+//! Writing a parsed configuration keeps the lines of the file, comments and
+//! blank lines included, and only rewrites the keys which were changed. New
+//! keys go to the end of their section and new peers to the end of the file.
+//! Endpoints given by hostname are kept as written in
+//! [`WgQuickConfig::endpoint_hosts`] and only resolved by
+//! [`WgQuickConfig::resolved`], right before the configuration is applied.
+//!
+//! # Example
+//!
+//! ```
+//! use wgbind::quick::WgQuickConfig;
+//!
+//! let input = "\
+//! [Interface]
+//! Address = 10.0.0.1/24
+//! DNS = 10.0.0.53, corp.example
+//! PostUp = iptables -A FORWARD -i %i -j ACCEPT
+//! ListenPort = 51820
+//! ";
+//!
+//! let config: WgQuickConfig = input.parse().unwrap();
+//! assert_eq!(config.device.listen_port, Some(51820));
+//! assert_eq!(config.dns_search, ["corp.example"]);
+//! assert_eq!(config.to_string().parse::<WgQuickConfig>(), Ok(config));
+//! ```
+
+use std::fmt;
+use std::fmt::Write;
+use std::io;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::allowed_ip::AllowedIp;
+use crate::conf::{self, Entry, HostEndpoint, ParseError, Section, Token};
+use crate::config::DeviceConfig;
+use crate::key::PublicKey;
+
+/// The routing table wg-quick adds the routes of the allowed ips to
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Table {
+    /// no routes are added
+    Off,
+    /// the main table, or a policy routing setup for default routes
+    Auto,
+    Id(u32),
+    /// a table name from /etc/iproute2/rt_tables
+    Name(String),
+}
+
+impl FromStr for Table {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("must not be empty");
+        }
+        if s.eq_ignore_ascii_case("off") {
+            return Ok(Table::Off);
+        }
+        if s.eq_ignore_ascii_case("auto") {
+            return Ok(Table::Auto);
+        }
+
+        Ok(s.parse().map(Table::Id).unwrap_or_else(|_| Table::Name(s.to_owned())))
+    }
+}
+
+impl fmt::Display for Table {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Table::Off => f.write_str("off"),
+            Table::Auto => f.write_str("auto"),
+            Table::Id(id) => write!(f, "{}", id),
+            Table::Name(name) => f.write_str(name),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+serde_as_string!(Table);
+
+/// A wg-quick configuration file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(default))]
+pub struct WgQuickConfig {
+    /// everything handed to `wg setconf`, replacing all peers like wg-quick does
+    pub device: DeviceConfig,
+    /// addresses of the interface, host bits are kept e.g. `10.0.0.1/24`
+    pub address: Vec<AllowedIp>,
+    /// name servers from `DNS`
+    pub dns: Vec<IpAddr>,
+    /// entries of `DNS` which are not an address are search domains
+    pub dns_search: Vec<String>,
+    pub mtu: Option<u32>,
+    pub table: Option<Table>,
+    /// shell commands in the order they appear, `%i` is replaced by wg-quick
+    pub pre_up: Vec<String>,
+    pub post_up: Vec<String>,
+    pub pre_down: Vec<String>,
+    pub post_down: Vec<String>,
+    /// whether wg-quick writes the configuration back on shutdown
+    pub save_config: bool,
+    /// endpoints given by hostname, the peers in `device` have none until resolved
+    pub endpoint_hosts: Vec<HostEndpoint>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) source: Source,
+}
+
+impl WgQuickConfig {
+    pub fn new(device: DeviceConfig) -> Self {
+        Self {
+            device,
+            ..Self::default()
+        }
+    }
+
+    /// A copy without private and preshared keys, e.g. for logging
+    pub fn redacted(&self) -> Self {
+        Self {
+            device: self.device.redacted(),
+            ..self.clone()
+        }
+    }
+
+    /// The configuration for [`set_device`](crate::set_device), with the hostnames of endpoints resolved
+    pub fn resolved(&self) -> io::Result<DeviceConfig> {
+        conf::resolve_endpoints(&self.device, &self.endpoint_hosts)
+    }
+
+    /// Applies one of the keys only known to wg-quick, `Ok(false)` for any other key
+    fn apply(&mut self, entry: &Entry<'_>) -> Result<bool, ParseError> {
+        match entry.key.to_ascii_lowercase().as_str() {
+            "address" => {
+                for (column, item) in entry.list() {
+                    let address = item.parse().map_err(|error| entry.invalid_at(column, "Address", error))?;
+                    self.address.push(address);
+                }
+            }
+            "dns" => {
+                for (_, item) in entry.list() {
+                    match item.parse() {
+                        Ok(server) => self.dns.push(server),
+                        Err(_) => self.dns_search.push(item.to_owned()),
+                    }
+                }
+            }
+            "mtu" => self.mtu = Some(conf::value(entry, "MTU")?),
+            "table" => self.table = Some(conf::value(entry, "Table")?),
+            "preup" => self.pre_up.push(entry.value.to_owned()),
+            "postup" => self.post_up.push(entry.value.to_owned()),
+            "predown" => self.pre_down.push(entry.value.to_owned()),
+            "postdown" => self.post_down.push(entry.value.to_owned()),
+            "saveconfig" => {
+                self.save_config = match entry.value.to_ascii_lowercase().as_str() {
+                    "true" => true,
+                    "false" => false,
+                    _ => return Err(entry.invalid("SaveConfig", "expected true or false")),
+                }
+            }
+            _ => return Ok(false),
+        }
+
+        Ok(true)
+    }
+
+    /// The keys of every section as they are written
+    fn sections(&self) -> Vec<(SectionId, Keys)> {
+        let mut keys = Vec::new();
+        if !self.address.is_empty() {
+            let address = self.address.iter().map(|ip| ip.to_string()).collect::<Vec<_>>();
+            keys.push(("Address", address.join(", ")));
+        }
+        if !self.dns.is_empty() || !self.dns_search.is_empty() {
+            let dns = self
+                .dns
+                .iter()
+                .map(|server| server.to_string())
+                .chain(self.dns_search.iter().cloned())
+                .collect::<Vec<_>>();
+            keys.push(("DNS", dns.join(", ")));
+        }
+        if let Some(mtu) = self.mtu {
+            keys.push(("MTU", mtu.to_string()));
+        }
+        if let Some(table) = &self.table {
+            keys.push(("Table", table.to_string()));
+        }
+        for (key, commands) in [
+            ("PreUp", &self.pre_up),
+            ("PostUp", &self.post_up),
+            ("PreDown", &self.pre_down),
+            ("PostDown", &self.post_down),
+        ] {
+            keys.extend(commands.iter().map(|command| (key, command.clone())));
+        }
+        if self.save_config {
+            keys.push(("SaveConfig", "true".to_owned()));
+        }
+        keys.extend(conf::interface_keys(&self.device));
+
+        let mut sections = vec![(SectionId::Interface, keys)];
+        for peer in &self.device.peers {
+            let host = self.endpoint_hosts.iter().find(|host| host.public_key == peer.public_key);
+            sections.push((SectionId::Peer(peer.public_key), conf::peer_keys(peer, host)));
+        }
+
+        sections
+    }
+}
+
+type Keys = Vec<(&'static str, String)>;
+
+const INTERFACE_KEYS: [&str; 12] = [
+    "Address",
+    "DNS",
+    "MTU",
+    "Table",
+    "PreUp",
+    "PostUp",
+    "PreDown",
+    "PostDown",
+    "SaveConfig",
+    "ListenPort",
+    "FwMark",
+    "PrivateKey",
+];
+const PEER_KEYS: [&str; 5] = ["PublicKey", "PresharedKey", "AllowedIPs", "Endpoint", "PersistentKeepalive"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum SectionId {
+    Interface,
+    Peer(PublicKey),
+}
+
+impl SectionId {
+    /// The spelling of `key` used when writing it
+    fn key(&self, key: &str) -> Option<&'static str> {
+        let keys: &[&'static str] = match self {
+            SectionId::Interface => &INTERFACE_KEYS,
+            SectionId::Peer(_) => &PEER_KEYS,
+        };
+
+        keys.iter().copied().find(|known| known.eq_ignore_ascii_case(key))
+    }
+}
+
+/// What a line of the source file is
+enum Line<'a> {
+    Header(&'a SectionId),
+    Key(&'a SectionId, &'static str),
+    /// a comment or blank line, within a section or before the first one
+    Other(Option<&'a SectionId>),
+}
+
+/// The file a configuration was parsed from
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Source {
+    text: String,
+    /// the keys as parsed, to tell which ones changed since
+    parsed: Vec<(SectionId, Keys)>,
+    /// the section of every section header in `text`, in order
+    headers: Vec<SectionId>,
+}
+
+/// How a file is formatted is no part of the configuration
+impl PartialEq for Source {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for Source {}
+
+fn find<'a>(sections: &'a [(SectionId, Keys)], id: &SectionId) -> Option<&'a Keys> {
+    sections.iter().find(|(section, _)| section == id).map(|(_, keys)| keys)
+}
+
+fn values<'a>(keys: Option<&'a Keys>, key: &str) -> Vec<&'a str> {
+    keys.into_iter().flatten().filter(|(k, _)| *k == key).map(|(_, value)| value.as_str()).collect()
+}
+
+fn write_key(output: &mut String, key: &str, value: &str) {
+    // the last line of a file may lack its newline
+    if !output.is_empty() && !output.ends_with('\n') {
+        output.push('\n');
+    }
+    let _ = writeln!(output, "{} = {}", key, value);
+}
+
+impl Source {
+    fn lines(&self) -> Vec<(&str, Line<'_>)> {
+        let mut tokens = conf::tokenize(&self.text).flatten().peekable();
+        let mut headers = self.headers.iter();
+        let mut section = None;
+
+        let mut lines = Vec::new();
+        for (index, text) in self.text.split_inclusive('\n').enumerate() {
+            let line = match tokens.next_if(|token| token.line() == index + 1) {
+                Some(Token::Section { .. }) => {
+                    section = headers.next();
+                    section.map_or(Line::Other(None), Line::Header)
+                }
+                Some(Token::Entry(entry)) => match section.and_then(|id| Some((id, id.key(entry.key)?))) {
+                    Some((id, key)) => Line::Key(id, key),
+                    None => Line::Other(section),
+                },
+                None => Line::Other(section),
+            };
+            lines.push((text, line));
+        }
+
+        lines
+    }
+
+    /// Writes the file with the keys of `sections` merged in
+    fn write(&self, output: &mut String, sections: &[(SectionId, Keys)]) {
+        let lines = self.lines();
+
+        // new keys follow the last key of the first section with the same header
+        let mut present = Vec::new();
+        let mut ends: Vec<(&SectionId, usize)> = Vec::new();
+        let mut first = false;
+        for (index, (_, line)) in lines.iter().enumerate() {
+            match *line {
+                Line::Header(id) => {
+                    first = !ends.iter().any(|(section, _)| *section == id);
+                    if first {
+                        ends.push((id, index));
+                    }
+                }
+                Line::Key(id, key) => {
+                    present.push((id, key));
+                    if first {
+                        if let Some(end) = ends.last_mut() {
+                            end.1 = index;
+                        }
+                    }
+                }
+                Line::Other(_) => {}
+            }
+        }
+
+        let mut rewritten = Vec::new();
+        for (index, (text, line)) in lines.iter().enumerate() {
+            let id = match *line {
+                Line::Header(id) | Line::Key(id, _) => Some(id),
+                Line::Other(id) => id,
+            };
+            // the lines of removed peers are dropped
+            let keys = id.map(|id| find(sections, id));
+            if keys == Some(None) {
+                continue;
+            }
+
+            match *line {
+                Line::Key(id, key) => {
+                    let now = values(find(sections, id), key);
+                    if now == values(find(&self.parsed, id), key) {
+                        output.push_str(text);
+                    } else if !rewritten.contains(&(id, key)) {
+                        rewritten.push((id, key));
+                        for value in now {
+                            write_key(output, key, value);
+                        }
+                    }
+                }
+                _ => output.push_str(text),
+            }
+
+            if let (Some(&(id, _)), Some(Some(keys))) = (ends.iter().find(|(_, end)| *end == index), keys) {
+                for (key, value) in keys.iter().filter(|(key, _)| !present.contains(&(id, *key))) {
+                    write_key(output, key, value);
+                }
+            }
+        }
+    }
+}
+
+/// Parses a wg-quick configuration file
+///
+/// Endpoints given by hostname are not resolved, see [`WgQuickConfig::resolved`].
+pub fn parse(input: &str) -> Result<WgQuickConfig, ParseError> {
+    let mut config = WgQuickConfig::default();
+    let (device, hosts) = conf::parse_keeping_hosts(input, |entry| config.apply(entry))?;
+    config.device = conf::replace_all(device);
+    config.endpoint_hosts = hosts;
+
+    let mut peers = config.device.peers.iter();
+    let headers = conf::tokenize(input)
+        .flatten()
+        .filter_map(|token| match token {
+            Token::Section { line, column, name } => match Section::parse(line, column, name) {
+                Ok(Section::Interface) => Some(SectionId::Interface),
+                Ok(Section::Peer) => peers.next().map(|peer| SectionId::Peer(peer.public_key)),
+                Err(_) => None,
+            },
+            Token::Entry(_) => None,
+        })
+        .collect();
+    config.source = Source {
+        text: input.to_owned(),
+        parsed: config.sections(),
+        headers,
+    };
+
+    Ok(config)
+}
+
+impl FromStr for WgQuickConfig {
+    type Err = ParseError;
+
+    /// Same as [`parse`]
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse(s)
+    }
+}
+
+impl fmt::Display for WgQuickConfig {
+    /// Writes the file the configuration was parsed from with the changes merged in
+    ///
+    /// Without one, the wg-quick keys come first, followed by the keys of
+    /// wg(8) and the peers.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sections = self.sections();
+        let mut output = String::new();
+        self.source.write(&mut output, &sections);
+
+        // sections missing from the file, an empty [Interface] only without one
+        for (id, keys) in &sections {
+            let empty = *id == SectionId::Interface && keys.is_empty() && !output.is_empty();
+            if empty || self.source.headers.contains(id) {
+                continue;
+            }
+            // one blank line before the header
+            if !output.is_empty() && !output.ends_with("\n\n") {
+                output.push_str(if output.ends_with('\n') { "\n" } else { "\n\n" });
+            }
+            output.push_str(match id {
+                SectionId::Interface => "[Interface]\n",
+                SectionId::Peer(_) => "[Peer]\n",
+            });
+            conf::write_keys(&mut output, keys);
+        }
+
+        f.write_str(&output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conf::ParseErrorKind;
+
+    const CONFIG: &str = "\
+[Interface]
+Address = 10.0.0.1/24, fd00::1/64
+DNS = 10.0.0.53, fd00::53, corp.example
+MTU = 1420
+Table = 1234
+PreUp = true
+PostUp = iptables -A FORWARD -i %i -j ACCEPT
+PostUp = ip rule add fwmark 0x1234 table 1234
+PreDown = true
+PostDown = iptables -D FORWARD -i %i -j ACCEPT
+SaveConfig = true
+ListenPort = 51820
+PrivateKey = yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=
+
+[Peer]
+PublicKey = xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=
+AllowedIPs = 10.0.0.2/32
+Endpoint = 192.0.2.1:51820
+PersistentKeepalive = 25
+";
+
+    #[test]
+    fn it_parses_wg_quick_keys() {
+        let config = parse(CONFIG).unwrap();
+
+        assert_eq!(config.address, ["10.0.0.1/24", "fd00::1/64"].map(|ip| ip.parse().unwrap()));
+        assert_eq!(config.dns, ["10.0.0.53", "fd00::53"].map(|ip| ip.parse::<IpAddr>().unwrap()));
+        assert_eq!(config.dns_search, ["corp.example"]);
+        assert_eq!(config.mtu, Some(1420));
+        assert_eq!(config.table, Some(Table::Id(1234)));
+        assert_eq!(config.pre_up, ["true"]);
+        assert_eq!(
+            config.post_up,
+            ["iptables -A FORWARD -i %i -j ACCEPT", "ip rule add fwmark 0x1234 table 1234"]
+        );
+        assert!(config.save_config);
+
+        assert_eq!(config.device.listen_port, Some(51820));
+        assert!(config.device.replace_peers);
+        assert_eq!(config.device.peers.len(), 1);
+        assert!(config.device.peers[0].replace_allowed_ips);
+    }
+
+    #[test]
+    fn it_writes_back_every_key() {
+        let config = parse(CONFIG).unwrap();
+
+        assert_eq!(config.to_string(), CONFIG);
+        assert_eq!(parse(&config.to_string()), Ok(config));
+    }
+
+    const COMMENTED: &str = "\
+# site to site link
+[Interface]
+address = 10.0.0.1/24   # office
+ListenPort = 51820
+PrivateKey = yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=
+
+# the hub
+[Peer]
+PublicKey = xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=
+Endpoint = vpn.example.com:51820
+AllowedIPs = 10.0.0.0/24
+AllowedIPs = fd00::/64
+";
+
+    #[test]
+    fn it_keeps_comments_and_hostnames() {
+        let mut config = parse(COMMENTED).unwrap();
+        let public_key = config.device.peers[0].public_key;
+
+        assert_eq!(config.to_string(), COMMENTED);
+        assert_eq!(config.device.peers[0].endpoint, None);
+        assert_eq!(
+            config.endpoint_hosts,
+            [HostEndpoint {
+                public_key,
+                host: "vpn.example.com".into(),
+                port: 51820,
+            }]
+        );
+
+        config.mtu = Some(1420);
+        config.device.listen_port = Some(51821);
+        config.device.peers[0].allowed_ips.pop();
+        let added = "TrMvSoP4jYQlY6RIzBgbssQqY3vxI2Pi+y71lOWWXX0=".parse().unwrap();
+        config.device.peers.push(crate::PeerConfig::new(added));
+        assert_eq!(
+            config.to_string(),
+            "\
+# site to site link
+[Interface]
+address = 10.0.0.1/24   # office
+ListenPort = 51821
+PrivateKey = yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=
+MTU = 1420
+
+# the hub
+[Peer]
+PublicKey = xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=
+Endpoint = vpn.example.com:51820
+AllowedIPs = 10.0.0.0/24
+
+[Peer]
+PublicKey = TrMvSoP4jYQlY6RIzBgbssQqY3vxI2Pi+y71lOWWXX0=
+"
+        );
+
+        config.endpoint_hosts[0].host = "localhost".into();
+        let resolved = config.resolved().unwrap();
+        assert_eq!(resolved.peers[0].endpoint.map(|endpoint| endpoint.port()), Some(51820));
+    }
+
+    #[test]
+    fn it_keeps_setconf_errors() {
+        assert_eq!("off".parse(), Ok(Table::Off));
+        assert_eq!("vpn".parse(), Ok(Table::Name("vpn".into())));
+
+        let error = parse("[Interface]\nSaveConfig = maybe").unwrap_err();
+        assert_eq!((error.line, error.column), (2, 14));
+
+        let error = parse("[Interface]\nAddres = 10.0.0.1/24").unwrap_err();
+        assert_eq!(error.kind, ParseErrorKind::UnknownKey("Addres".into()));
+
+        // wg-quick keys are only valid within [Interface]
+        let error = parse("[Peer]\nPublicKey = xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=\nMTU = 1420").unwrap_err();
+        assert_eq!(error.line, 3);
+    }
+}
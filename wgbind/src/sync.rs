@@ -0,0 +1,320 @@
+//! Reconciling a device with a desired configuration, like `wg syncconf`
+//!
+//! Writing a configuration with `replace_peers` removes every peer first and
+//! adds them again, which drops their sessions. [`sync`] instead reads the
+//! device, compares it with the desired configuration and only sends what
+//! differs: new peers, removed peers and the changed values of existing ones,
+//! all within a single `wg_set_device` call.
+//!
+//! The desired configuration describes all peers, a peer of the device which
+//! is missing from it gets removed. Any other value which is `None` in the
+//! desired configuration is left as it is.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use wgbind::{conf, sync};
+//!
+//! let desired = conf::parse(&std::fs::read_to_string("/etc/wireguard/wg0.conf").unwrap()).unwrap();
+//! let changes = sync::sync(&"wg0".parse().unwrap(), &desired).unwrap();
+//! println!("{}", changes);
+//! ```
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::allowed_ip::AllowedIp;
+use crate::backend::{Backend, Kernel};
+use crate::config::{DeviceConfig, PeerConfig};
+use crate::device::{Device, Peer};
+use crate::error::Error;
+use crate::interface_name::InterfaceName;
+use crate::key::PublicKey;
+
+/// Which values of an existing peer change
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerUpdate {
+    pub public_key: PublicKey,
+    pub preshared_key: bool,
+    pub endpoint: bool,
+    pub persistent_keepalive_interval: bool,
+    pub allowed_ips: bool,
+}
+
+/// The difference between a device and a desired configuration
+///
+/// Displaying a change set names what changes without any secret values, so
+/// it can be logged as is.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChangeSet {
+    pub private_key: bool,
+    pub listen_port: bool,
+    pub fwmark: bool,
+    pub added: Vec<PublicKey>,
+    pub removed: Vec<PublicKey>,
+    pub updated: Vec<PeerUpdate>,
+    /// the minimal configuration which applies the changes
+    pub config: DeviceConfig,
+}
+
+impl ChangeSet {
+    /// true if the device matches the desired configuration already
+    pub fn is_empty(&self) -> bool {
+        !self.private_key
+            && !self.listen_port
+            && !self.fwmark
+            && self.added.is_empty()
+            && self.removed.is_empty()
+            && self.updated.is_empty()
+    }
+}
+
+impl fmt::Display for ChangeSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return f.write_str("no changes");
+        }
+
+        let mut changes = Vec::new();
+        for (changed, name) in [
+            (self.private_key, "private key"),
+            (self.listen_port, "listen port"),
+            (self.fwmark, "fwmark"),
+        ] {
+            if changed {
+                changes.push(format!("change {}", name));
+            }
+        }
+        changes.extend(self.added.iter().map(|key| format!("add peer {}", key)));
+        changes.extend(self.removed.iter().map(|key| format!("remove peer {}", key)));
+        for update in &self.updated {
+            let fields = [
+                (update.preshared_key, "preshared key"),
+                (update.endpoint, "endpoint"),
+                (update.persistent_keepalive_interval, "persistent keepalive"),
+                (update.allowed_ips, "allowed ips"),
+            ]
+            .iter()
+            .filter(|(changed, _)| *changed)
+            .map(|(_, name)| *name)
+            .collect::<Vec<_>>();
+            changes.push(format!("update peer {} ({})", update.public_key, fields.join(", ")));
+        }
+
+        f.write_str(&changes.join("; "))
+    }
+}
+
+/// Allowed ips as the kernel stores them, ignoring order and host bits
+fn normalized(allowed_ips: &[AllowedIp]) -> HashSet<AllowedIp> {
+    allowed_ips.iter().map(|ip| ip.normalized()).collect()
+}
+
+fn diff_peer(current: &Peer, desired: &PeerConfig) -> Option<(PeerUpdate, PeerConfig)> {
+    let current_psk = current.preshared_key().filter(|key| !key.is_zero());
+    let desired_psk = desired.preshared_key.as_ref().map(|key| Some(key).filter(|key| !key.is_zero()));
+
+    let update = PeerUpdate {
+        public_key: desired.public_key,
+        preshared_key: desired_psk.is_some_and(|key| key != current_psk),
+        endpoint: desired.endpoint.is_some_and(|endpoint| current.endpoint() != Some(endpoint)),
+        persistent_keepalive_interval: desired
+            .persistent_keepalive_interval
+            .is_some_and(|interval| interval != current.persistent_keepalive_interval()),
+        allowed_ips: normalized(&desired.allowed_ips) != normalized(current.allowed_ips()),
+    };
+
+    if !(update.preshared_key || update.endpoint || update.persistent_keepalive_interval || update.allowed_ips) {
+        return None;
+    }
+
+    let mut config = PeerConfig::new(desired.public_key);
+    if update.preshared_key {
+        config.preshared_key = desired.preshared_key.clone();
+    }
+    if update.endpoint {
+        config.endpoint = desired.endpoint;
+    }
+    if update.persistent_keepalive_interval {
+        config.persistent_keepalive_interval = desired.persistent_keepalive_interval;
+    }
+    if update.allowed_ips {
+        config.allowed_ips = desired.allowed_ips.clone();
+        config.replace_allowed_ips = true;
+    }
+
+    Some((update, config))
+}
+
+/// Computes the changes which turn `current` into `desired`
+///
+/// `replace_peers` and `remove` of the desired configuration are ignored,
+/// a peer is removed by leaving it out.
+pub fn diff(current: &Device, desired: &DeviceConfig) -> ChangeSet {
+    let mut changes = ChangeSet::default();
+
+    if let Some(key) = &desired.private_key {
+        if current.private_key() != Some(key) {
+            changes.private_key = true;
+            changes.config.private_key = Some(key.clone());
+        }
+    }
+    if let Some(port) = desired.listen_port {
+        if current.listen_port() != port {
+            changes.listen_port = true;
+            changes.config.listen_port = Some(port);
+        }
+    }
+    if let Some(fwmark) = desired.fwmark {
+        if current.fwmark() != fwmark {
+            changes.fwmark = true;
+            changes.config.fwmark = Some(fwmark);
+        }
+    }
+
+    let current_peers: HashMap<&PublicKey, &Peer> =
+        current.peers().iter().map(|peer| (peer.public_key(), peer)).collect();
+    let desired_peers = desired.peers.iter().filter(|peer| !peer.remove);
+
+    for peer in desired_peers.clone() {
+        match current_peers.get(&peer.public_key) {
+            None => {
+                changes.added.push(peer.public_key);
+                changes.config.peers.push(PeerConfig {
+                    replace_allowed_ips: true,
+                    ..peer.clone()
+                });
+            }
+            Some(current) => {
+                if let Some((update, config)) = diff_peer(current, peer) {
+                    changes.updated.push(update);
+                    changes.config.peers.push(config);
+                }
+            }
+        }
+    }
+
+    let desired_keys: HashSet<&PublicKey> = desired_peers.map(|peer| &peer.public_key).collect();
+    for peer in current.peers() {
+        if !desired_keys.contains(peer.public_key()) {
+            changes.removed.push(*peer.public_key());
+            changes.config.peers.push(PeerConfig::new(*peer.public_key()).remove(true));
+        }
+    }
+
+    changes
+}
+
+/// Reads the device, computes the changes and applies them in one call
+///
+/// Nothing is written if the device matches already. Returns the applied changes.
+pub fn sync(device_name: &InterfaceName, desired: &DeviceConfig) -> Result<ChangeSet, Error> {
+    sync_with(&Kernel, device_name, desired)
+}
+
+/// Same as [`sync`] for a device of any [`Backend`]
+pub fn sync_with(backend: &impl Backend, device_name: &InterfaceName, desired: &DeviceConfig) -> Result<ChangeSet, Error> {
+    let current = backend.get(device_name)?;
+    let changes = diff(&current, desired);
+
+    if !changes.is_empty() {
+        backend.set(device_name, &changes.config)?;
+    }
+
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dump;
+
+    const PEER_A: &str = "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=";
+    const PEER_B: &str = "TrMvSoP4jYQlY6RIzBgbssQqY3vxI2Pi+y71lOWWXX0=";
+    const PEER_C: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+
+    fn current() -> Device {
+        let input = format!(
+            "(none)\t(none)\t51820\toff\n\
+             {PEER_A}\t(none)\t192.0.2.1:51820\t10.0.0.0/24,fd00::/64\t0\t0\t0\t25\n\
+             {PEER_B}\t(none)\t(none)\t10.0.1.0/24\t0\t0\t0\toff\n"
+        );
+        dump::parse(&"wg0".parse().unwrap(), &input).unwrap()
+    }
+
+    fn peer(key: &str, allowed_ips: &[&str]) -> PeerConfig {
+        PeerConfig::new(key.parse().unwrap()).allowed_ips(allowed_ips.iter().map(|ip| ip.parse().unwrap()))
+    }
+
+    #[test]
+    fn it_finds_no_changes_for_the_same_state() {
+        let desired = current().to_config();
+        let changes = diff(&current(), &desired);
+
+        assert!(changes.is_empty(), "{}", changes);
+        assert_eq!(changes.to_string(), "no changes");
+
+        // host bits and order of the allowed ips do not matter
+        let desired = DeviceConfig::new()
+            .peer(peer(PEER_A, &["fd00::1/64", "10.0.0.0/24"]))
+            .peer(peer(PEER_B, &["10.0.1.0/24"]));
+        assert!(diff(&current(), &desired).is_empty());
+    }
+
+    #[test]
+    fn it_computes_minimal_changes() {
+        let desired = DeviceConfig::new()
+            .listen_port(51821)
+            .peer(peer(PEER_A, &["10.0.0.0/24", "fd00::/64"]).endpoint("192.0.2.2:51820".parse().unwrap()))
+            .peer(peer(PEER_C, &["10.0.2.0/24"]));
+
+        let changes = diff(&current(), &desired);
+
+        assert!(changes.listen_port);
+        assert!(!changes.private_key);
+        assert_eq!(changes.added, [PEER_C.parse().unwrap()]);
+        assert_eq!(changes.removed, [PEER_B.parse().unwrap()]);
+        assert_eq!(
+            changes.updated,
+            [PeerUpdate {
+                public_key: PEER_A.parse().unwrap(),
+                preshared_key: false,
+                endpoint: true,
+                persistent_keepalive_interval: false,
+                allowed_ips: false,
+            }]
+        );
+
+        let config = &changes.config;
+        assert!(!config.replace_peers);
+        assert_eq!(config.listen_port, Some(51821));
+        assert_eq!(config.peers.len(), 3);
+
+        // only the endpoint of the existing peer is sent
+        assert_eq!(
+            config.peers[0],
+            PeerConfig::new(PEER_A.parse().unwrap()).endpoint("192.0.2.2:51820".parse().unwrap())
+        );
+        assert!(config.peers[1].replace_allowed_ips);
+        assert_eq!(config.peers[2], PeerConfig::new(PEER_B.parse().unwrap()).remove(true));
+
+        assert_eq!(
+            changes.to_string(),
+            format!("change listen port; add peer {PEER_C}; remove peer {PEER_B}; update peer {PEER_A} (endpoint)")
+        );
+    }
+
+    #[test]
+    fn it_replaces_changed_allowed_ips() {
+        let desired = DeviceConfig::new()
+            .peer(peer(PEER_A, &["10.0.0.0/24"]).persistent_keepalive_interval(0))
+            .peer(peer(PEER_B, &["10.0.1.0/24"]));
+
+        let changes = diff(&current(), &desired);
+        let update = &changes.updated[0];
+        assert!(update.allowed_ips && update.persistent_keepalive_interval);
+        assert!(changes.config.peers[0].replace_allowed_ips);
+        assert_eq!(changes.config.peers[0].allowed_ips, ["10.0.0.0/24".parse().unwrap()]);
+        assert_eq!(changes.config.peers[0].persistent_keepalive_interval, Some(0));
+    }
+}
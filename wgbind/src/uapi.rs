@@ -0,0 +1,558 @@
+//! Userspace implementations reached through their control socket
+//!
+//! wireguard-go and boringtun do not need the kernel module. Each device they run
+//! listens on `/var/run/wireguard/<name>.sock` and speaks the cross platform text
+//! protocol: a request is `get=1` or `set=1` followed by `key=value` lines and an
+//! empty line, the reply ends with `errno=N` and an empty line. Keys are hex encoded.
+//!
+//! [`list_device_names`](crate::list_device_names), [`get_device`](crate::get_device)
+//! and [`set_device`](crate::set_device) pick up these sockets on their own, like
+//! wg(8) does. [`Uapi`] talks to the sockets only, optionally within another
+//! directory.
+//!
+//! ```no_run
+//! use wgbind::{Backend, Uapi};
+//!
+//! let uapi = Uapi::with_socket_dir("/run/wireguard-go");
+//! for name in uapi.list().unwrap() {
+//!     println!("{:?}", uapi.get(&name.parse().unwrap()).unwrap());
+//! }
+//! ```
+
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use crate::backend::Backend;
+use crate::config::DeviceConfig;
+use crate::device::{Device, Peer};
+use crate::error::{Error, Operation};
+use crate::interface_name::InterfaceName;
+use crate::key::{PresharedKey, PrivateKey, PublicKey};
+
+/// Where wireguard-go and boringtun create their sockets
+pub const SOCKET_DIR: &str = "/var/run/wireguard";
+
+/// How long a daemon may take to accept a request and to reply, unless set otherwise
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Userspace devices with a control socket in one directory
+///
+/// Creating and deleting devices is up to the daemon, [`Backend::add`] and
+/// [`Backend::delete`] fail with `EOPNOTSUPP`. A daemon which stops
+/// answering fails the request with `ETIMEDOUT` once the timeout is up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Uapi {
+    socket_dir: PathBuf,
+    timeout: Duration,
+}
+
+impl Default for Uapi {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Uapi {
+    /// Sockets within [`SOCKET_DIR`]
+    pub fn new() -> Self {
+        Self::with_socket_dir(SOCKET_DIR)
+    }
+
+    pub fn with_socket_dir(socket_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            socket_dir: socket_dir.into(),
+            timeout: TIMEOUT,
+        }
+    }
+
+    /// Limits how long each request may take altogether, [`TIMEOUT`] by default
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn socket_dir(&self) -> &Path {
+        &self.socket_dir
+    }
+
+    pub fn socket_path(&self, name: &InterfaceName) -> PathBuf {
+        self.socket_dir.join(format!("{}.sock", name))
+    }
+
+    /// Whether a userspace implementation runs the device
+    pub fn has_device(&self, name: &InterfaceName) -> bool {
+        std::fs::symlink_metadata(self.socket_path(name))
+            .map(|metadata| metadata.file_type().is_socket())
+            .unwrap_or(false)
+    }
+
+    /// Sends a request and returns the lines of the reply without the errno
+    fn request(&self, operation: Operation, name: &InterfaceName, request: &str) -> Result<Vec<String>, Error> {
+        let error = |errno: i32| Error::from_errno(operation, Some(name.as_str()), errno);
+        let io_error = |e: io::Error| match e.kind() {
+            // a missing socket or one left behind by a daemon which is gone
+            io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused => error(libc::ENODEV),
+            // a timeout of the socket shows up as EAGAIN
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => error(libc::ETIMEDOUT),
+            _ => error(e.raw_os_error().unwrap_or(libc::EIO)),
+        };
+
+        // the timeout bounds the whole request, not each call which may block
+        let deadline = Instant::now() + self.timeout;
+        let remaining = || match deadline.checked_duration_since(Instant::now()) {
+            Some(left) if !left.is_zero() => Ok(Some(left)),
+            _ => Err(error(libc::ETIMEDOUT)),
+        };
+
+        let mut stream = connect(&self.socket_path(name), remaining()?).map_err(io_error)?;
+        stream.set_write_timeout(remaining()?).map_err(io_error)?;
+        stream.write_all(request.as_bytes()).map_err(io_error)?;
+
+        // the reply ends with an empty line
+        let mut reply = Vec::new();
+        let mut chunk = [0; 4096];
+        while !reply.ends_with(b"\n\n") && reply != b"\n" {
+            stream.set_read_timeout(remaining()?).map_err(io_error)?;
+            match stream.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(read) => reply.extend_from_slice(&chunk[..read]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(io_error(e)),
+            }
+        }
+
+        let reply = String::from_utf8(reply).map_err(|_| error(libc::EPROTO))?;
+        let mut lines = Vec::new();
+        let mut errno = None;
+        for line in reply.lines() {
+            if line.is_empty() {
+                break;
+            }
+
+            match line.strip_prefix("errno=") {
+                Some(value) => errno = Some(value.parse::<i32>().map_err(|_| error(libc::EPROTO))?),
+                None => lines.push(line.to_string()),
+            }
+        }
+
+        match errno {
+            Some(0) => Ok(lines),
+            Some(errno) => Err(error(errno)),
+            // the daemon hung up before finishing the reply
+            None => Err(error(libc::EPROTO)),
+        }
+    }
+}
+
+/// Connects like `UnixStream::connect` but gives up with `EAGAIN` after `timeout`
+///
+/// Once a daemon stops accepting, the backlog of its socket fills up and a plain
+/// connect blocks. Linux bounds that wait by the send timeout of the socket.
+fn connect(path: &Path, timeout: Option<Duration>) -> io::Result<UnixStream> {
+    let mut address: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+    address.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    let path = path.as_os_str().as_bytes();
+    // sun_path keeps a terminating NUL
+    if path.len() >= address.sun_path.len() {
+        return Err(io::Error::from_raw_os_error(libc::ENAMETOOLONG));
+    }
+    for (dst, src) in address.sun_path.iter_mut().zip(path) {
+        *dst = *src as libc::c_char;
+    }
+
+    let fd = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let stream = UnixStream::from(unsafe { OwnedFd::from_raw_fd(fd) });
+    stream.set_write_timeout(timeout)?;
+
+    let length = std::mem::size_of::<libc::sockaddr_un>() as libc::socklen_t;
+    if unsafe { libc::connect(fd, &address as *const libc::sockaddr_un as *const libc::sockaddr, length) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stream)
+}
+
+impl Backend for Uapi {
+    fn list(&self) -> Result<Vec<String>, Error> {
+        let entries = match std::fs::read_dir(&self.socket_dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(Error::from_errno(Operation::ListDevices, None, e.raw_os_error().unwrap_or(libc::EIO)))
+            }
+        };
+
+        let mut names = Vec::new();
+        for entry in entries.flatten() {
+            let is_socket = entry.file_type().map(|kind| kind.is_socket()).unwrap_or(false);
+            let file_name = entry.file_name();
+            let name = file_name.to_str().and_then(|name| name.strip_suffix(".sock"));
+            if let (true, Some(name)) = (is_socket, name) {
+                if name.parse::<InterfaceName>().is_ok() {
+                    names.push(name.to_owned());
+                }
+            }
+        }
+        names.sort();
+
+        Ok(names)
+    }
+
+    fn get(&self, name: &InterfaceName) -> Result<Device, Error> {
+        let lines = self.request(Operation::GetDevice, name, "get=1\n\n")?;
+
+        parse_device(name, &lines)
+            .ok_or_else(|| Error::from_errno(Operation::GetDevice, Some(name.as_str()), libc::EPROTO))
+    }
+
+    fn set(&self, name: &InterfaceName, config: &DeviceConfig) -> Result<(), Error> {
+        self.request(Operation::SetDevice, name, &set_request(config)).map(drop)
+    }
+
+    fn add(&self, name: &InterfaceName) -> Result<(), Error> {
+        Err(unsupported(Operation::AddDevice, name))
+    }
+
+    fn delete(&self, name: &InterfaceName) -> Result<(), Error> {
+        Err(unsupported(Operation::DeleteDevice, name))
+    }
+}
+
+/// Not [`Error::from_errno`], for the kernel `EOPNOTSUPP` means something else
+fn unsupported(operation: Operation, name: &InterfaceName) -> Error {
+    Error::Netlink {
+        operation,
+        interface: Some(name.as_str().to_owned()),
+        errno: libc::EOPNOTSUPP,
+    }
+}
+
+fn to_hex(key: &[u8; 32]) -> String {
+    key.iter().fold(String::with_capacity(64), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    })
+}
+
+fn from_hex(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let mut key = [0; 32];
+    for (byte, pair) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+
+    Some(key)
+}
+
+/// The `get=1` reply, `None` for values which do not parse
+fn parse_device(name: &InterfaceName, lines: &[String]) -> Option<Device> {
+    let mut device = Device {
+        name: name.as_str().to_owned(),
+        // the protocol does not tell, the tun device the daemon created does
+        ifindex: unsafe { libc::if_nametoindex(name.to_raw().as_ptr()) },
+        public_key: None,
+        private_key: None,
+        fwmark: 0,
+        listen_port: 0,
+        peers: Vec::new(),
+    };
+    let mut handshake = (0, 0);
+
+    for line in lines {
+        let (key, value) = line.split_once('=')?;
+        let peer = device.peers.last_mut();
+
+        match (key, peer) {
+            ("private_key", _) => {
+                let key = PrivateKey::from_bytes(from_hex(value)?);
+                device.public_key = Some(key.public_key());
+                device.private_key = Some(key);
+            }
+            ("listen_port", _) => device.listen_port = value.parse().ok()?,
+            ("fwmark", _) => device.fwmark = value.parse().ok()?,
+            ("public_key", peer) => {
+                if let Some(peer) = peer {
+                    peer.last_handshake_time = handshake_time(handshake);
+                }
+                handshake = (0, 0);
+                device.peers.push(Peer {
+                    public_key: PublicKey::from_bytes(from_hex(value)?),
+                    preshared_key: None,
+                    endpoint: None,
+                    last_handshake_time: None,
+                    rx_bytes: 0,
+                    tx_bytes: 0,
+                    persistent_keepalive_interval: 0,
+                    allowed_ips: Vec::new(),
+                });
+            }
+            ("preshared_key", Some(peer)) => {
+                let key = PresharedKey::from_bytes(from_hex(value)?);
+                peer.preshared_key = (!key.is_zero()).then_some(key);
+            }
+            ("endpoint", Some(peer)) => peer.endpoint = Some(value.parse::<SocketAddr>().ok()?),
+            ("persistent_keepalive_interval", Some(peer)) => peer.persistent_keepalive_interval = value.parse().ok()?,
+            ("allowed_ip", Some(peer)) => peer.allowed_ips.push(value.parse().ok()?),
+            ("last_handshake_time_sec", Some(_)) => handshake.0 = value.parse().ok()?,
+            ("last_handshake_time_nsec", Some(_)) => handshake.1 = value.parse().ok()?,
+            ("rx_bytes", Some(peer)) => peer.rx_bytes = value.parse().ok()?,
+            ("tx_bytes", Some(peer)) => peer.tx_bytes = value.parse().ok()?,
+            // e.g. protocol_version, newer daemons may add more
+            _ => {}
+        }
+    }
+    if let Some(peer) = device.peers.last_mut() {
+        peer.last_handshake_time = handshake_time(handshake);
+    }
+
+    Some(device)
+}
+
+/// A zero timestamp means there was no handshake
+fn handshake_time((secs, nanos): (u64, u32)) -> Option<std::time::SystemTime> {
+    if secs == 0 && nanos == 0 {
+        return None;
+    }
+
+    UNIX_EPOCH.checked_add(Duration::new(secs, nanos.min(999_999_999)))
+}
+
+/// The `set=1` request, each value only if the configuration sets it
+fn set_request(config: &DeviceConfig) -> String {
+    let mut request = String::from("set=1\n");
+
+    if let Some(key) = &config.private_key {
+        let _ = writeln!(request, "private_key={}", to_hex(key.as_bytes()));
+    }
+    if let Some(port) = config.listen_port {
+        let _ = writeln!(request, "listen_port={}", port);
+    }
+    if let Some(fwmark) = config.fwmark {
+        let _ = writeln!(request, "fwmark={}", fwmark);
+    }
+    if config.replace_peers {
+        let _ = writeln!(request, "replace_peers=true");
+    }
+
+    for peer in &config.peers {
+        let _ = writeln!(request, "public_key={}", to_hex(peer.public_key.as_bytes()));
+        if peer.remove {
+            let _ = writeln!(request, "remove=true");
+            continue;
+        }
+        if let Some(key) = &peer.preshared_key {
+            let _ = writeln!(request, "preshared_key={}", to_hex(key.as_bytes()));
+        }
+        if let Some(endpoint) = peer.endpoint {
+            let _ = writeln!(request, "endpoint={}", endpoint);
+        }
+        if let Some(interval) = peer.persistent_keepalive_interval {
+            let _ = writeln!(request, "persistent_keepalive_interval={}", interval);
+        }
+        if peer.replace_allowed_ips {
+            let _ = writeln!(request, "replace_allowed_ips=true");
+        }
+        for allowed_ip in &peer.allowed_ips {
+            let _ = writeln!(request, "allowed_ip={}", allowed_ip);
+        }
+    }
+    request.push('\n');
+
+    request
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+    use std::os::fd::AsRawFd;
+    use std::os::unix::net::UnixListener;
+    use std::thread::{self, JoinHandle};
+
+    use super::*;
+    use crate::config::PeerConfig;
+
+    /// A socket directory in which each socket answers a single request
+    struct StandIn {
+        dir: PathBuf,
+    }
+
+    impl StandIn {
+        fn new(test: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("wgbind-uapi-{}-{}", test, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            Self { dir }
+        }
+
+        /// Replies with `reply` and hands back the request
+        fn serve(&self, name: &str, reply: &'static str) -> JoinHandle<String> {
+            let listener = UnixListener::bind(self.dir.join(format!("{}.sock", name))).unwrap();
+            thread::spawn(move || {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = String::new();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                while reader.read_line(&mut request).unwrap() > 0 && !request.ends_with("\n\n") {}
+                stream.write_all(reply.as_bytes()).unwrap();
+                request
+            })
+        }
+    }
+
+    impl Drop for StandIn {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn it_gets_and_sets_devices_over_the_socket() {
+        let standin = StandIn::new("get-set");
+        let uapi = Uapi::with_socket_dir(&standin.dir);
+        std::fs::write(standin.dir.join("wg9.sock"), "").unwrap();
+
+        let private_key = PrivateKey::from_bytes([0x11; 32]);
+        let get = standin.serve(
+            "wg0",
+            concat!(
+                "private_key=1111111111111111111111111111111111111111111111111111111111111111\n",
+                "listen_port=51820\n",
+                "fwmark=7\n",
+                "public_key=0202020202020202020202020202020202020202020202020202020202020202\n",
+                "preshared_key=0000000000000000000000000000000000000000000000000000000000000000\n",
+                "protocol_version=1\n",
+                "endpoint=[2001:db8::1]:51820\n",
+                "last_handshake_time_sec=1700000000\n",
+                "last_handshake_time_nsec=5\n",
+                "tx_bytes=7\n",
+                "rx_bytes=42\n",
+                "persistent_keepalive_interval=25\n",
+                "allowed_ip=10.0.0.2/32\n",
+                "allowed_ip=fd00::/64\n",
+                "public_key=0303030303030303030303030303030303030303030303030303030303030303\n",
+                "errno=0\n\n",
+            ),
+        );
+
+        // only sockets count, the regular file is skipped
+        assert_eq!(uapi.list().unwrap(), vec!["wg0"]);
+        assert!(uapi.has_device(&"wg0".parse().unwrap()));
+        assert!(!uapi.has_device(&"wg9".parse().unwrap()));
+
+        let device = uapi.get(&"wg0".parse().unwrap()).unwrap();
+        assert_eq!(get.join().unwrap(), "get=1\n\n");
+        assert_eq!(device.name(), "wg0");
+        assert_eq!(device.private_key(), Some(&private_key));
+        assert_eq!(device.public_key(), Some(&private_key.public_key()));
+        assert_eq!((device.listen_port(), device.fwmark()), (51820, 7));
+        assert_eq!(device.peers().len(), 2);
+        let peer = &device.peers()[0];
+        assert_eq!(peer.public_key(), &PublicKey::from_bytes([2; 32]));
+        assert_eq!(peer.preshared_key(), None);
+        assert_eq!(peer.endpoint(), Some("[2001:db8::1]:51820".parse().unwrap()));
+        assert_eq!(peer.last_handshake_time(), Some(UNIX_EPOCH + Duration::new(1_700_000_000, 5)));
+        assert_eq!((peer.rx_bytes(), peer.tx_bytes()), (42, 7));
+        assert_eq!(peer.persistent_keepalive_interval(), 25);
+        assert_eq!(peer.allowed_ips(), &["10.0.0.2/32".parse().unwrap(), "fd00::/64".parse().unwrap()]);
+        assert_eq!(device.peers()[1].last_handshake_time(), None);
+
+        let set = standin.serve("wg1", "errno=0\n\n");
+        let config = DeviceConfig::new()
+            .private_key(private_key)
+            .listen_port(51821)
+            .replace_peers(true)
+            .peer(
+                PeerConfig::new(PublicKey::from_bytes([2; 32]))
+                    .preshared_key(PresharedKey::from_bytes([0xab; 32]))
+                    .endpoint("192.0.2.1:51820".parse().unwrap())
+                    .persistent_keepalive_interval(0)
+                    .replace_allowed_ips(true)
+                    .allowed_ip("10.0.0.0/24".parse().unwrap()),
+            )
+            .peer(PeerConfig::new(PublicKey::from_bytes([3; 32])).remove(true).allowed_ip("10.0.1.0/24".parse().unwrap()));
+        uapi.set(&"wg1".parse().unwrap(), &config).unwrap();
+        assert_eq!(
+            set.join().unwrap(),
+            concat!(
+                "set=1\n",
+                "private_key=1111111111111111111111111111111111111111111111111111111111111111\n",
+                "listen_port=51821\n",
+                "replace_peers=true\n",
+                "public_key=0202020202020202020202020202020202020202020202020202020202020202\n",
+                "preshared_key=abababababababababababababababababababababababababababababababab\n",
+                "endpoint=192.0.2.1:51820\n",
+                "persistent_keepalive_interval=0\n",
+                "replace_allowed_ips=true\n",
+                "allowed_ip=10.0.0.0/24\n",
+                "public_key=0303030303030303030303030303030303030303030303030303030303030303\n",
+                "remove=true\n",
+                "\n",
+            )
+        );
+    }
+
+    #[test]
+    fn it_maps_errors_of_the_daemon() {
+        let standin = StandIn::new("errors");
+        let uapi = Uapi::with_socket_dir(&standin.dir);
+        let name: InterfaceName = "wg0".parse().unwrap();
+
+        assert!(matches!(uapi.get(&name), Err(Error::NoSuchDevice { .. })));
+
+        let denied = standin.serve("wg0", "errno=1\n\n");
+        let result = uapi.set(&name, &DeviceConfig::new().fwmark(1));
+        assert!(matches!(result, Err(Error::PermissionDenied { operation: Operation::SetDevice, .. })), "{:?}", result);
+        assert_eq!(denied.join().unwrap(), "set=1\nfwmark=1\n\n");
+        std::fs::remove_file(uapi.socket_path(&name)).unwrap();
+
+        let garbled = standin.serve("wg0", "public_key=nothex\nerrno=0\n\n");
+        assert_eq!(uapi.get(&name).unwrap_err().errno(), Some(libc::EPROTO));
+        garbled.join().unwrap();
+
+        // a daemon which never accepts nor replies
+        let hung: InterfaceName = "wg1".parse().unwrap();
+        let listener = UnixListener::bind(uapi.socket_path(&hung)).unwrap();
+        let result = uapi.clone().timeout(Duration::from_millis(50)).get(&hung);
+        assert_eq!(result.as_ref().unwrap_err().errno(), Some(libc::ETIMEDOUT), "{:?}", result);
+
+        // once its backlog is full connecting blocks as well
+        assert_eq!(unsafe { libc::listen(listener.as_raw_fd(), 0) }, 0);
+        let pending = connect(&uapi.socket_path(&hung), Some(Duration::from_millis(50)));
+        assert_eq!(pending.unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        let result = uapi.clone().timeout(Duration::from_millis(50)).get(&hung);
+        assert_eq!(result.as_ref().unwrap_err().errno(), Some(libc::ETIMEDOUT), "{:?}", result);
+        drop(listener);
+
+        // a daemon which trickles its reply byte by byte
+        let slow: InterfaceName = "wg2".parse().unwrap();
+        let listener = UnixListener::bind(uapi.socket_path(&slow)).unwrap();
+        let trickle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            for byte in "listen_port=51820\n".bytes().cycle().take(200) {
+                if stream.write_all(&[byte]).is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(5));
+            }
+        });
+        let started = Instant::now();
+        let result = uapi.clone().timeout(Duration::from_millis(100)).get(&slow);
+        assert_eq!(result.as_ref().unwrap_err().errno(), Some(libc::ETIMEDOUT), "{:?}", result);
+        assert!(started.elapsed() < Duration::from_millis(500));
+        trickle.join().unwrap();
+
+        assert_eq!(uapi.add(&name).unwrap_err().errno(), Some(libc::EOPNOTSUPP));
+        assert!(Uapi::with_socket_dir(standin.dir.join("missing")).list().unwrap().is_empty());
+    }
+}
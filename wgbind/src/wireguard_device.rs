@@ -1,197 +1,438 @@
 
-use std::ffi::CStr;
+use std::ptr::NonNull;
 
 use super::*;
 
-extern crate wgbindraw_sys;
-use wgbindraw_sys::*;
+/// Which side allocated the wg_device behind a [`WireguardDevice`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Allocator {
+    /// allocated by the c library via wg_get_device, released with wg_free_device
+    C,
+    /// allocated by rust, released by dropping the boxes again
+    Rust,
+}
+
+/// The raw wg_device together with the knowledge how to release it
+#[derive(Debug)]
+enum RawDevice {
+    C(NonNull<wg_device>),
+    /// Every peer and allowed ip linked into this device was created through
+    /// `Box::into_raw`, hence the whole chain is owned by rust as well
+    Rust(Box<wg_device>),
+}
 
 /// # WireguardDevice
-/// 
+///
 /// holds configuration data for a Wireguard Device.
-/// if this object drops, the associated strings get 
-/// lost, if the wireguard implementation depends on these
-/// string values, that might cause significant problems !!!
-/// 
-/// 
+/// The handle owns the underlying wg_device including its peers and allowed ips
+/// and remembers whether the c library or rust allocated them. On drop the memory
+/// is released by the same side which allocated it.
+///
+/// [`WireguardControl`] reads and writes the device through the [`Backend`] `B`,
+/// the kernel unless [`WireguardDevice::with_backend`] picked another one.
+///
 #[derive(Debug)]
-pub struct WireguardDevice {
-    pub raw_device : Box<*const::core::ffi::c_void>,
-    
+pub struct WireguardDevice<B = Kernel> {
+    raw_device : RawDevice,
+    backend: B,
 }
 
+// The handle is the sole owner of the wg_device and every list element hanging
+// off it. Nothing else keeps pointers into that memory, so moving it to another
+// thread is fine. It is not Sync because the c library gives no such guarantee.
+unsafe impl<B: Send> Send for WireguardDevice<B> {}
+
 
 /// trait to bind functionailty directly to an instance
 /// of WireguardDevice
-/// 
-/// 
+///
+///
 pub trait WireguardControl   {
 
     /// Creates new network interface and hence a new wg_device indirectly
-    fn create_interface(&self, name: &str) -> Result<(),std::io::Error>;
+    fn create_interface(&self, name: &InterfaceName) -> Result<(),Error>;
 
     /// deletes a network interface basically dropping everything
-    fn remove_interface(&mut self) -> Result<(),std::io::Error>;
-
-    /// writes to the kernal 
-    fn update_device(&mut self) -> Result<(),std::io::Error>;
+    fn remove_interface(&mut self) -> Result<(),Error>;
 
     /// Read from the kernel
-    fn refresh_device(&mut self) -> Result<(), std::io::Error>;
+    fn update_device(&mut self) -> Result<(),Error>;
 
-    fn raw_device_handler(&self) -> &Box<*const ::core::ffi::c_void>;
+    /// writes to the kernal
+    fn refresh_device(&mut self) -> Result<(), Error>;
 
-    fn raw_device_ptr(&self) -> *const wg_device {
-        let ptr =  **self.raw_device_handler();
-        ptr.cast()
-    }
-}
-
-/// converts any raw wg_device into a managed WireguardDevice
-impl From<*mut wg_device> for WireguardDevice{
-    fn from(value: *mut wg_device) -> Self {
-        let handler = unsafe{ &*value as *const wg_device }; 
-        let handler = handler as * const ::core::ffi::c_void;
-        let handler = unsafe{ Box::new(
-            handler
-        )};
-         
-        WireguardDevice::new(
-            handler
-        )
-    }
+    fn raw_device_ptr(&self) -> *const wg_device;
 }
 
 
 impl WireguardDevice {
-    pub fn new(device : Box<*const ::core::ffi::c_void>) -> Self { 
-         Self { 
-            raw_device: device
-        } 
+    /// Takes ownership of a rust allocated device
+    ///
+    /// Only the device itself is taken over. `first_peer` and `last_peer` are reset
+    /// to NULL because nobody can tell who allocated a foreign peer list.
+    pub fn new(device : wg_device) -> Self {
+        let device = wg_device {
+            first_peer: std::ptr::null_mut(),
+            last_peer: std::ptr::null_mut(),
+            ..device
+        };
+
+        Self {
+            raw_device: RawDevice::Rust(Box::new(device)),
+            backend: Kernel,
+        }
     }
 
-    pub fn private_key(&self) -> Option<&str> {
-        let pk = unsafe { self.raw_device_ptr().as_ref().unwrap().private_key }.as_ptr();
-        let pk = unsafe{std::slice::from_raw_parts(pk, 32)};
-        let pk = CStr::from_bytes_until_nul(pk);
+    /// Takes ownership of a device allocated by the c library
+    ///
+    /// Returns `None` for a NULL pointer, e.g. of a failed wg_get_device.
+    ///
+    /// # Safety
+    ///
+    /// A non NULL `device` must be handed out by wg_get_device and not be owned by
+    /// anyone else. It is released with wg_free_device once the handle drops.
+    pub unsafe fn from_raw(device: *mut wg_device) -> Option<Self> {
+        let device = NonNull::new(device)?;
+
+        Some(Self {
+            raw_device: RawDevice::C(device),
+            backend: Kernel,
+        })
+    }
 
-        if pk.is_ok() {
-            let pk = pk.unwrap();
-            let pk = pk.to_str().unwrap();
-            return Some(pk);
+    /// Gives up ownership and returns a pointer which must be released with wg_free_device
+    ///
+    /// A rust allocated device is copied into memory of the c allocator first, so
+    /// the caller does not need to know where the device came from.
+    pub fn into_raw(self) -> *mut wg_device {
+        if let RawDevice::C(device) = &self.raw_device {
+            let device = device.as_ptr();
+            std::mem::forget(self);
+            return device;
         }
 
-        None
+        // the copy owns nothing of the original which is released once self drops
+        unsafe { copy_to_c_allocator(self.raw()) }
     }
 
-    pub fn public_key(&self) -> Option<&str> {
-        let pubk = unsafe { self.raw_device_ptr().as_ref().unwrap().public_key }.as_ptr();
+}
 
-        let pubk = unsafe{std::slice::from_raw_parts(pubk, 32)};
+impl<B> WireguardDevice<B> {
+    /// The same device, read and written through another backend from now on
+    pub fn with_backend<C>(self, backend: C) -> WireguardDevice<C> {
+        WireguardDevice {
+            raw_device: self.raw_device,
+            backend,
+        }
+    }
 
-        let pubk = CStr::from_bytes_until_nul(pubk);
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
 
-        if pubk.is_ok() {
-            let pubk = pubk.unwrap();
-            let pubk = pubk.to_str().unwrap();
-            return Some(pubk);
+    /// Which side allocated the device and is going to release it
+    pub fn allocator(&self) -> Allocator {
+        match self.raw_device {
+            RawDevice::C(_) => Allocator::C,
+            RawDevice::Rust(_) => Allocator::Rust,
         }
+    }
 
-        None
+    pub(crate) fn as_mut_ptr(&mut self) -> *mut wg_device {
+        match &mut self.raw_device {
+            RawDevice::C(device) => device.as_ptr(),
+            RawDevice::Rust(device) => &mut **device,
+        }
     }
 
-    pub fn name(&self) ->  Option<&str> {
-        let pk = unsafe { self.raw_device_ptr().as_ref().unwrap().name }.as_ptr() as *const u8;
-        let pk = unsafe{std::slice::from_raw_parts(pk, 16)};
-        let pk = CStr::from_bytes_until_nul(pk);
+    /// Appends a peer and its allowed ips to a rust allocated device
+    ///
+    /// The list pointers of `peer` and of the allowed ips are overwritten.
+    ///
+    /// # Panics
+    ///
+    /// if the device was allocated by the c library
+    pub(crate) fn push_peer(&mut self, peer: wg_peer, allowed_ips: impl IntoIterator<Item = wg_allowedip>) {
+        let RawDevice::Rust(device) = &mut self.raw_device else {
+            panic!("peers can only be added to rust allocated devices");
+        };
+
+        let mut peer = Box::new(wg_peer {
+            first_allowedip: std::ptr::null_mut(),
+            last_allowedip: std::ptr::null_mut(),
+            next_peer: std::ptr::null_mut(),
+            ..peer
+        });
+
+        let (first, last) = allowed_ip::build_chain(allowed_ips);
+        peer.first_allowedip = first;
+        peer.last_allowedip = last;
+
+        let peer = Box::into_raw(peer);
+        match unsafe { device.last_peer.as_mut() } {
+            Some(last) => last.next_peer = peer,
+            None => device.first_peer = peer,
+        }
+        device.last_peer = peer;
+    }
 
-        if pk.is_ok() {
-            let pk = pk.unwrap();
-            let pk = pk.to_str().unwrap();
-            return Some(pk);
+    pub(crate) fn raw(&self) -> &wg_device {
+        match &self.raw_device {
+            RawDevice::C(device) => unsafe { device.as_ref() },
+            RawDevice::Rust(device) => device,
         }
+    }
+
+    fn has(&self, flag: wg_device_flags) -> bool {
+        self.raw().flags & flag == flag
+    }
 
-        None
+    /// `None` unless WGDEVICE_HAS_PRIVATE_KEY is set
+    pub fn private_key(&self) -> Option<PrivateKey> {
+        self.has(wg_device_flags::WGDEVICE_HAS_PRIVATE_KEY)
+            .then(|| PrivateKey::from_bytes(self.raw().private_key))
+    }
+
+    /// `None` unless WGDEVICE_HAS_PUBLIC_KEY is set
+    pub fn public_key(&self) -> Option<PublicKey> {
+        self.has(wg_device_flags::WGDEVICE_HAS_PUBLIC_KEY)
+            .then(|| PublicKey::from_bytes(self.raw().public_key))
+    }
+
+    pub fn name(&self) -> Result<InterfaceName, InvalidInterfaceName> {
+        InterfaceName::from_raw(&self.raw().name)
     }
 
 
     pub fn flags(&self) -> wg_device_flags {
-        unsafe { self.raw_device_ptr().as_ref().unwrap().flags }.clone()
+        self.raw().flags
     }
 
     pub fn fwmark(&self) -> u32 {
-        unsafe { self.raw_device_ptr().as_ref().unwrap().fwmark }.clone()
+        self.raw().fwmark
     }
 
 }
 
-impl Into<wg_device> for WireguardDevice{
-    fn into(self) -> wg_device {
-        todo!()
+/// Releases the peer and allowed ip chains of a rust allocated device
+///
+/// # Safety
+///
+/// all list elements must have been created by Box::into_raw
+unsafe fn free_rust_lists(device: &mut wg_device) {
+    let mut peer = std::mem::replace(&mut device.first_peer, std::ptr::null_mut());
+    device.last_peer = std::ptr::null_mut();
+
+    while !peer.is_null() {
+        let boxed = unsafe { Box::from_raw(peer) };
+
+        let mut allowedip = boxed.first_allowedip;
+        while !allowedip.is_null() {
+            let boxed = unsafe { Box::from_raw(allowedip) };
+            allowedip = boxed.next_allowedip;
+        }
+
+        peer = boxed.next_peer;
+    }
+}
+
+/// Deep copies a device into memory obtained from the c allocator
+///
+/// # Safety
+///
+/// all list elements of `device` must be valid
+unsafe fn copy_to_c_allocator(device: &wg_device) -> *mut wg_device {
+    unsafe fn calloc<T>() -> *mut T {
+        let ptr = unsafe { libc::calloc(1, std::mem::size_of::<T>()) };
+        if ptr.is_null() {
+            std::alloc::handle_alloc_error(std::alloc::Layout::new::<T>());
+        }
+        ptr.cast()
     }
+
+    let copy = unsafe { calloc::<wg_device>() };
+    unsafe {
+        copy.write(wg_device {
+            first_peer: std::ptr::null_mut(),
+            last_peer: std::ptr::null_mut(),
+            ..*device
+        })
+    };
+
+    let mut peer = device.first_peer;
+    while let Some(source) = unsafe { peer.as_ref() } {
+        let new_peer = unsafe { calloc::<wg_peer>() };
+        unsafe {
+            std::ptr::copy_nonoverlapping(source, new_peer, 1);
+            (*new_peer).first_allowedip = std::ptr::null_mut();
+            (*new_peer).last_allowedip = std::ptr::null_mut();
+            (*new_peer).next_peer = std::ptr::null_mut();
+        }
+
+        let mut allowedip = source.first_allowedip;
+        while let Some(source) = unsafe { allowedip.as_ref() } {
+            let new_allowedip = unsafe { calloc::<wg_allowedip>() };
+            unsafe {
+                std::ptr::copy_nonoverlapping(source, new_allowedip, 1);
+                (*new_allowedip).next_allowedip = std::ptr::null_mut();
+
+                match (*new_peer).last_allowedip.as_mut() {
+                    Some(last) => last.next_allowedip = new_allowedip,
+                    None => (*new_peer).first_allowedip = new_allowedip,
+                }
+                (*new_peer).last_allowedip = new_allowedip;
+            }
+            allowedip = source.next_allowedip;
+        }
+
+        unsafe {
+            match (*copy).last_peer.as_mut() {
+                Some(last) => last.next_peer = new_peer,
+                None => (*copy).first_peer = new_peer,
+            }
+            (*copy).last_peer = new_peer;
+        }
+        peer = source.next_peer;
+    }
+
+    copy
 }
 
-impl Into<WireguardDevice> for wg_device{
-    fn into(self) -> WireguardDevice {
-        todo!()
+/// Copies the device itself. The peer list stays with the handle and is released
+/// together with it, hence the returned value carries no peers.
+impl From<WireguardDevice> for wg_device {
+    fn from(value: WireguardDevice) -> Self {
+        wg_device {
+            first_peer: std::ptr::null_mut(),
+            last_peer: std::ptr::null_mut(),
+            ..*value.raw()
+        }
     }
 }
 
+/// see [`WireguardDevice::new`]
+impl From<wg_device> for WireguardDevice {
+    fn from(value: wg_device) -> Self {
+        WireguardDevice::new(value)
+    }
+}
 
-impl Into<*mut wg_device> for WireguardDevice{
-    fn into(self) -> *mut wg_device {
-        todo!()
+/// Boxed variant of [`WireguardDevice::new`] for callers which need a stable address.
+/// Unlike a leaked reference the device is released once the box drops.
+impl From<wg_device> for Box<WireguardDevice> {
+    fn from(value: wg_device) -> Self {
+        Box::new(WireguardDevice::new(value))
     }
 }
 
-impl Into<&mut WireguardDevice> for wg_device {
-    fn into(self) -> &'static mut WireguardDevice {
-        todo!()
+/// see [`WireguardDevice::into_raw`]
+impl From<WireguardDevice> for *mut wg_device {
+    fn from(value: WireguardDevice) -> Self {
+        value.into_raw()
     }
 }
 
 
-impl Drop for WireguardDevice {
+
+impl Drop for RawDevice {
     fn drop(&mut self) {
-        free_device(self);
+        match self {
+            RawDevice::C(device) => unsafe { wg_free_device(device.as_ptr()) },
+            RawDevice::Rust(device) => unsafe { free_rust_lists(device) },
+        }
     }
 }
 
 
 
-impl WireguardControl for WireguardDevice where Self:Drop{
-    fn create_interface(&self, name : &str)-> Result<(),std::io::Error> {
-        return add_device(name);
+impl<B: Backend> WireguardControl for WireguardDevice<B> {
+    fn create_interface(&self, name : &InterfaceName)-> Result<(),Error> {
+        self.backend.add(name)
     }
 
-    fn remove_interface(&mut self) -> Result<(),std::io::Error> {
-        
-        let res = delete_device(self.name().unwrap());
-        if res.is_ok() {
-            free_device(self);
-            return  Ok(());
-        }
-        
-        res.into()
+    fn remove_interface(&mut self) -> Result<(),Error> {
+        // the memory of the handle stays valid, it is released once the handle drops
+        let name = self.name().map_err(|e| e.into_error(Operation::DeleteDevice))?;
+        self.backend.delete(&name)
     }
 
-    fn update_device(&mut self) -> Result<(),std::io::Error> {
-        let result = get_device(self.name().unwrap());
-        if result.is_ok() {
-            *self = result.unwrap().into();
+    fn update_device(&mut self) -> Result<(),Error> {
+        let name = self.name().map_err(|e| e.into_error(Operation::GetDevice))?;
+        let device = self.backend.get(&name)?;
+        self.raw_device = device.to_raw().raw_device;
 
-            return Ok(());
+        Ok(())
+    }
+
+    fn refresh_device(&mut self) -> Result<(), Error> {
+        let name = self.name().map_err(|e| e.into_error(Operation::SetDevice))?;
+        let config = unsafe { DeviceConfig::from_raw(self.raw()) };
+
+        self.backend.set(&name, &config)
+    }
+
+    fn raw_device_ptr(&self) -> *const wg_device {
+        self.raw()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rust_device_with_peer() -> WireguardDevice {
+        let mut device = WireguardDevice::new(unsafe { std::mem::zeroed() });
+
+        let allowedip = Box::into_raw(Box::new(unsafe { std::mem::zeroed::<wg_allowedip>() }));
+        let mut peer = Box::new(unsafe { std::mem::zeroed::<wg_peer>() });
+        peer.public_key = [3; 32];
+        peer.first_allowedip = allowedip;
+        peer.last_allowedip = allowedip;
+        let peer = Box::into_raw(peer);
+
+        let raw = device.as_mut_ptr();
+        unsafe {
+            (*raw).fwmark = 7;
+            (*raw).first_peer = peer;
+            (*raw).last_peer = peer;
         }
-        
-        Err(result.unwrap_err())
+
+        device
     }
 
-    fn refresh_device(&mut self) -> Result<(), std::io::Error> {
-        
-        set_device(self)
+    #[test]
+    fn it_hands_out_c_allocated_copies() {
+        let device = rust_device_with_peer();
+        assert_eq!(device.allocator(), Allocator::Rust);
+
+        let raw = device.into_raw();
+        let copy = unsafe { WireguardDevice::from_raw(raw) }.unwrap();
+        assert_eq!(copy.allocator(), Allocator::C);
+        assert_eq!(copy.fwmark(), 7);
+
+        let peer = unsafe { copy.raw().first_peer.as_ref() }.unwrap();
+        assert_eq!(peer.public_key, [3; 32]);
+        assert!(!peer.first_allowedip.is_null());
+        assert!(peer.next_peer.is_null());
+
+        assert!(unsafe { WireguardDevice::from_raw(std::ptr::null_mut()) }.is_none());
     }
 
-    fn raw_device_handler(&self) -> &Box<*const ::core::ffi::c_void> {
-        &self.raw_device
+    #[test]
+    fn it_strips_foreign_peer_lists() {
+        let mut raw: wg_device = unsafe { std::mem::zeroed() };
+        raw.first_peer = NonNull::dangling().as_ptr();
+        raw.last_peer = raw.first_peer;
+
+        let device = WireguardDevice::from(raw);
+        assert!(device.raw().first_peer.is_null());
+
+        let boxed: Box<WireguardDevice> = raw.into();
+        assert!(boxed.raw().first_peer.is_null());
+        assert_eq!(boxed.allocator(), Allocator::Rust);
+
+        let raw: wg_device = rust_device_with_peer().into();
+        assert!(raw.first_peer.is_null());
+        assert_eq!(raw.fwmark, 7);
     }
 }
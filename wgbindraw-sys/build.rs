@@ -1,9 +1,12 @@
 #![allow(non_upper_case_globals)]
 #![allow(non_camel_case_types)]
 
+#[cfg(feature = "c-library")]
 use std::env;
+#[cfg(feature = "c-library")]
 use std::path::PathBuf;
 
+#[cfg(feature = "c-library")]
 const libctypes: &[&str] = &[
     "in_addr",
     "in6_addr",
@@ -13,6 +16,7 @@ const libctypes: &[&str] = &[
     "timespec",
 ];
 
+#[cfg(feature = "c-library")]
 const ignoretypes: &[&str] = &[
     "in6_addr__bindgen_ty_1",
     "sa_family_t",
@@ -21,6 +25,13 @@ const ignoretypes: &[&str] = &[
 ];
 
 fn main() {
+    // without the c library src/bindings.rs is used as checked in
+    #[cfg(feature = "c-library")]
+    build_c_library();
+}
+
+#[cfg(feature = "c-library")]
+fn build_c_library() {
     //println!("cargo:rustc-link-lib=clib");
     println!("cargo:rerun-if-changed=wg/wireguard.h");
 
@@ -1,6 +1,12 @@
 #![allow(non_upper_case_globals)]
 #![allow(non_camel_case_types)]
 #![allow(non_snake_case)]
+// bindgen imports timespec whether used or not and documents no safety sections
+#![allow(unused_imports, clippy::missing_safety_doc)]
 
+#[cfg(feature = "c-library")]
 include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+
+#[cfg(not(feature = "c-library"))]
+include!("bindings.rs");
  
\ No newline at end of file